@@ -0,0 +1,86 @@
+"""
+    converts the IANA service name and port number registry to the
+    /etc/services format of data/iana-services
+
+    usage: python3 iana_services.py service-names-port-numbers.csv > ../data/iana-services
+
+    the csv is at
+    https://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.csv
+
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+"""
+
+import csv
+import sys
+
+
+HEADER = """\
+# IANA service names and port numbers, generated by py/iana_services.py
+# from
+# https://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.csv
+#
+# format: name port/protocol [aliases] [# comment]
+"""
+
+
+"""
+get the ports of a "Port Number" field, a single port or a range
+"""
+def ports(field):
+    if "-" in field:
+        first, last = field.split("-", 1)
+        return range(int(first), int(last) + 1)
+    return [int(field)]
+
+
+"""
+read the registry, the first name of a port and protocol is the
+service name, the others become aliases
+"""
+def read(path):
+    services = {}
+    with open(path, newline="", encoding="utf-8") as f:
+        for row in csv.DictReader(f):
+            name = row["Service Name"].strip()
+            port = row["Port Number"].strip()
+            proto = row["Transport Protocol"].strip().lower()
+            if not name or not port or proto not in ("tcp", "udp"):
+                continue
+            for p in ports(port):
+                names = services.setdefault((p, proto), [])
+                if name not in names:
+                    names.append(name)
+    return services
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: iana_services.py service-names-port-numbers.csv")
+
+    services = read(sys.argv[1])
+
+    sys.stdout.write(HEADER)
+    sys.stdout.write("\n")
+    for (port, proto), names in sorted(services.items()):
+        line = "{:<15} {}/{}".format(names[0], port, proto)
+        if len(names) > 1:
+            line += "\t" + " ".join(names[1:])
+        sys.stdout.write(line + "\n")
+
+
+if __name__ == "__main__":
+    main()
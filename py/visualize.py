@@ -21,6 +21,7 @@
 
 from pyvis.network import Network
 
+import os
 import pandas as pd
 import networkx as nx
 import matplotlib.pyplot as plt
@@ -39,24 +40,30 @@ class Visualizer:
         self.G = nx.Graph()
 
     """
-    add nodes to the graph, these are IP addresses
+    add nodes to the graph, these are IP addresses. nodes
+    found in `labels` are labeled with their hostname
     """
-    def add_nodes(self, nodes):
+    def add_nodes(self, nodes, labels):
         for node in nodes:
-            self.G.add_node(node)
+            self.G.add_node(node, label=labels.get(node, node))
     
     """
     add edges to the graph, these are destination and source IPs
+    and an optional label, shown when hovering over the edge
     """
     def add_edges(self, edges):
         for edge in edges:
-            self.G.add_edge(edge[0], edge[1])
+            if edge[2]:
+                self.G.add_edge(edge[0], edge[1], title=edge[2])
+            else:
+                self.G.add_edge(edge[0], edge[1])
 
     """
     write a plot of the graph to a file
     """
     def show(self):
-        nx.draw(self.G, with_labels=True, node_size=20, 
+        labels = nx.get_node_attributes(self.G, 'label')
+        nx.draw(self.G, labels=labels, node_size=20, 
             font_size=2, node_color="tab:green", edge_color='tab:grey',
             width=0.25)
         filename = 'graph.png'
@@ -68,7 +75,7 @@ class Visualizer:
         nt.show('nx.html')
 
 
-"""list of nodes (1d list) and edges (list of tuples (src,dst))
+"""list of nodes (1d list) and edges (list of tuples (src,dst,label))
 
 reads the graph's edges from a csv, creates a list of nodes
 and a list of edges as tuples
@@ -78,17 +85,37 @@ def read_graph():
     nodes = set()
     edges = list()
 
-    df = pd.read_csv('graph.csv')
+    df = pd.read_csv('graph.csv', keep_default_na=False)
 
-    for (src,dst) in zip(df['src'], df['dst']):
+    for (src,dst,label) in zip(df['src'], df['dst'], df['label']):
         
-        edges.append((src,dst))
+        edges.append((src,dst,label))
         nodes.add(src)
         nodes.add(dst)
 
     return (list(nodes), edges)
 
 
+"""dict of node -> label
+
+reads the node labels (hostnames) from a csv, the file
+is optional
+"""
+def read_labels():
+
+    labels = dict()
+
+    if not os.path.exists('labels.csv'):
+        return labels
+
+    df = pd.read_csv('labels.csv')
+
+    for (node,label) in zip(df['node'], df['label']):
+        labels[node] = label
+
+    return labels
+
+
 """None
 
 reads the csv, builds the graph and creates
@@ -100,9 +127,10 @@ if __name__ == '__main__':
 
     print('[+] reading graph data')
     (nodes, edges) = read_graph()
+    labels = read_labels()
 
     print('[+] adding nodes')
-    v.add_nodes(nodes)
+    v.add_nodes(nodes, labels)
 
     print('[+] adding edges')
     v.add_edges(edges)
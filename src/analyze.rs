@@ -19,9 +19,13 @@
 use std::fs;
 use std::io::Error;
 use std::io::prelude::*;
-use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use crate::pinfo::{PacketData};
+use crate::util;
+use crate::pinfo::{PacketData, Protocol};
+use crate::services::Services;
+use crate::dissect::Dissectors;
 
 /*
     NOTES:
@@ -32,8 +36,45 @@ use crate::pinfo::{PacketData};
           only looking at source/destination IP/MAC addrs.
 */
 
+/// labels for the edges of the graph, what the dissectors found and the
+/// named services the packets went to
+fn edge_labels(pv: &Vec<PacketData>, dissectors: &Dissectors, services: &Services)
+    -> HashMap<(Ipv4Addr, Ipv4Addr), String> {
+
+    let mut labels: HashMap<_, BTreeSet<String>> = HashMap::new();
+
+    for item in pv {
+        let proto = item.get_proto();
+        if proto != Protocol::TCP && proto != Protocol::UDP {
+            continue;
+        }
+        // the destination port is the service for requests, the
+        // source port for responses
+        let port = [item.get_dport().0, item.get_sport().0].into_iter()
+            .find(|p| services.name(*p, proto).is_some());
+        if let Some(port) = port {
+            labels.entry((item.get_sip(), item.get_dip()))
+                .or_default()
+                .insert(services.label(port, proto));
+        }
+    }
+
+    let mut labels: HashMap<_, String> = labels.into_iter()
+        .map(|(edge, l)| (edge, l.into_iter().collect::<Vec<_>>().join(", ")))
+        .collect();
+
+    for (edge, label) in dissectors.edge_labels() {
+        labels.entry(edge)
+            .and_modify(|l| *l = format!("{} {}", label, l))
+            .or_insert(label);
+    }
+
+    labels
+}
+
 /// write the result as a dotfile
-pub fn dotfile(pv: &Vec<PacketData>) -> Result<(), Error> {
+pub fn dotfile(pv: &Vec<PacketData>, dissectors: &Dissectors, services: &Services)
+    -> Result<(), Error> {
 
     let mut connections = HashSet::new();
 
@@ -47,11 +88,20 @@ pub fn dotfile(pv: &Vec<PacketData>) -> Result<(), Error> {
     // TODO: if a node shall have a weight, it may be necessary to
     //       add duplicates here, so the de-duplication may not even
     //       be necessary.
+    let labels = edge_labels(pv, dissectors, services);
+
     for item in pv {
-        connections.insert(item.write_dot());
+        let label = labels.get(&(item.get_sip(), item.get_dip()));
+        connections.insert(item.write_dot(label.map(|l| l.as_str())));
     }
 
     write!(file, "digraph g {{\n")?;
+
+    // label the nodes we know a name for
+    for (ip, name) in dissectors.hostnames() {
+        write!(file, "\"{:?}\" [label=\"{:?}\\n{}\"]\n", ip, ip, util::dot_escape(&name))?;
+    }
+
     for item in connections {
         write!(file, "{}", item)?;
     }
@@ -62,7 +112,8 @@ pub fn dotfile(pv: &Vec<PacketData>) -> Result<(), Error> {
 
 /// just executes the python program to visualize
 /// for now, might be re-done in rust later
-pub fn visualize(pv: &Vec<PacketData>) -> Result<(), Error> {
+pub fn visualize(pv: &Vec<PacketData>, dissectors: &Dissectors, services: &Services)
+    -> Result<(), Error> {
 
     let mut connections = HashSet::new();
 
@@ -73,18 +124,35 @@ pub fn visualize(pv: &Vec<PacketData>) -> Result<(), Error> {
                 .open("graph.csv")
                 .unwrap();
 
+    let labels = edge_labels(pv, dissectors, services);
+
     for item in pv {
-        connections.insert(item.write_graph());
+        let label = labels.get(&(item.get_sip(), item.get_dip()));
+        connections.insert(item.write_graph(label.map(|l| l.as_str())));
     }
 
     // NOTE: this is de-duplicated, because the CSV doesn't gain anything
     //       by having duplicates, of course, it may be that we actually 
     //       want a weight here, so I will leave this open for now.
-    write!(file, "src,dst\n")?;
+    write!(file, "src,dst,label\n")?;
     for item in connections {
         write!(file, "{}", item)?;
     }
 
+    // node labels for the visualizer, hosts without a name are 
+    // labeled with their address
+    let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("labels.csv")
+                .unwrap();
+
+    write!(file, "node,label\n")?;
+    for (ip, name) in dissectors.hostnames() {
+        write!(file, "{:?},{}\n", ip, util::csv_field(&format!("{:?} ({})", ip, name)))?;
+    }
+
     let output = std::process::Command::new("python3")
         .arg("py/visualize.py")
         .output()
@@ -97,8 +165,22 @@ pub fn visualize(pv: &Vec<PacketData>) -> Result<(), Error> {
     Ok(())
 }
 
+/// write the HTTP transactions as csv
+pub fn http_csv(dissectors: &Dissectors) -> Result<(), Error> {
+
+    let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("http.csv")
+                .unwrap();
+
+    dissectors.http.csv(&mut file)
+}
+
 /// generate a report as a textfile 
-pub fn generate_report(pv: &Vec<PacketData>) -> Result<(), Error> {
+pub fn generate_report(pv: &Vec<PacketData>, dissectors: &Dissectors,
+    services: &Services) -> Result<(), Error> {
 
     let mut linebreak: usize =  1;
     let mut ips = HashSet::new();
@@ -109,8 +191,11 @@ pub fn generate_report(pv: &Vec<PacketData>) -> Result<(), Error> {
     for item in pv {
         ips.insert(item.get_sip());
         ips.insert(item.get_dip());
-        ports.insert(item.get_sport());
-        ports.insert(item.get_dport());
+        let proto = item.get_proto();
+        if proto == Protocol::TCP || proto == Protocol::UDP {
+            ports.insert((item.get_sport(), proto));
+            ports.insert((item.get_dport(), proto));
+        }
         macs.insert(item.get_smac());
         macs.insert(item.get_dmac());
     }
@@ -158,19 +243,16 @@ pub fn generate_report(pv: &Vec<PacketData>) -> Result<(), Error> {
     write!(file, "\n\n-- Unique Lower Ports \n")?;
 
     let mut ports = ports.into_iter().collect::<Vec<_>>();
-    ports.sort();
+    ports.sort_by_key(|(port, proto)| (*port, proto.to_string()));
 
-    for item in ports {
+    for (port, proto) in ports {
 
-        if item.0 < 32768 {
-            // nicely format the strings in the report 
-            let mut item = item.to_string();
-            for _ in 0..6-item.len() {
-                item.push(' ')
-            }
-            write!(file, "{} ", item)?;
+        if port.0 < 32768 {
+            // nicely format the strings in the report, with the
+            // service name if there is one
+            write!(file, "{:<28}", services.label(port.0, proto))?;
 
-            if linebreak == 12 {
+            if linebreak == 4 {
                 write!(file, "\n")?;
                 linebreak = 0;
             }
@@ -179,5 +261,7 @@ pub fn generate_report(pv: &Vec<PacketData>) -> Result<(), Error> {
         }
     }
 
+    dissectors.report(&mut file)?;
+
     Ok(())
 }
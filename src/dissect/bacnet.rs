@@ -0,0 +1,617 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! BACnet/IP: the BVLC header carries an NPDU (network layer), which
+//! carries an APDU (application layer) unless it is a network message.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, be16};
+
+/// BACnet/IP port
+pub const BACNET_PORT: u16 = 47808;
+
+/// BVLC type of BACnet/IP
+const BVLC_TYPE: u8 = 0x81;
+
+/// BVLC functions that carry a NPDU
+const BVLC_FORWARDED:       u8 = 0x04;
+const BVLC_DISTRIBUTE:      u8 = 0x09;
+const BVLC_UNICAST:         u8 = 0x0a;
+const BVLC_BROADCAST:       u8 = 0x0b;
+
+/// NPDU control bits
+const NPDU_NETWORK_MSG:     u8 = 0x80;
+const NPDU_DNET:            u8 = 0x20;
+const NPDU_SNET:            u8 = 0x08;
+
+/// APDU types
+const APDU_CONFIRMED:       u8 = 0;
+const APDU_UNCONFIRMED:     u8 = 1;
+const APDU_COMPLEX_ACK:     u8 = 3;
+
+/// segmented message bit of confirmed requests and complex acks
+const APDU_SEGMENTED:       u8 = 0x08;
+
+/// unconfirmed services
+const SVC_I_AM:             u8 = 0;
+const SVC_WHO_IS:           u8 = 8;
+
+/// confirmed services
+const SVC_READ_PROPERTY:    u8 = 12;
+const SVC_READ_PROP_MULTI:  u8 = 14;
+const SVC_WRITE_PROPERTY:   u8 = 15;
+const SVC_WRITE_PROP_MULTI: u8 = 16;
+
+/// object type of devices
+const OBJECT_DEVICE: u16 = 8;
+
+/// device properties taken from ReadProperty answers
+const PROP_FIRMWARE:    u32 = 44;
+const PROP_MODEL_NAME:  u32 = 70;
+const PROP_OBJECT_NAME: u32 = 77;
+const PROP_VENDOR_NAME: u32 = 121;
+
+/// application tag numbers
+const TAG_BOOLEAN:          u8 = 1;
+const TAG_UNSIGNED:         u8 = 2;
+const TAG_CHARACTER_STRING: u8 = 7;
+const TAG_OBJECT_ID:        u8 = 12;
+
+/// get the name of a confirmed service
+pub fn confirmed_name(service: u8) -> String {
+    match service {
+        0  => "acknowledgeAlarm".to_string(),
+        1  => "confirmedCOVNotification".to_string(),
+        2  => "confirmedEventNotification".to_string(),
+        3  => "getAlarmSummary".to_string(),
+        4  => "getEnrollmentSummary".to_string(),
+        5  => "subscribeCOV".to_string(),
+        6  => "atomicReadFile".to_string(),
+        7  => "atomicWriteFile".to_string(),
+        8  => "addListElement".to_string(),
+        9  => "removeListElement".to_string(),
+        10 => "createObject".to_string(),
+        11 => "deleteObject".to_string(),
+        SVC_READ_PROPERTY    => "readProperty".to_string(),
+        SVC_READ_PROP_MULTI  => "readPropertyMultiple".to_string(),
+        SVC_WRITE_PROPERTY   => "writeProperty".to_string(),
+        SVC_WRITE_PROP_MULTI => "writePropertyMultiple".to_string(),
+        17 => "deviceCommunicationControl".to_string(),
+        18 => "confirmedPrivateTransfer".to_string(),
+        19 => "confirmedTextMessage".to_string(),
+        20 => "reinitializeDevice".to_string(),
+        26 => "readRange".to_string(),
+        28 => "subscribeCOVProperty".to_string(),
+        29 => "getEventInformation".to_string(),
+        _  => format!("confirmed service {}", service),
+    }
+}
+
+/// get the name of an unconfirmed service
+pub fn unconfirmed_name(service: u8) -> String {
+    match service {
+        SVC_I_AM   => "i-Am".to_string(),
+        1          => "i-Have".to_string(),
+        2          => "unconfirmedCOVNotification".to_string(),
+        3          => "unconfirmedEventNotification".to_string(),
+        4          => "unconfirmedPrivateTransfer".to_string(),
+        5          => "unconfirmedTextMessage".to_string(),
+        6          => "timeSynchronization".to_string(),
+        7          => "who-Has".to_string(),
+        SVC_WHO_IS => "who-Is".to_string(),
+        9          => "utcTimeSynchronization".to_string(),
+        10         => "writeGroup".to_string(),
+        _          => format!("unconfirmed service {}", service),
+    }
+}
+
+/// get the name of an object type
+pub fn object_type_name(kind: u16) -> String {
+    match kind {
+        0  => "analog-input".to_string(),
+        1  => "analog-output".to_string(),
+        2  => "analog-value".to_string(),
+        3  => "binary-input".to_string(),
+        4  => "binary-output".to_string(),
+        5  => "binary-value".to_string(),
+        6  => "calendar".to_string(),
+        7  => "command".to_string(),
+        OBJECT_DEVICE => "device".to_string(),
+        9  => "event-enrollment".to_string(),
+        10 => "file".to_string(),
+        11 => "group".to_string(),
+        12 => "loop".to_string(),
+        13 => "multi-state-input".to_string(),
+        14 => "multi-state-output".to_string(),
+        15 => "notification-class".to_string(),
+        16 => "program".to_string(),
+        17 => "schedule".to_string(),
+        18 => "averaging".to_string(),
+        19 => "multi-state-value".to_string(),
+        20 => "trend-log".to_string(),
+        _  => format!("object-type-{}", kind),
+    }
+}
+
+/// get the name of a property identifier
+pub fn property_name(prop: u32) -> String {
+    match prop {
+        8   => "all".to_string(),
+        12  => "application-software-version".to_string(),
+        28  => "description".to_string(),
+        36  => "event-state".to_string(),
+        PROP_FIRMWARE => "firmware-revision".to_string(),
+        56  => "local-date".to_string(),
+        57  => "local-time".to_string(),
+        62  => "max-apdu-length-accepted".to_string(),
+        PROP_MODEL_NAME => "model-name".to_string(),
+        75  => "object-identifier".to_string(),
+        76  => "object-list".to_string(),
+        PROP_OBJECT_NAME => "object-name".to_string(),
+        79  => "object-type".to_string(),
+        80  => "optional".to_string(),
+        81  => "out-of-service".to_string(),
+        85  => "present-value".to_string(),
+        87  => "priority-array".to_string(),
+        96  => "protocol-object-types-supported".to_string(),
+        97  => "protocol-services-supported".to_string(),
+        98  => "protocol-version".to_string(),
+        103 => "reliability".to_string(),
+        104 => "relinquish-default".to_string(),
+        105 => "required".to_string(),
+        107 => "segmentation-supported".to_string(),
+        111 => "status-flags".to_string(),
+        112 => "system-status".to_string(),
+        117 => "units".to_string(),
+        120 => "vendor-identifier".to_string(),
+        PROP_VENDOR_NAME => "vendor-name".to_string(),
+        139 => "protocol-revision".to_string(),
+        _   => format!("property-{}", prop),
+    }
+}
+
+/// get the name of a vendor id, only some well known ones
+pub fn vendor_name(vendor: u32) -> String {
+    match vendor {
+        0  => "ASHRAE".to_string(),
+        2  => "Trane".to_string(),
+        5  => "Johnson Controls".to_string(),
+        7  => "Siemens".to_string(),
+        8  => "Delta Controls".to_string(),
+        10 => "Schneider Electric".to_string(),
+        17 => "Honeywell".to_string(),
+        24 => "Automated Logic".to_string(),
+        _  => format!("vendor {}", vendor),
+    }
+}
+
+/// an encoded BACnet tag
+struct Tag<'a> {
+    number:     u8,
+    context:    bool,
+    opening:    bool,
+    closing:    bool,
+    data:       &'a [u8],
+}
+
+/// read the tag at `pos`, returns the tag and the position after it
+fn read_tag(buf: &[u8], pos: usize) -> Option<(Tag<'_>, usize)> {
+
+    let first = *buf.get(pos)?;
+    let mut pos = pos + 1;
+
+    let mut number = first >> 4;
+    if number == 0x0f {
+        number = *buf.get(pos)?;
+        pos += 1;
+    }
+    let context = first & 0x08 != 0;
+    let lvt = first & 0x07;
+
+    let mut tag = Tag {
+        number, context,
+        opening: context && lvt == 6,
+        closing: context && lvt == 7,
+        data: &[],
+    };
+    // booleans keep their value in the length field
+    if tag.opening || tag.closing || (!context && number == TAG_BOOLEAN) {
+        return Some((tag, pos));
+    }
+
+    let len = if lvt == 5 {
+        let len = *buf.get(pos)?;
+        pos += 1;
+        match len {
+            254 => {
+                pos += 2;
+                be16(buf, pos-2)? as usize
+            },
+            255 => return None,
+            _ => len as usize,
+        }
+    } else {
+        lvt as usize
+    };
+
+    tag.data = buf.get(pos..pos+len)?;
+    Some((tag, pos + len))
+}
+
+/// decode an unsigned value of up to four bytes
+fn unsigned(data: &[u8]) -> Option<u32> {
+    if data.is_empty() || data.len() > 4 {
+        return None;
+    }
+    Some(data.iter().fold(0, |v, b| v << 8 | *b as u32))
+}
+
+/// split an object identifier into type and instance
+fn object_id(data: &[u8]) -> Option<(u16, u32)> {
+    if data.len() != 4 {
+        return None;
+    }
+    let v = unsigned(data)?;
+    Some(((v >> 22) as u16, v & 0x3fffff))
+}
+
+/// decode a character string, only UTF-8/ANSI is supported
+fn character_string(data: &[u8]) -> Option<String> {
+    match data.split_first() {
+        Some((0, s)) => Some(String::from_utf8_lossy(s).to_string()),
+        _ => None,
+    }
+}
+
+/// the properties a request names, as (object type, instance, property).
+/// ReadProperty/WriteProperty name them at depth 0, the multiple
+/// variants list them in context tag 1.
+fn properties(service: u8, buf: &[u8]) -> Vec<(u16, u32, u32)> {
+
+    let multi = service == SVC_READ_PROP_MULTI
+        || service == SVC_WRITE_PROP_MULTI;
+
+    let mut found = Vec::new();
+    let mut object = None;
+    let mut depth = 0;
+    let mut pos = 0;
+
+    while let Some((tag, next)) = read_tag(buf, pos) {
+        pos = next;
+        if tag.opening {
+            depth += 1;
+            continue;
+        }
+        if tag.closing {
+            depth -= 1;
+            continue;
+        }
+        if !tag.context {
+            continue;
+        }
+        match (depth, tag.number) {
+            (0, 0) => object = object_id(tag.data),
+            (0, 1) if !multi => {
+                if let (Some((kind, instance)), Some(prop)) =
+                    (object, unsigned(tag.data)) {
+                    found.push((kind, instance, prop));
+                }
+            },
+            (1, 0) if multi => {
+                if let (Some((kind, instance)), Some(prop)) =
+                    (object, unsigned(tag.data)) {
+                    found.push((kind, instance, prop));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    found
+}
+
+/// a device that answered with I-Am
+#[derive(Default)]
+pub struct Device {
+    pub ip:         Option<Ipv4Addr>,
+    /// network number and MAC of devices behind a BACnet router
+    pub network:    Option<(u16, String)>,
+    pub vendor:     Option<u32>,
+    pub max_apdu:   Option<u32>,
+    /// properties of the device object learned from ReadProperty
+    pub properties: BTreeMap<u32, String>,
+}
+
+/// everything we saw between two hosts
+#[derive(Default)]
+pub struct Pair {
+    pub services:   BTreeMap<String, u64>,
+    pub reads:      BTreeSet<(u16, u32, u32)>,
+    pub writes:     BTreeSet<(u16, u32, u32)>,
+}
+
+/// what we learned from the BACnet traffic in the capture
+#[derive(Default)]
+pub struct BacnetInfo {
+    /// devices keyed by device instance
+    pub devices:    BTreeMap<u32, Device>,
+    pub pairs:      BTreeMap<(Ipv4Addr, Ipv4Addr), Pair>,
+}
+
+/// the parts of a NPDU we need
+struct Npdu<'a> {
+    /// source network and MAC if the message was routed
+    source:     Option<(u16, String)>,
+    apdu:       &'a [u8],
+}
+
+/// parse the network layer header, `None` for network messages
+fn parse_npdu(buf: &[u8]) -> Option<Npdu<'_>> {
+
+    if *buf.first()? != 0x01 {
+        return None;
+    }
+    let control = *buf.get(1)?;
+    if control & NPDU_NETWORK_MSG != 0 {
+        return None;
+    }
+
+    let mut pos = 2;
+    if control & NPDU_DNET != 0 {
+        let len = *buf.get(pos+2)? as usize;
+        pos += 3 + len;
+    }
+    let mut source = None;
+    if control & NPDU_SNET != 0 {
+        let net = be16(buf, pos)?;
+        let len = *buf.get(pos+2)? as usize;
+        let mac = buf.get(pos+3..pos+3+len)?;
+        source = Some((net, mac.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":")));
+        pos += 3 + len;
+    }
+    // hop count
+    if control & NPDU_DNET != 0 {
+        pos += 1;
+    }
+
+    Some(Npdu { source, apdu: buf.get(pos..)? })
+}
+
+impl BacnetInfo {
+
+    /// handle a BACnet/IP datagram
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        if payload.len() < 4 || payload[0] != BVLC_TYPE {
+            return;
+        }
+
+        let (sender, npdu) = match payload[1] {
+            BVLC_UNICAST | BVLC_BROADCAST | BVLC_DISTRIBUTE => {
+                (flow.sip, &payload[4..])
+            },
+            // the B/IP address of the original sender comes first
+            BVLC_FORWARDED => match payload.get(10..) {
+                Some(n) => {
+                    let ip = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+                    (ip, n)
+                },
+                None => return,
+            },
+            _ => return,
+        };
+
+        let Some(npdu) = parse_npdu(npdu) else {
+            return;
+        };
+        let apdu = npdu.apdu;
+        let Some(&first) = apdu.first() else {
+            return;
+        };
+
+        match first >> 4 {
+            APDU_CONFIRMED => {
+                let mut pos = 3;
+                if first & APDU_SEGMENTED != 0 {
+                    pos += 2;
+                }
+                let Some(&service) = apdu.get(pos) else {
+                    return;
+                };
+                let pair = self.pairs.entry((flow.sip, flow.dip)).or_default();
+                *pair.services.entry(confirmed_name(service)).or_insert(0) += 1;
+                let props = properties(service, &apdu[pos+1..]);
+                match service {
+                    SVC_READ_PROPERTY | SVC_READ_PROP_MULTI => {
+                        pair.reads.extend(props)
+                    },
+                    SVC_WRITE_PROPERTY | SVC_WRITE_PROP_MULTI => {
+                        pair.writes.extend(props)
+                    },
+                    _ => (),
+                }
+            },
+            APDU_UNCONFIRMED => {
+                let Some(&service) = apdu.get(1) else {
+                    return;
+                };
+                let pair = self.pairs.entry((flow.sip, flow.dip)).or_default();
+                *pair.services.entry(unconfirmed_name(service)).or_insert(0) += 1;
+                if service == SVC_I_AM {
+                    self.i_am(sender, npdu.source, &apdu[2..]);
+                }
+            },
+            APDU_COMPLEX_ACK => {
+                let mut pos = 2;
+                if first & APDU_SEGMENTED != 0 {
+                    pos += 2;
+                }
+                if apdu.get(pos) == Some(&SVC_READ_PROPERTY) {
+                    self.read_property_ack(&apdu[pos+1..]);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// device instance, max APDU, segmentation and vendor of an I-Am
+    /// the device at `ip` sent
+    fn i_am(&mut self, ip: Ipv4Addr, source: Option<(u16, String)>, buf: &[u8]) {
+
+        let mut tags = Vec::new();
+        let mut pos = 0;
+        while let Some((tag, next)) = read_tag(buf, pos) {
+            tags.push(tag);
+            pos = next;
+        }
+
+        let Some(Some((OBJECT_DEVICE, instance))) = tags.first()
+            .filter(|t| !t.context && t.number == TAG_OBJECT_ID)
+            .map(|t| object_id(t.data)) else {
+            return;
+        };
+
+        let device = self.devices.entry(instance).or_default();
+        device.ip = Some(ip);
+        device.network = source;
+        device.max_apdu = tags.get(1)
+            .filter(|t| t.number == TAG_UNSIGNED)
+            .and_then(|t| unsigned(t.data));
+        device.vendor = tags.get(3)
+            .filter(|t| t.number == TAG_UNSIGNED)
+            .and_then(|t| unsigned(t.data));
+    }
+
+    /// names, model and firmware of devices from ReadProperty answers
+    fn read_property_ack(&mut self, buf: &[u8]) {
+
+        let Some((object, next)) = read_tag(buf, 0) else {
+            return;
+        };
+        let Some((prop, next)) = read_tag(buf, next) else {
+            return;
+        };
+        let Some((OBJECT_DEVICE, instance)) = object_id(object.data) else {
+            return;
+        };
+        let Some(prop) = unsigned(prop.data) else {
+            return;
+        };
+        if !matches!(prop, PROP_FIRMWARE | PROP_MODEL_NAME | PROP_OBJECT_NAME
+            | PROP_VENDOR_NAME) {
+            return;
+        }
+
+        // opening tag 3, then the value
+        let Some((open, next)) = read_tag(buf, next) else {
+            return;
+        };
+        if !open.opening {
+            return;
+        }
+        if let Some((value, _)) = read_tag(buf, next) {
+            if !value.context && value.number == TAG_CHARACTER_STRING {
+                if let Some(s) = character_string(value.data) {
+                    self.devices.entry(instance).or_default()
+                        .properties.insert(prop, s);
+                }
+            }
+        }
+    }
+
+    /// write the BACnet sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if !self.devices.is_empty() {
+
+            write!(file, "\n\n-- BACnet Devices\n")?;
+
+            for (instance, device) in &self.devices {
+
+                let ip = device.ip.map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                write!(file, "device {:<8} {}", instance, ip)?;
+                if let Some((net, mac)) = &device.network {
+                    write!(file, " (network {} mac {})", net, mac)?;
+                }
+                write!(file, "\n")?;
+
+                let prop = |p| device.properties.get(&p);
+                if let Some(name) = prop(PROP_OBJECT_NAME) {
+                    write!(file, "    name:     {}\n", name)?;
+                }
+                match (device.vendor, prop(PROP_VENDOR_NAME)) {
+                    (Some(id), Some(name)) => write!(file,
+                        "    vendor:   {} ({})\n", name, vendor_name(id))?,
+                    (Some(id), None) => write!(file,
+                        "    vendor:   {}\n", vendor_name(id))?,
+                    (None, Some(name)) => write!(file,
+                        "    vendor:   {}\n", name)?,
+                    (None, None) => (),
+                }
+                if let Some(model) = prop(PROP_MODEL_NAME) {
+                    write!(file, "    model:    {}\n", model)?;
+                }
+                if let Some(firmware) = prop(PROP_FIRMWARE) {
+                    write!(file, "    firmware: {}\n", firmware)?;
+                }
+                if let Some(max_apdu) = device.max_apdu {
+                    write!(file, "    max apdu: {}\n", max_apdu)?;
+                }
+            }
+        }
+
+        if !self.pairs.is_empty() {
+
+            write!(file, "\n\n-- BACnet (source -> destination)\n")?;
+
+            let props = |set: &BTreeSet<(u16, u32, u32)>| {
+                set.iter()
+                    .map(|(kind, instance, prop)| format!("{}:{} {}",
+                        object_type_name(*kind), instance, property_name(*prop)))
+                    .collect::<Vec<_>>()
+            };
+
+            for ((src, dst), pair) in &self.pairs {
+                write!(file, "{} -> {}\n", src, dst)?;
+                for (service, count) in &pair.services {
+                    write!(file, "    service: {} ({}x)\n", service, count)?;
+                }
+                for prop in props(&pair.reads) {
+                    write!(file, "    read:    {}\n", prop)?;
+                }
+                for prop in props(&pair.writes) {
+                    write!(file, "    write:   {}\n", prop)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
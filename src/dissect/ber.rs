@@ -0,0 +1,146 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! a minimal reader for ASN.1 BER/DER encoded data, just enough to walk
+//! certificates and the like. only definite lengths and single byte tags
+//! are supported.
+//!
+
+/// universal tags
+pub const TAG_BOOLEAN:      u8 = 0x01;
+pub const TAG_INTEGER:      u8 = 0x02;
+pub const TAG_BIT_STRING:   u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL:         u8 = 0x05;
+pub const TAG_OID:          u8 = 0x06;
+pub const TAG_UTC_TIME:     u8 = 0x17;
+pub const TAG_GEN_TIME:     u8 = 0x18;
+pub const TAG_SEQUENCE:     u8 = 0x30;
+pub const TAG_SET:          u8 = 0x31;
+
+/// a single tag-length-value element
+#[derive(Clone, Copy)]
+pub struct Tlv<'a> {
+    pub tag:    u8,
+    pub value:  &'a [u8],
+    /// the complete encoding, header included
+    pub raw:    &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+
+    /// the elements contained in a constructed element
+    pub fn children(&self) -> Vec<Tlv<'a>> {
+        let mut items = Vec::new();
+        let mut rest = self.value;
+        while let Some((tlv, next)) = read(rest) {
+            items.push(tlv);
+            rest = next;
+        }
+        items
+    }
+
+    /// the value as unsigned integer, `None` if it does not fit
+    pub fn uint(&self) -> Option<u64> {
+        if self.value.is_empty() || self.value.len() > 9 {
+            return None;
+        }
+        let mut v: u64 = 0;
+        for (i, b) in self.value.iter().enumerate() {
+            // the 9th byte may only be a leading zero
+            if i == 0 && self.value.len() == 9 && *b != 0 {
+                return None;
+            }
+            v = v << 8 | *b as u64;
+        }
+        Some(v)
+    }
+
+    /// the value as text, for the various string types
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(self.value).to_string()
+    }
+
+    /// the value as dotted object identifier
+    pub fn oid(&self) -> String {
+        oid_string(self.value)
+    }
+
+}
+
+/// read the element at the start of `buf`, returns it and the data
+/// behind it
+pub fn read(buf: &[u8]) -> Option<(Tlv<'_>, &[u8])> {
+
+    let tag = *buf.first()?;
+
+    // high tag numbers span several bytes
+    if tag & 0x1f == 0x1f {
+        return None;
+    }
+
+    let first = *buf.get(1)? as usize;
+    let (len, hdr) = if first & 0x80 == 0 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        // indefinite lengths and lengths beyond 4 bytes are not supported
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for b in buf.get(2..2+n)? {
+            len = len << 8 | *b as usize;
+        }
+        (len, 2 + n)
+    };
+
+    let value = buf.get(hdr..hdr+len)?;
+    Some((Tlv { tag, value, raw: &buf[..hdr+len] }, &buf[hdr+len..]))
+}
+
+/// decode an object identifier to its dotted form
+pub fn oid_string(value: &[u8]) -> String {
+
+    let mut parts: Vec<u64> = Vec::new();
+    let mut acc: u64 = 0;
+
+    for (i, b) in value.iter().enumerate() {
+        acc = acc << 7 | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if parts.is_empty() {
+                // the first arc holds the first two numbers
+                let first = (acc / 40).min(2);
+                parts.push(first);
+                parts.push(acc - first * 40);
+            } else {
+                parts.push(acc);
+            }
+            acc = 0;
+        } else if i == value.len() - 1 {
+            // truncated
+            break;
+        }
+    }
+
+    parts.iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -0,0 +1,212 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! CoAP over UDP. the URI of a request is spread over Uri-Path and
+//! Uri-Query options, which are delta encoded.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+
+use crate::dissect::{Flow, be16};
+
+/// CoAP server port
+pub const COAP_PORT: u16 = 5683;
+
+/// CoAP version 1
+const VERSION: u8 = 1;
+
+/// marks the start of the payload
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// option numbers
+const OPT_OBSERVE:      u16 = 6;
+const OPT_URI_PATH:     u16 = 11;
+const OPT_URI_QUERY:    u16 = 15;
+
+/// get the name of a request method
+fn method_name(code: u8) -> &'static str {
+    match code {
+        1 => "GET",
+        2 => "POST",
+        3 => "PUT",
+        4 => "DELETE",
+        5 => "FETCH",
+        6 => "PATCH",
+        7 => "iPATCH",
+        _ => "unknown",
+    }
+}
+
+/// format a response code as class.detail
+fn response_name(code: u8) -> String {
+    let name = match code {
+        0x41 => " Created",
+        0x42 => " Deleted",
+        0x43 => " Valid",
+        0x44 => " Changed",
+        0x45 => " Content",
+        0x80 => " Bad Request",
+        0x81 => " Unauthorized",
+        0x83 => " Forbidden",
+        0x84 => " Not Found",
+        0x85 => " Method Not Allowed",
+        0xa0 => " Internal Server Error",
+        _ => "",
+    };
+    format!("{}.{:02}{}", code >> 5, code & 0x1f, name)
+}
+
+/// read the extended value of an option delta or length nibble
+fn extended(buf: &[u8], pos: &mut usize, nibble: u8) -> Option<u16> {
+    match nibble {
+        13 => {
+            let v = *buf.get(*pos)? as u16 + 13;
+            *pos += 1;
+            Some(v)
+        },
+        14 => {
+            let v = be16(buf, *pos)?.checked_add(269)?;
+            *pos += 2;
+            Some(v)
+        },
+        15 => None,
+        n => Some(n as u16),
+    }
+}
+
+/// split the options of a message into number and value
+fn options(buf: &[u8]) -> Vec<(u16, &[u8])> {
+
+    let mut options = Vec::new();
+    let mut pos = 0;
+    let mut number = 0u16;
+
+    while let Some(&b) = buf.get(pos) {
+        if b == PAYLOAD_MARKER {
+            break;
+        }
+        pos += 1;
+        let (Some(delta), Some(len)) =
+            (extended(buf, &mut pos, b >> 4), extended(buf, &mut pos, b & 0x0f)) else {
+            break;
+        };
+        let Some(value) = buf.get(pos..pos+len as usize) else {
+            break;
+        };
+        number = number.saturating_add(delta);
+        options.push((number, value));
+        pos += len as usize;
+    }
+
+    options
+}
+
+/// what a client asked a server
+#[derive(Default)]
+pub struct Exchange {
+    /// requests keyed by method and URI
+    pub requests:   BTreeMap<(String, String), u64>,
+    pub responses:  BTreeMap<String, u64>,
+}
+
+/// what we learned from the CoAP traffic in the capture
+#[derive(Default)]
+pub struct CoapInfo {
+    /// keyed by server and client
+    pub exchanges:  BTreeMap<(Ipv4Addr, Ipv4Addr), Exchange>,
+}
+
+impl CoapInfo {
+
+    /// handle a UDP payload on the CoAP port
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let (Some(&first), Some(&code)) = (payload.first(), payload.get(1)) else {
+            return;
+        };
+        if first >> 6 != VERSION || payload.len() < 4 {
+            return;
+        }
+
+        // the token follows the fixed header, then the options
+        let token_len = (first & 0x0f) as usize;
+        let Some(rest) = payload.get(4+token_len..) else {
+            return;
+        };
+
+        // class 0 are requests, 0.00 is an empty message
+        if code >> 5 == 0 {
+            if code == 0 {
+                return;
+            }
+            let mut path = Vec::new();
+            let mut query = Vec::new();
+            let mut observe = false;
+            for (number, value) in options(rest) {
+                let value = String::from_utf8_lossy(value).to_string();
+                match number {
+                    OPT_URI_PATH => path.push(value),
+                    OPT_URI_QUERY => query.push(value),
+                    OPT_OBSERVE => observe = true,
+                    _ => (),
+                }
+            }
+            let mut uri = format!("/{}", path.join("/"));
+            if !query.is_empty() {
+                uri = format!("{}?{}", uri, query.join("&"));
+            }
+            let mut method = method_name(code).to_string();
+            if observe {
+                method.push_str(" (observe)");
+            }
+            let exchange = self.exchanges.entry((flow.dip, flow.sip)).or_default();
+            *exchange.requests.entry((method, uri)).or_insert(0) += 1;
+        } else {
+            let exchange = self.exchanges.entry((flow.sip, flow.dip)).or_default();
+            *exchange.responses.entry(response_name(code)).or_insert(0) += 1;
+        }
+    }
+
+    /// write the CoAP section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.exchanges.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- CoAP Resources (server <- client)\n")?;
+
+        for ((server, client), e) in &self.exchanges {
+            write!(file, "{} <- {}\n", server, client)?;
+            for ((method, uri), count) in &e.requests {
+                write!(file, "    {} {} ({}x)\n", method, uri, count)?;
+            }
+            for (response, count) in &e.responses {
+                write!(file, "    response {} ({}x)\n", response, count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
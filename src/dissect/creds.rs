@@ -0,0 +1,461 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! credentials sent in the clear and the insecure protocols each host
+//! uses. secrets are redacted in the report unless asked otherwise.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::util;
+use crate::dissect::{Flow, http, mqtt, snmp};
+use crate::dissect::framing::Framing;
+
+/// server ports of the cleartext protocols with logins
+pub const FTP_PORT:         u16 = 21;
+pub const TELNET_PORT:      u16 = 23;
+pub const SMTP_PORT:        u16 = 25;
+pub const SUBMISSION_PORT:  u16 = 587;
+pub const POP3_PORT:        u16 = 110;
+pub const IMAP_PORT:        u16 = 143;
+
+/// telnet commands
+const IAC:  u8 = 0xff;
+const SB:   u8 = 0xfa;
+const SE:   u8 = 0xf0;
+const WILL: u8 = 0xfb;
+
+/// get the protocol running on a server port
+fn port_protocol(port: u16) -> Option<&'static str> {
+    match port {
+        FTP_PORT                    => Some("FTP"),
+        TELNET_PORT                 => Some("Telnet"),
+        SMTP_PORT | SUBMISSION_PORT => Some("SMTP"),
+        POP3_PORT                   => Some("POP3"),
+        IMAP_PORT                   => Some("IMAP"),
+        mqtt::MQTT_PORT             => Some("MQTT"),
+        _                           => None,
+    }
+}
+
+/// how the data of `flow` is framed for `CredsInfo::tcp`, `None` for
+/// telnet where every byte counts
+pub fn framing(flow: &Flow) -> Option<Framing> {
+    match port_protocol(flow.dport).or(port_protocol(flow.sport)) {
+        Some("Telnet") => None,
+        Some("MQTT") => Some(Framing::Mqtt),
+        Some(_) => Some(Framing::Lines),
+        None => Some(Framing::Http),
+    }
+}
+
+/// the part of a login we have seen so far on a connection
+enum Pending {
+    /// USER was sent, PASS comes next
+    User(String),
+    /// SASL PLAIN without initial response, the next line has it
+    Plain,
+    /// SASL LOGIN, the next lines have user name and password
+    LoginUser,
+    LoginPass(String),
+    /// telnet prompted for the user name, typed so far
+    TelnetUser(String),
+    /// telnet user name complete, waiting for the password prompt
+    TelnetWait(String),
+    /// telnet prompted for the password, user name and typed so far
+    TelnetPass(String, String),
+    /// SMTP DATA was sent, the message runs up to a line with a dot
+    MailBody,
+}
+
+/// true if `command` carries a login in `protocol`
+fn login_command(protocol: &str, command: &str) -> bool {
+    matches!((protocol, command),
+        ("FTP" | "POP3", "USER" | "PASS")
+        | ("IMAP", "LOGIN" | "AUTHENTICATE")
+        | ("SMTP" | "POP3", "AUTH"))
+}
+
+/// split SASL PLAIN into user name and password, the authorization
+/// identity comes first
+fn sasl_plain(text: &str) -> Option<(String, String)> {
+    let decoded = util::base64(text)?;
+    let mut parts = decoded.split(|b| *b == 0).skip(1);
+    let user = String::from_utf8_lossy(parts.next()?).to_string();
+    let password = String::from_utf8_lossy(parts.next()?).to_string();
+    Some((user, password))
+}
+
+/// decode a base64 line of SASL LOGIN
+fn sasl_text(text: &str) -> String {
+    util::base64(text)
+        .map(|b| String::from_utf8_lossy(&b).to_string())
+        .unwrap_or_default()
+}
+
+/// remove telnet option negotiation from a segment
+fn telnet_data(buf: &[u8]) -> Vec<u8> {
+
+    let mut data = Vec::new();
+    let mut pos = 0;
+
+    while let Some(&b) = buf.get(pos) {
+        if b != IAC {
+            data.push(b);
+            pos += 1;
+            continue;
+        }
+        match buf.get(pos+1) {
+            Some(&IAC) => {
+                data.push(IAC);
+                pos += 2;
+            },
+            Some(&SB) => {
+                // skip to the end of the subnegotiation
+                pos += 2;
+                while pos + 1 < buf.len() && !(buf[pos] == IAC && buf[pos+1] == SE) {
+                    pos += 1;
+                }
+                pos += 2;
+            },
+            // WILL, WONT, DO and DONT carry an option
+            Some(&cmd) if cmd >= WILL => pos += 3,
+            _ => pos += 2,
+        }
+    }
+
+    data
+}
+
+/// a user name and a secret
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct Credential {
+    /// `None` for SNMP communities
+    pub user:   Option<String>,
+    pub secret: String,
+}
+
+/// what we learned about cleartext logins in the capture
+#[derive(Default)]
+pub struct CredsInfo {
+    /// show secrets in the report instead of redacting them
+    pub reveal:         bool,
+    /// keyed by server, client and protocol
+    pub credentials:    BTreeMap<(Ipv4Addr, Ipv4Addr, &'static str), BTreeSet<Credential>>,
+    /// insecure protocols and the role a host has in them
+    pub hosts:          BTreeMap<Ipv4Addr, BTreeSet<(&'static str, &'static str)>>,
+    /// logins in progress keyed by client, client port, server and
+    /// server port
+    pending:            BTreeMap<(Ipv4Addr, u16, Ipv4Addr, u16), Pending>,
+}
+
+impl CredsInfo {
+
+    /// remember that two hosts talk an insecure protocol
+    fn uses(&mut self, client: Ipv4Addr, server: Ipv4Addr, protocol: &'static str,
+        roles: (&'static str, &'static str)) {
+        self.hosts.entry(client).or_default().insert((protocol, roles.0));
+        self.hosts.entry(server).or_default().insert((protocol, roles.1));
+    }
+
+    fn add(&mut self, server: Ipv4Addr, client: Ipv4Addr, protocol: &'static str,
+        user: Option<String>, secret: String) {
+        self.credentials.entry((server, client, protocol))
+            .or_default()
+            .insert(Credential { user, secret });
+    }
+
+    /// handle TCP data, framed as `framing` says
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        // HTTP runs on all kinds of ports
+        if let Some(http::HttpMessage::Request { headers, .. }) = http::parse(payload) {
+            self.uses(flow.sip, flow.dip, "HTTP", ("client", "server"));
+            for name in ["Authorization", "Proxy-Authorization"] {
+                let basic = http::header(&headers, name)
+                    .and_then(|v| v.strip_prefix("Basic "))
+                    .and_then(util::base64);
+                if let Some(decoded) = basic {
+                    let decoded = String::from_utf8_lossy(&decoded).to_string();
+                    let (user, password) = decoded.split_once(':').unwrap_or((&decoded, ""));
+                    self.add(flow.dip, flow.sip, "HTTP Basic",
+                        Some(user.to_string()), password.to_string());
+                }
+            }
+            return;
+        }
+
+        let (to_server, protocol) = match (port_protocol(flow.dport), port_protocol(flow.sport)) {
+            (Some(p), _) => (true, p),
+            (None, Some(p)) => (false, p),
+            _ => return,
+        };
+        let (client, server) = if to_server { (flow.sip, flow.dip) } else { (flow.dip, flow.sip) };
+        self.uses(client, server, protocol, ("client", "server"));
+
+        let key = if to_server {
+            (flow.sip, flow.sport, flow.dip, flow.dport)
+        } else {
+            (flow.dip, flow.dport, flow.sip, flow.sport)
+        };
+
+        match (protocol, to_server) {
+            ("MQTT", true) => {
+                for (user, password) in mqtt::credentials(payload) {
+                    self.add(server, client, protocol, Some(user), password);
+                }
+            },
+            ("Telnet", false) => self.telnet_prompt(key, payload),
+            ("Telnet", true) => self.telnet_input(key, protocol, payload),
+            (_, true) => {
+                let text = String::from_utf8_lossy(payload).to_string();
+                for line in text.lines() {
+                    self.line(key, protocol, line.trim_end_matches('\r'));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// handle a command line a client sent to FTP, POP3, IMAP or SMTP
+    fn line(&mut self, key: (Ipv4Addr, u16, Ipv4Addr, u16), protocol: &'static str,
+        line: &str) {
+
+        let (client, _, server, _) = key;
+
+        // continuation lines of a SASL exchange, "*" cancels it
+        match self.pending.remove(&key) {
+            Some(Pending::Plain) if line != "*" => {
+                if let Some((user, password)) = sasl_plain(line) {
+                    self.add(server, client, protocol, Some(user), password);
+                }
+                return;
+            },
+            Some(Pending::LoginUser) if line != "*" => {
+                self.pending.insert(key, Pending::LoginPass(sasl_text(line)));
+                return;
+            },
+            Some(Pending::LoginPass(user)) if line != "*" => {
+                self.add(server, client, protocol, Some(user), sasl_text(line));
+                return;
+            },
+            Some(Pending::User(user)) => {
+                self.pending.insert(key, Pending::User(user));
+            },
+            // the lines of a mail are not commands
+            Some(Pending::MailBody) => {
+                if line != "." {
+                    self.pending.insert(key, Pending::MailBody);
+                }
+                return;
+            },
+            _ => (),
+        }
+
+        let mut words = line.split_whitespace();
+        let mut command = words.next().unwrap_or("").to_uppercase();
+        // IMAP commands come after a tag
+        if protocol == "IMAP" {
+            command = words.next().unwrap_or("").to_uppercase();
+        }
+
+        if protocol == "SMTP" && command == "DATA" {
+            self.pending.insert(key, Pending::MailBody);
+            return;
+        }
+        if !login_command(protocol, &command) {
+            return;
+        }
+
+        match command.as_str() {
+            "USER" => {
+                let user = words.collect::<Vec<_>>().join(" ");
+                self.pending.insert(key, Pending::User(user));
+            },
+            "PASS" => {
+                let user = match self.pending.remove(&key) {
+                    Some(Pending::User(user)) => user,
+                    _ => String::new(),
+                };
+                let password = words.collect::<Vec<_>>().join(" ");
+                self.add(server, client, protocol, Some(user), password);
+            },
+            "LOGIN" => {
+                let mut arg = || words.next().unwrap_or("").trim_matches('"').to_string();
+                let user = arg();
+                let password = arg();
+                self.add(server, client, protocol, Some(user), password);
+            },
+            "AUTH" | "AUTHENTICATE" => {
+                let mechanism = words.next().unwrap_or("").to_uppercase();
+                let initial = words.next();
+                match (mechanism.as_str(), initial) {
+                    ("PLAIN", Some(ir)) => {
+                        if let Some((user, password)) = sasl_plain(ir) {
+                            self.add(server, client, protocol, Some(user), password);
+                        }
+                    },
+                    ("PLAIN", None) => {
+                        self.pending.insert(key, Pending::Plain);
+                    },
+                    ("LOGIN", Some(ir)) => {
+                        self.pending.insert(key, Pending::LoginPass(sasl_text(ir)));
+                    },
+                    ("LOGIN", None) => {
+                        self.pending.insert(key, Pending::LoginUser);
+                    },
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// look for login and password prompts a telnet server sends
+    fn telnet_prompt(&mut self, key: (Ipv4Addr, u16, Ipv4Addr, u16), payload: &[u8]) {
+
+        let text = String::from_utf8_lossy(&telnet_data(payload)).to_lowercase();
+
+        if text.contains("password:") {
+            let user = match self.pending.remove(&key) {
+                Some(Pending::TelnetWait(user) | Pending::TelnetUser(user)) => user,
+                _ => String::new(),
+            };
+            self.pending.insert(key, Pending::TelnetPass(user, String::new()));
+        } else if text.contains("login:") || text.contains("username:") {
+            self.pending.insert(key, Pending::TelnetUser(String::new()));
+        }
+    }
+
+    /// collect what a telnet client types after a prompt, often a
+    /// character per segment
+    fn telnet_input(&mut self, key: (Ipv4Addr, u16, Ipv4Addr, u16),
+        protocol: &'static str, payload: &[u8]) {
+
+        let (client, _, server, _) = key;
+
+        for b in telnet_data(payload) {
+            let Some(pending) = self.pending.get_mut(&key) else {
+                return;
+            };
+            let typed = match pending {
+                Pending::TelnetUser(typed) | Pending::TelnetPass(_, typed) => typed,
+                _ => return,
+            };
+            match b {
+                b'\r' | b'\n' => {
+                    match self.pending.remove(&key) {
+                        Some(Pending::TelnetUser(user)) if !user.is_empty() => {
+                            self.pending.insert(key, Pending::TelnetWait(user));
+                        },
+                        Some(Pending::TelnetPass(user, password)) => {
+                            self.add(server, client, protocol, Some(user), password);
+                        },
+                        _ => (),
+                    }
+                },
+                // backspace and delete
+                0x08 | 0x7f => {
+                    typed.pop();
+                },
+                b if b.is_ascii_graphic() || b == b' ' => typed.push(b as char),
+                _ => (),
+            }
+        }
+    }
+
+    /// handle a UDP payload on one of the SNMP ports
+    pub fn snmp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(message) = snmp::parse(payload) else {
+            return;
+        };
+        let Some(community) = message.community else {
+            return;
+        };
+
+        // requests go to 161, traps to 162, both carry the community
+        match flow.dport {
+            snmp::SNMP_PORT => {
+                self.uses(flow.sip, flow.dip, "SNMPv1/v2c", ("manager", "agent"));
+            },
+            snmp::SNMP_TRAP_PORT => {
+                self.uses(flow.sip, flow.dip, "SNMPv1/v2c", ("agent", "manager"));
+            },
+            _ => return,
+        }
+        self.add(flow.dip, flow.sip, "SNMP", None, community);
+    }
+
+    /// a secret as shown in the report
+    fn secret(&self, secret: &str) -> String {
+        if self.reveal {
+            format!("\"{}\"", secret)
+        } else {
+            "<redacted>".to_string()
+        }
+    }
+
+    /// write the credential sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if !self.credentials.is_empty() {
+
+            write!(file, "\n\n-- Cleartext Credentials (server <- client)\n")?;
+            if !self.reveal {
+                write!(file, "secrets are redacted, run with --show-credentials to see them\n")?;
+            }
+
+            for ((server, client, protocol), credentials) in &self.credentials {
+                write!(file, "{} <- {} {}\n", server, client, protocol)?;
+                for c in credentials {
+                    match &c.user {
+                        Some(user) => write!(file, "    user: {}  password: {}\n",
+                            user, self.secret(&c.secret))?,
+                        None => {
+                            let weak = if snmp::weak_community(&c.secret) { "  [WEAK]" } else { "" };
+                            write!(file, "    community: {}{}\n",
+                                self.secret(&c.secret), weak)?
+                        },
+                    }
+                }
+            }
+        }
+
+        if !self.hosts.is_empty() {
+
+            write!(file, "\n\n-- Insecure Protocols per Host\n")?;
+
+            for (host, protocols) in &self.hosts {
+                let protocols = protocols.iter()
+                    .map(|(protocol, role)| format!("{} {}", protocol, role))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(file, "{:<15} {}\n", host, protocols)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,277 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::pinfo::MacAddr;
+use crate::dissect::{Flow, be32};
+
+/// DHCP server port
+pub const DHCP_SERVER_PORT: u16 = 67;
+
+/// DHCP client port
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+/// the options start behind the fixed BOOTP header and the magic cookie
+const COOKIE_OFFSET:  usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE:   u32 = 0x63825363;
+
+/// options we decode
+const OPT_PAD:          u8 = 0;
+const OPT_HOSTNAME:     u8 = 12;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_MSG_TYPE:     u8 = 53;
+const OPT_SERVER_ID:    u8 = 54;
+const OPT_PARAM_LIST:   u8 = 55;
+const OPT_VENDOR_CLASS: u8 = 60;
+const OPT_END:          u8 = 255;
+
+/// message type of a DHCPACK
+const DHCPACK: u8 = 5;
+
+/// a decoded DHCPv4 message
+pub struct DhcpMessage {
+    pub msg_type:       Option<u8>,
+    pub xid:            u32,
+    pub client_mac:     MacAddr,
+    pub ciaddr:         Ipv4Addr,
+    pub yiaddr:         Ipv4Addr,
+    pub requested_ip:   Option<Ipv4Addr>,
+    pub server_id:      Option<Ipv4Addr>,
+    pub hostname:       Option<String>,
+    pub vendor_class:   Option<String>,
+    pub param_list:     Vec<u8>,
+}
+
+/// get the name of a DHCP message type (option 53)
+pub fn msg_type_name(t: u8) -> String {
+    match t {
+        1 => "DISCOVER".to_string(),
+        2 => "OFFER".to_string(),
+        3 => "REQUEST".to_string(),
+        4 => "DECLINE".to_string(),
+        5 => "ACK".to_string(),
+        6 => "NAK".to_string(),
+        7 => "RELEASE".to_string(),
+        8 => "INFORM".to_string(),
+        _ => format!("TYPE{}", t),
+    }
+}
+
+/// parse a DHCPv4 message, plain BOOTP without options is ignored
+pub fn parse(buf: &[u8]) -> Option<DhcpMessage> {
+
+    if be32(buf, COOKIE_OFFSET)? != MAGIC_COOKIE {
+        return None;
+    }
+
+    // only ethernet hardware addresses
+    if buf[1] != 1 || buf[2] != 6 {
+        return None;
+    }
+
+    let mut msg = DhcpMessage {
+        msg_type:       None,
+        xid:            be32(buf, 4)?,
+        client_mac:     MacAddr::new(&buf[28..34]),
+        ciaddr:         Ipv4Addr::from(be32(buf, 12)?),
+        yiaddr:         Ipv4Addr::from(be32(buf, 16)?),
+        requested_ip:   None,
+        server_id:      None,
+        hostname:       None,
+        vendor_class:   None,
+        param_list:     Vec::new(),
+    };
+
+    let mut pos = OPTIONS_OFFSET;
+
+    while let Some(&code) = buf.get(pos) {
+
+        if code == OPT_END {
+            break;
+        }
+        if code == OPT_PAD {
+            pos += 1;
+            continue;
+        }
+
+        let len = *buf.get(pos+1)? as usize;
+        let data = buf.get(pos+2..pos+2+len)?;
+
+        match code {
+            OPT_MSG_TYPE if len == 1 => msg.msg_type = Some(data[0]),
+            OPT_REQUESTED_IP if len == 4 => {
+                msg.requested_ip = Some(Ipv4Addr::from(be32(data, 0)?));
+            },
+            OPT_SERVER_ID if len == 4 => {
+                msg.server_id = Some(Ipv4Addr::from(be32(data, 0)?));
+            },
+            OPT_HOSTNAME => {
+                msg.hostname = Some(option_string(data));
+            },
+            OPT_VENDOR_CLASS => {
+                msg.vendor_class = Some(option_string(data));
+            },
+            OPT_PARAM_LIST => msg.param_list = data.to_vec(),
+            _ => (),
+        }
+
+        pos += 2 + len;
+    }
+
+    Some(msg)
+}
+
+/// options are not null terminated by the spec, some clients do anyway
+fn option_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// everything we know about a single DHCP client
+#[derive(Default)]
+pub struct Lease {
+    /// address assigned by the server in a DHCPACK
+    pub assigned:       Option<Ipv4Addr>,
+    /// address the client asked for
+    pub requested:      Option<Ipv4Addr>,
+    pub server:         Option<Ipv4Addr>,
+    pub hostname:       Option<String>,
+    pub vendor_class:   Option<String>,
+    pub param_list:     Vec<u8>,
+    /// number of messages seen per message type
+    pub messages:       BTreeMap<u8, u64>,
+}
+
+/// what we learned from the DHCP traffic in the capture
+#[derive(Default)]
+pub struct DhcpInfo {
+    pub leases: HashMap<MacAddr, Lease>,
+}
+
+impl DhcpInfo {
+
+    /// handle a DHCP datagram
+    pub fn udp(&mut self, _flow: &Flow, payload: &[u8]) {
+        if let Some(msg) = parse(payload) {
+            self.update(&msg);
+        }
+    }
+
+    /// add a decoded message to the summary, messages are keyed by the
+    /// client hardware address, not the ethernet source, relays forward
+    /// them on behalf of the client
+    pub fn update(&mut self, msg: &DhcpMessage) {
+
+        let lease = self.leases.entry(msg.client_mac).or_default();
+
+        if let Some(t) = msg.msg_type {
+            *lease.messages.entry(t).or_insert(0) += 1;
+        }
+
+        if msg.msg_type == Some(DHCPACK) && !msg.yiaddr.is_unspecified() {
+            lease.assigned = Some(msg.yiaddr);
+        }
+
+        if let Some(ip) = msg.requested_ip {
+            lease.requested = Some(ip);
+        } else if !msg.ciaddr.is_unspecified() {
+            lease.requested = Some(msg.ciaddr);
+        }
+
+        if msg.server_id.is_some() {
+            lease.server = msg.server_id;
+        }
+        if msg.hostname.is_some() {
+            lease.hostname = msg.hostname.clone();
+        }
+        if msg.vendor_class.is_some() {
+            lease.vendor_class = msg.vendor_class.clone();
+        }
+        if !msg.param_list.is_empty() {
+            lease.param_list = msg.param_list.clone();
+        }
+    }
+
+    /// the hostnames clients announced, keyed by their leased address
+    pub fn hostnames(&self) -> HashMap<Ipv4Addr, String> {
+        let mut names = HashMap::new();
+        for lease in self.leases.values() {
+            let ip = lease.assigned.or(lease.requested);
+            if let (Some(ip), Some(name)) = (ip, &lease.hostname) {
+                names.insert(ip, name.clone());
+            }
+        }
+        names
+    }
+
+    /// write the DHCP section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.leases.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- DHCP Leases (MAC -> IP -> Hostname -> Vendor Class)\n")?;
+
+        let mut macs = self.leases.keys().collect::<Vec<_>>();
+        macs.sort_by_key(|mac| mac.to_string());
+
+        for mac in macs {
+            let lease = &self.leases[mac];
+
+            // an address the client only asked for is marked with `?`
+            let ip = match (lease.assigned, lease.requested) {
+                (Some(ip), _)    => ip.to_string(),
+                (None, Some(ip)) => format!("{}?", ip),
+                (None, None)     => "-".to_string(),
+            };
+
+            write!(file, "{}    {:<16} {:<24} {}\n", mac, ip,
+                lease.hostname.as_deref().unwrap_or("-"),
+                lease.vendor_class.as_deref().unwrap_or("-"))?;
+
+            if let Some(server) = lease.server {
+                write!(file, "    server:     {}\n", server)?;
+            }
+
+            if !lease.param_list.is_empty() {
+                let params = lease.param_list.iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                write!(file, "    parameters: {}\n", params)?;
+            }
+
+            let messages = lease.messages.iter()
+                .map(|(t, n)| format!("{} {}", msg_type_name(*t), n))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(file, "    messages:   {}\n", messages)?;
+        }
+
+        Ok(())
+    }
+
+}
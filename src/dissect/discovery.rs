@@ -0,0 +1,285 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! host inventory from the protocols devices use to announce themselves
+//! on the local network: NetBIOS name service, LLMNR, mDNS and SSDP.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::dissect::{Flow, dns, nbns, ssdp};
+use crate::dissect::dns::RData;
+use crate::dissect::http::header;
+
+/// LLMNR port
+pub const LLMNR_PORT: u16 = 5355;
+
+/// mDNS port
+pub const MDNS_PORT: u16 = 5353;
+
+/// the meta query listing all service types, not a service itself
+const MDNS_META: &str = "_services._dns-sd._udp";
+
+/// what a host told us about itself
+#[derive(Default)]
+pub struct Host {
+    /// names with the protocol they were learned from, in the order
+    /// they were seen
+    pub names:      Vec<(String, &'static str)>,
+    /// mDNS service types and instances, NetBIOS workgroups
+    pub services:   BTreeSet<String>,
+    /// UPnP device and service types, server strings and description
+    pub upnp:       BTreeSet<String>,
+}
+
+impl Host {
+
+    /// add a name, names already known are ignored
+    fn add_name(&mut self, name: &str, source: &'static str) {
+        if name.is_empty() || self.names.iter().any(|(n, _)| n == name) {
+            return;
+        }
+        self.names.push((name.to_string(), source));
+    }
+
+}
+
+/// the inventory of all hosts that announced themselves
+#[derive(Default)]
+pub struct DiscoveryInfo {
+    pub hosts:      BTreeMap<Ipv4Addr, Host>,
+    /// HTTP servers serving UPnP device descriptions, from LOCATION
+    locations:      HashSet<(Ipv4Addr, u16)>,
+}
+
+/// strip the `.local` domain of mDNS names
+fn local_name(name: &str) -> &str {
+    name.strip_suffix(".local").unwrap_or(name)
+}
+
+/// get address and port from a LOCATION url like
+/// `http://192.168.1.5:49152/desc.xml`
+fn location_addr(url: &str) -> Option<(Ipv4Addr, u16)> {
+    let rest = url.strip_prefix("http://")?;
+    let authority = rest.split('/').next()?;
+    match authority.split_once(':') {
+        Some((host, port)) => Some((host.parse().ok()?, port.parse().ok()?)),
+        None => Some((authority.parse().ok()?, 80)),
+    }
+}
+
+impl DiscoveryInfo {
+
+    /// handle a datagram of one of the discovery protocols
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+        if flow.has_port(nbns::NBNS_PORT) {
+            self.nbns(flow, payload);
+        }
+        if flow.has_port(LLMNR_PORT) {
+            self.llmnr(payload);
+        }
+        if flow.sport == MDNS_PORT {
+            self.mdns(flow, payload);
+        }
+        if flow.has_port(ssdp::SSDP_PORT) {
+            self.ssdp(flow, payload);
+        }
+    }
+
+    /// handle an HTTP message, only used for UPnP device descriptions
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        if !self.locations.contains(&(flow.sip, flow.sport)) {
+            return;
+        }
+
+        if let Some(fields) = ssdp::parse_description(payload) {
+            let host = self.hosts.entry(flow.sip).or_default();
+            for (tag, value) in fields {
+                host.upnp.insert(format!("{}: {}", tag, value));
+            }
+        }
+    }
+
+    /// names from registrations, answers and node status responses
+    fn nbns(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(msg) = nbns::parse(payload) else {
+            return;
+        };
+
+        for binding in &msg.bindings {
+            let host = self.hosts.entry(binding.ip).or_default();
+            if binding.name.is_host() {
+                host.add_name(&binding.name.name, "nbns");
+            } else if binding.name.is_workgroup() {
+                host.services.insert(
+                    format!("workgroup {}", binding.name.name));
+            }
+        }
+
+        // node status names belong to the responder
+        if msg.response {
+            for name in &msg.status {
+                let host = self.hosts.entry(flow.sip).or_default();
+                if name.is_host() {
+                    host.add_name(&name.name, "nbns");
+                } else if name.is_workgroup() {
+                    host.services.insert(format!("workgroup {}", name.name));
+                }
+            }
+        }
+    }
+
+    /// names from LLMNR responses
+    fn llmnr(&mut self, payload: &[u8]) {
+
+        let Some(msg) = dns::parse(payload) else {
+            return;
+        };
+
+        if !msg.response {
+            return;
+        }
+
+        for record in &msg.answers {
+            if let RData::A(ip) = record.data {
+                self.hosts.entry(ip).or_default()
+                    .add_name(&record.name, "llmnr");
+            }
+        }
+    }
+
+    /// names and services from mDNS responses
+    fn mdns(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(msg) = dns::parse(payload) else {
+            return;
+        };
+
+        if !msg.response {
+            return;
+        }
+
+        for record in msg.answers.iter().chain(msg.additional.iter()) {
+            match &record.data {
+                RData::A(ip) => {
+                    self.hosts.entry(*ip).or_default()
+                        .add_name(local_name(&record.name), "mdns");
+                },
+                RData::Ptr(target) => {
+                    // reverse lookups name the host, everything else
+                    // points from a service type to an instance
+                    if let Some(IpAddr::V4(ip)) = dns::reverse_addr(&record.name) {
+                        self.hosts.entry(ip).or_default()
+                            .add_name(local_name(target), "mdns");
+                        continue;
+                    }
+                    let service = local_name(&record.name);
+                    if service.starts_with('_') && service != MDNS_META {
+                        self.hosts.entry(flow.sip).or_default()
+                            .services.insert(service.to_string());
+                    }
+                },
+                RData::Srv { port, target } => {
+                    // instance._service._proto.local
+                    let owner = local_name(&record.name);
+                    let Some(idx) = owner.find("._") else {
+                        continue;
+                    };
+                    self.hosts.entry(flow.sip).or_default()
+                        .services.insert(format!("{} \"{}\" on {}:{}",
+                            &owner[idx+1..], &owner[..idx],
+                            local_name(target), port));
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// device types and server strings from announcements and responses
+    fn ssdp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(msg) = ssdp::parse(payload) else {
+            return;
+        };
+
+        if msg.kind == ssdp::SsdpKind::Search {
+            return;
+        }
+
+        let host = self.hosts.entry(flow.sip).or_default();
+
+        if let Some(t) = msg.device_type() {
+            host.upnp.insert(t.to_string());
+        }
+        if let Some(server) = header(&msg.headers, "SERVER") {
+            host.upnp.insert(format!("server: {}", server));
+        }
+        if let Some(location) = header(&msg.headers, "LOCATION") {
+            if let Some(addr) = location_addr(location) {
+                self.locations.insert(addr);
+            }
+        }
+    }
+
+    /// the first name each host announced
+    pub fn hostnames(&self) -> HashMap<Ipv4Addr, String> {
+        self.hosts.iter()
+            .filter_map(|(ip, host)| {
+                Some((*ip, host.names.first()?.0.clone()))
+            })
+            .collect()
+    }
+
+    /// write the host inventory section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.hosts.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- Host Inventory (NBNS, LLMNR, mDNS, SSDP)\n")?;
+
+        for (ip, host) in &self.hosts {
+            write!(file, "{}\n", ip)?;
+
+            if !host.names.is_empty() {
+                let names = host.names.iter()
+                    .map(|(name, source)| format!("{} ({})", name, source))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(file, "    names:    {}\n", names)?;
+            }
+            for service in &host.services {
+                write!(file, "    service:  {}\n", service)?;
+            }
+            for upnp in &host.upnp {
+                write!(file, "    upnp:     {}\n", upnp)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
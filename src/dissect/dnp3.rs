@@ -0,0 +1,402 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::Flow;
+
+/// DNP3 port, both TCP and UDP
+pub const DNP3_PORT: u16 = 20000;
+
+/// link layer start bytes
+const START: [u8; 2] = [0x05, 0x64];
+
+/// link header size including its CRC
+const LINK_HEADER_LEN: usize = 10;
+
+/// user data is split in blocks of this size, each followed by a CRC
+const BLOCK_LEN: usize = 16;
+
+/// link control: the frame comes from the master
+const DIR_MASTER: u8 = 0x80;
+
+/// transport header: first fragment of a message
+const TRANSPORT_FIR: u8 = 0x40;
+
+/// application function codes
+const FC_READ:             u8 = 1;
+const FC_SELECT:           u8 = 3;
+const FC_OPERATE:          u8 = 4;
+const FC_DIRECT_OPERATE:   u8 = 5;
+const FC_DIRECT_OPERATE_NR: u8 = 6;
+const FC_COLD_RESTART:     u8 = 13;
+const FC_WARM_RESTART:     u8 = 14;
+const FC_RESPONSE:         u8 = 129;
+const FC_UNSOLICITED:      u8 = 130;
+
+/// IIN1.7, the outstation restarted
+const IIN_DEVICE_RESTART: u8 = 0x80;
+
+/// get the name of an application function code
+pub fn function_name(fc: u8) -> String {
+    match fc {
+        0   => "CONFIRM".to_string(),
+        1   => "READ".to_string(),
+        2   => "WRITE".to_string(),
+        3   => "SELECT".to_string(),
+        4   => "OPERATE".to_string(),
+        5   => "DIRECT_OPERATE".to_string(),
+        6   => "DIRECT_OPERATE_NR".to_string(),
+        7   => "IMMED_FREEZE".to_string(),
+        8   => "IMMED_FREEZE_NR".to_string(),
+        9   => "FREEZE_CLEAR".to_string(),
+        10  => "FREEZE_CLEAR_NR".to_string(),
+        11  => "FREEZE_AT_TIME".to_string(),
+        12  => "FREEZE_AT_TIME_NR".to_string(),
+        13  => "COLD_RESTART".to_string(),
+        14  => "WARM_RESTART".to_string(),
+        15  => "INITIALIZE_DATA".to_string(),
+        16  => "INITIALIZE_APPL".to_string(),
+        17  => "START_APPL".to_string(),
+        18  => "STOP_APPL".to_string(),
+        19  => "SAVE_CONFIG".to_string(),
+        20  => "ENABLE_UNSOLICITED".to_string(),
+        21  => "DISABLE_UNSOLICITED".to_string(),
+        22  => "ASSIGN_CLASS".to_string(),
+        23  => "DELAY_MEASURE".to_string(),
+        24  => "RECORD_CURRENT_TIME".to_string(),
+        25  => "OPEN_FILE".to_string(),
+        26  => "CLOSE_FILE".to_string(),
+        27  => "DELETE_FILE".to_string(),
+        28  => "GET_FILE_INFO".to_string(),
+        29  => "AUTHENTICATE_FILE".to_string(),
+        30  => "ABORT_FILE".to_string(),
+        31  => "ACTIVATE_CONFIG".to_string(),
+        32  => "AUTHENTICATE_REQ".to_string(),
+        33  => "AUTH_REQ_NO_ACK".to_string(),
+        129 => "RESPONSE".to_string(),
+        130 => "UNSOLICITED_RESPONSE".to_string(),
+        131 => "AUTHENTICATE_RESP".to_string(),
+        _   => format!("FC{}", fc),
+    }
+}
+
+/// get the name of an object group
+pub fn group_name(group: u8) -> &'static str {
+    match group {
+        1   => "Binary Input",
+        2   => "Binary Input Event",
+        3   => "Double-bit Binary Input",
+        4   => "Double-bit Binary Input Event",
+        10  => "Binary Output",
+        11  => "Binary Output Event",
+        12  => "Binary Command (CROB)",
+        20  => "Counter",
+        21  => "Frozen Counter",
+        22  => "Counter Event",
+        30  => "Analog Input",
+        32  => "Analog Input Event",
+        34  => "Analog Input Deadband",
+        40  => "Analog Output Status",
+        41  => "Analog Output Block",
+        42  => "Analog Output Event",
+        50  => "Time and Date",
+        51  => "Time and Date CTO",
+        52  => "Time Delay",
+        60  => "Class Data",
+        70  => "File Control",
+        80  => "Internal Indications",
+        110 => "Octet String",
+        120 => "Authentication",
+        _   => "Unknown",
+    }
+}
+
+/// true for functions that operate outputs
+pub fn is_control(fc: u8) -> bool {
+    matches!(fc, FC_SELECT | FC_OPERATE | FC_DIRECT_OPERATE | FC_DIRECT_OPERATE_NR)
+}
+
+/// true for functions that restart the outstation
+pub fn is_restart(fc: u8) -> bool {
+    matches!(fc, FC_COLD_RESTART | FC_WARM_RESTART)
+}
+
+/// size of a single object in requests, for the objects that carry data
+/// there. we stop walking the objects at anything not listed.
+fn object_size(group: u8, variation: u8) -> Option<usize> {
+    match (group, variation) {
+        (12, 1) => Some(11),
+        (41, 1) => Some(5),
+        (41, 2) => Some(3),
+        (41, 3) => Some(5),
+        (41, 4) => Some(9),
+        (50, 1) => Some(6),
+        (52, 2) => Some(2),
+        // class data and the like never carry data
+        (60, _) => Some(0),
+        _       => None,
+    }
+}
+
+/// a decoded link frame with the start of its application fragment
+pub struct Dnp3Frame {
+    /// frame was sent by the master
+    pub from_master:    bool,
+    pub dest:           u16,
+    pub src:            u16,
+    /// application function, only in the first frame of a fragment
+    pub function:       Option<u8>,
+    /// internal indications of responses
+    pub iin:            Option<[u8; 2]>,
+    /// object groups and variations of the fragment
+    pub objects:        Vec<(u8, u8)>,
+}
+
+/// walk the object headers of a request. the walk stops at the first
+/// object whose size we don't know.
+fn object_headers(fc: u8, mut buf: &[u8]) -> Vec<(u8, u8)> {
+
+    let mut objects = Vec::new();
+
+    while buf.len() >= 3 {
+
+        let (group, variation, qualifier) = (buf[0], buf[1], buf[2]);
+        objects.push((group, variation));
+
+        let prefix = match (qualifier >> 4) & 0x07 {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            3 => 4,
+            _ => return objects,
+        };
+
+        let rest = &buf[3..];
+        let (count, range_len) = match qualifier & 0x0f {
+            0 if rest.len() >= 2 => {
+                (rest[1].wrapping_sub(rest[0]) as usize + 1, 2)
+            },
+            1 if rest.len() >= 4 => {
+                let start = u16::from_le_bytes([rest[0], rest[1]]);
+                let stop = u16::from_le_bytes([rest[2], rest[3]]);
+                (stop.wrapping_sub(start) as usize + 1, 4)
+            },
+            6 => (0, 0),
+            7 if !rest.is_empty() => (rest[0] as usize, 1),
+            8 if rest.len() >= 2 => {
+                (u16::from_le_bytes([rest[0], rest[1]]) as usize, 2)
+            },
+            _ => return objects,
+        };
+
+        // reads only name the objects they want
+        let size = if fc == FC_READ {
+            0
+        } else {
+            match object_size(group, variation) {
+                Some(size) => size,
+                None => return objects,
+            }
+        };
+
+        let skip = 3 + range_len + count * (prefix + size);
+        match buf.get(skip..) {
+            Some(next) => buf = next,
+            None => break,
+        }
+    }
+
+    objects
+}
+
+/// parse the link frame at the start of `buf`, returns the frame and
+/// its length on the wire
+pub fn parse_frame(buf: &[u8]) -> Option<(Dnp3Frame, usize)> {
+
+    if !buf.starts_with(&START) || buf.len() < LINK_HEADER_LEN {
+        return None;
+    }
+
+    // the length counts control, addresses and user data, not the CRCs
+    let len = buf[2] as usize;
+    if len < 5 {
+        return None;
+    }
+    let ctrl = buf[3];
+    let dest = u16::from_le_bytes([buf[4], buf[5]]);
+    let src = u16::from_le_bytes([buf[6], buf[7]]);
+
+    let user_len = len - 5;
+    let blocks = user_len.div_ceil(BLOCK_LEN);
+    let wire_len = LINK_HEADER_LEN + user_len + blocks * 2;
+
+    // strip the CRCs of the user data blocks
+    let mut data = Vec::with_capacity(user_len);
+    let mut pos = LINK_HEADER_LEN;
+    let mut left = user_len;
+    while left > 0 {
+        let n = left.min(BLOCK_LEN);
+        data.extend_from_slice(buf.get(pos..pos+n)?);
+        pos += n + 2;
+        left -= n;
+    }
+
+    let mut frame = Dnp3Frame {
+        from_master:    ctrl & DIR_MASTER != 0,
+        dest, src,
+        function:       None,
+        iin:            None,
+        objects:        Vec::new(),
+    };
+
+    // transport header, application control and function code
+    if data.len() >= 3 && data[0] & TRANSPORT_FIR != 0 {
+        let fc = data[2];
+        frame.function = Some(fc);
+        let objects = if fc == FC_RESPONSE || fc == FC_UNSOLICITED {
+            if data.len() >= 5 {
+                frame.iin = Some([data[3], data[4]]);
+            }
+            // responses carry data of all kinds, only the first header
+            // is taken
+            data.get(5..7)
+                .map(|o| vec![(o[0], o[1])])
+                .unwrap_or_default()
+        } else {
+            object_headers(fc, &data[3..])
+        };
+        frame.objects = objects;
+    }
+
+    Some((frame, wire_len))
+}
+
+/// everything we saw between a master and an outstation
+#[derive(Default)]
+pub struct Association {
+    pub functions:  BTreeMap<u8, u64>,
+    pub objects:    BTreeSet<(u8, u8)>,
+    /// responses with the device restart indication set
+    pub restarted:  u64,
+}
+
+/// (master ip, master address, outstation ip, outstation address)
+type AssocKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// what we learned from the DNP3 traffic in the capture
+#[derive(Default)]
+pub struct Dnp3Info {
+    pub associations: BTreeMap<AssocKey, Association>,
+}
+
+impl Dnp3Info {
+
+    /// handle whole link frames from TCP or a UDP datagram, there may
+    /// be several
+    pub fn data(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let mut pos = 0;
+
+        while let Some((frame, len)) = parse_frame(&payload[pos..]) {
+
+            let key = if frame.from_master {
+                (flow.sip, frame.src, flow.dip, frame.dest)
+            } else {
+                (flow.dip, frame.dest, flow.sip, frame.src)
+            };
+
+            let assoc = self.associations.entry(key).or_default();
+
+            if let Some(fc) = frame.function {
+                *assoc.functions.entry(fc).or_insert(0) += 1;
+            }
+            assoc.objects.extend(frame.objects.iter());
+            if let Some(iin) = frame.iin {
+                if iin[0] & IIN_DEVICE_RESTART != 0 {
+                    assoc.restarted += 1;
+                }
+            }
+
+            pos += len;
+            if pos >= payload.len() {
+                break;
+            }
+        }
+    }
+
+    /// write the DNP3 section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.associations.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- DNP3 (master -> outstation)\n")?;
+
+        for ((master, maddr, outstation, oaddr), assoc) in &self.associations {
+
+            write!(file, "{} (addr {}) -> {} (addr {})\n",
+                master, maddr, outstation, oaddr)?;
+
+            for (fc, count) in &assoc.functions {
+                let mark = if is_control(*fc) {
+                    "  [CONTROL]"
+                } else if is_restart(*fc) {
+                    "  [RESTART]"
+                } else {
+                    ""
+                };
+                write!(file, "    function: {:<22} ({}x){}\n",
+                    function_name(*fc), count, mark)?;
+            }
+
+            let groups = assoc.objects.iter()
+                .map(|(g, v)| format!("g{}v{} {}", g, v, group_name(*g)))
+                .collect::<Vec<_>>();
+            for group in groups {
+                write!(file, "    object:   {}\n", group)?;
+            }
+
+            if assoc.restarted > 0 {
+                write!(file, "    outstation reported a restart (IIN1.7) in \
+                              {} responses  [RESTART]\n", assoc.restarted)?;
+            }
+        }
+
+        write!(file, "\n\n-- DNP3 Control Operations and Restarts\n")?;
+
+        for ((master, _, outstation, _), assoc) in &self.associations {
+            let ops = assoc.functions.iter()
+                .filter(|(fc, _)| is_control(**fc) || is_restart(**fc))
+                .map(|(fc, count)| format!("{} {}x", function_name(*fc), count))
+                .collect::<Vec<_>>();
+            if !ops.is_empty() {
+                write!(file, "{:<15} -> {:<15} {}\n",
+                    master.to_string(), outstation.to_string(), ops.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,395 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::dissect::{Flow, be16, be32};
+
+/// DNS server port, both UDP and TCP
+pub const DNS_PORT: u16 = 53;
+
+/// size of the DNS header
+const HEADER_LEN: usize = 12;
+
+/// record types we care about
+const TYPE_A:     u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_PTR:   u16 = 12;
+const TYPE_AAAA:  u16 = 28;
+const TYPE_SRV:   u16 = 33;
+
+/// response code for a name that does not exist
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+/// a single entry of the question section
+pub struct Question {
+    pub name:   String,
+    pub qtype:  u16,
+}
+
+/// the data of a resource record, only the types we can use are decoded
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    Ptr(String),
+    Cname(String),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+/// a single resource record from the answer section
+pub struct Record {
+    pub name:   String,
+    pub rtype:  u16,
+    pub data:   RData,
+}
+
+/// a decoded DNS message
+pub struct DnsMessage {
+    pub id:         u16,
+    pub response:   bool,
+    pub opcode:     u8,
+    pub rcode:      u8,
+    pub questions:  Vec<Question>,
+    pub answers:    Vec<Record>,
+    /// records of the authority and additional sections
+    pub additional: Vec<Record>,
+}
+
+/// get the name of a response code
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        _ => format!("RCODE{}", rcode),
+    }
+}
+
+/// read a (possibly compressed) domain name starting at `offset`,
+/// returns the name and the offset right behind it
+pub fn read_name(buf: &[u8], offset: usize) -> Option<(String, usize)> {
+
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *buf.get(pos)? as usize;
+
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+
+        if len & 0xc0 == 0xc0 {
+            // compression pointer, the name continues somewhere else
+            let ptr = (be16(buf, pos)? & 0x3fff) as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            // guard against pointer loops
+            jumps += 1;
+            if jumps > 32 {
+                return None;
+            }
+            pos = ptr;
+            continue;
+        }
+
+        let label = buf.get(pos+1..pos+1+len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+
+    let name = if labels.is_empty() {
+        ".".to_string()
+    } else {
+        labels.join(".")
+    };
+
+    Some((name, end.unwrap_or(pos)))
+}
+
+/// read the resource record at `offset`, returns the record and the 
+/// offset right behind it
+fn read_record(buf: &[u8], offset: usize) -> Option<(Record, usize)> {
+
+    let (name, next) = read_name(buf, offset)?;
+    let rtype = be16(buf, next)?;
+    // skip type, class and ttl
+    let rdlen = be16(buf, next+8)? as usize;
+    let rdata = next + 10;
+    let raw = buf.get(rdata..rdata+rdlen)?;
+
+    let data = match rtype {
+        TYPE_A if rdlen == 4 => {
+            RData::A(Ipv4Addr::from(be32(raw, 0)?))
+        },
+        TYPE_AAAA if rdlen == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(raw);
+            RData::AAAA(Ipv6Addr::from(octets))
+        },
+        // names in rdata may point back into the message
+        TYPE_PTR   => RData::Ptr(read_name(buf, rdata)?.0),
+        TYPE_CNAME => RData::Cname(read_name(buf, rdata)?.0),
+        TYPE_SRV   => RData::Srv {
+            // priority and weight come first
+            port:   be16(raw, 4)?,
+            target: read_name(buf, rdata+6)?.0,
+        },
+        _          => RData::Other,
+    };
+
+    Some((Record { name, rtype, data }, rdata + rdlen))
+}
+
+/// parse a DNS message as it is carried in a UDP datagram
+pub fn parse(buf: &[u8]) -> Option<DnsMessage> {
+
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+
+    let id = be16(buf, 0)?;
+    let flags = be16(buf, 2)?;
+    let qdcount = be16(buf, 4)?;
+    let ancount = be16(buf, 6)?;
+    let nscount = be16(buf, 8)?;
+    let arcount = be16(buf, 10)?;
+
+    let mut msg = DnsMessage {
+        id,
+        response:   flags & 0x8000 != 0,
+        opcode:     ((flags >> 11) & 0x0f) as u8,
+        rcode:      (flags & 0x000f) as u8,
+        questions:  Vec::new(),
+        answers:    Vec::new(),
+        additional: Vec::new(),
+    };
+
+    let mut pos = HEADER_LEN;
+
+    for _ in 0..qdcount {
+        let (name, next) = read_name(buf, pos)?;
+        let qtype = be16(buf, next)?;
+        // skip type and class
+        pos = next + 4;
+        msg.questions.push(Question { name, qtype });
+    }
+
+    for _ in 0..ancount {
+        let (record, next) = read_record(buf, pos)?;
+        msg.answers.push(record);
+        pos = next;
+    }
+
+    // the additional records are not needed for plain DNS, they are
+    // decoded on a best effort basis for mDNS and friends
+    for _ in 0..nscount as u32 + arcount as u32 {
+        let Some((record, next)) = read_record(buf, pos) else {
+            break;
+        };
+        msg.additional.push(record);
+        pos = next;
+    }
+
+    Some(msg)
+}
+
+/// get the address a reverse lookup name like `4.3.2.1.in-addr.arpa`
+/// refers to
+pub fn reverse_addr(name: &str) -> Option<IpAddr> {
+
+    let name = name.to_ascii_lowercase();
+
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rest.split('.')
+            .map(|o| o.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3])));
+    }
+
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let nibbles = rest.split('.')
+            .map(|n| u8::from_str_radix(n, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut octets = [0u8; 16];
+        for (i, pair) in nibbles.rchunks(2).enumerate() {
+            octets[i] = pair[1] << 4 | pair[0];
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+
+    None
+}
+
+/// what we learned from the DNS traffic in the capture
+#[derive(Default)]
+pub struct DnsInfo {
+    /// address to hostname mapping, taken from answers
+    pub names:      HashMap<IpAddr, String>,
+    /// domains queried by each client and how often
+    pub queries:    HashMap<Ipv4Addr, BTreeMap<String, u64>>,
+    /// domains each client got a NXDOMAIN for and how often
+    pub nxdomain:   HashMap<Ipv4Addr, BTreeMap<String, u64>>,
+    /// number of responses per response code
+    pub rcodes:     BTreeMap<u8, u64>,
+}
+
+impl DnsInfo {
+
+    /// handle a DNS datagram
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+        if let Some(msg) = parse(payload) {
+            self.update(flow, &msg);
+        }
+    }
+
+    /// handle TCP data, messages are prefixed with their length. the
+    /// framing only hands over complete messages.
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+        let mut pos = 0;
+        while let Some(len) = be16(payload, pos) {
+            let len = len as usize;
+            match payload.get(pos+2..pos+2+len) {
+                Some(buf) => self.udp(flow, buf),
+                None      => break,
+            }
+            pos += 2 + len;
+        }
+    }
+
+    /// add a decoded message to the summary
+    pub fn update(&mut self, flow: &Flow, msg: &DnsMessage) {
+
+        // only standard queries are of interest
+        if msg.opcode != 0 {
+            return;
+        }
+
+        if !msg.response {
+            let domains = self.queries.entry(flow.sip)
+                .or_default();
+            for q in &msg.questions {
+                *domains.entry(q.name.clone()).or_insert(0) += 1;
+            }
+            return;
+        }
+
+        *self.rcodes.entry(msg.rcode).or_insert(0) += 1;
+
+        if msg.rcode == RCODE_NXDOMAIN {
+            let domains = self.nxdomain.entry(flow.dip)
+                .or_default();
+            for q in &msg.questions {
+                *domains.entry(q.name.clone()).or_insert(0) += 1;
+            }
+            return;
+        }
+
+        // prefer the name the client asked for over the end of a
+        // CNAME chain, it is what the user actually knows
+        let queried = msg.questions.first().map(|q| q.name.clone());
+
+        for record in &msg.answers {
+            match &record.data {
+                RData::A(ip) => {
+                    let name = queried.clone()
+                        .unwrap_or(record.name.clone());
+                    self.names.insert(IpAddr::V4(*ip), name);
+                },
+                RData::AAAA(ip) => {
+                    let name = queried.clone()
+                        .unwrap_or(record.name.clone());
+                    self.names.insert(IpAddr::V6(*ip), name);
+                },
+                RData::Ptr(target) => {
+                    if let Some(ip) = reverse_addr(&record.name) {
+                        self.names.insert(ip, target.clone());
+                    }
+                },
+                RData::Cname(_) | RData::Srv { .. } | RData::Other => (),
+            }
+        }
+    }
+
+    /// write the DNS sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.queries.is_empty() && self.rcodes.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- DNS Resolved Names\n")?;
+        let mut names = self.names.iter().collect::<Vec<_>>();
+        names.sort();
+        for (ip, name) in names {
+            write!(file, "{:<40} {}\n", ip.to_string(), name)?;
+        }
+
+        write!(file, "\n\n-- DNS Queries per Client\n")?;
+        let mut clients = self.queries.keys().collect::<Vec<_>>();
+        clients.sort();
+        for client in clients {
+            write!(file, "{}\n", client)?;
+            for (domain, count) in &self.queries[client] {
+                write!(file, "    {:<60} {}\n", domain, count)?;
+            }
+        }
+
+        write!(file, "\n\n-- DNS Response Codes\n")?;
+        for (rcode, count) in &self.rcodes {
+            write!(file, "{:<10} {}\n", rcode_name(*rcode), count)?;
+        }
+
+        write!(file, "\n\n-- DNS NXDOMAIN per Client\n")?;
+        let mut clients = self.nxdomain.keys().collect::<Vec<_>>();
+        clients.sort();
+        for client in clients {
+            let domains = &self.nxdomain[client];
+            let total: u64 = domains.values().sum();
+            write!(file, "{} ({} NXDOMAIN)\n", client, total)?;
+            for (domain, count) in domains {
+                write!(file, "    {:<60} {}\n", domain, count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,541 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! EtherNet/IP encapsulation and the CIP messages it carries. explicit
+//! messaging and ListIdentity run on 44818, implicit (cyclic) I/O on
+//! UDP 2222. all fields are little endian.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::dissect::{Flow, le16, le32};
+
+/// explicit messaging port, TCP and UDP
+pub const ENIP_PORT: u16 = 44818;
+
+/// implicit I/O port
+pub const ENIP_IO_PORT: u16 = 2222;
+
+/// size of the encapsulation header
+const ENCAP_LEN: usize = 24;
+
+/// encapsulation commands
+const CMD_LIST_IDENTITY:    u16 = 0x0063;
+const CMD_SEND_RR_DATA:     u16 = 0x006f;
+const CMD_SEND_UNIT_DATA:   u16 = 0x0070;
+
+/// common packet format item types
+const ITEM_IDENTITY:        u16 = 0x000c;
+const ITEM_CONNECTED_DATA:  u16 = 0x00b1;
+const ITEM_UNCONNECTED:     u16 = 0x00b2;
+const ITEM_SEQUENCED_ADDR:  u16 = 0x8002;
+
+/// CIP classes
+const CLASS_IDENTITY:       u16 = 0x01;
+const CLASS_CONN_MANAGER:   u16 = 0x06;
+
+/// CIP services
+const SVC_GET_ATTRIBUTES_ALL:   u8 = 0x01;
+const SVC_MULTIPLE_SERVICE:     u8 = 0x0a;
+const SVC_UNCONNECTED_SEND:     u8 = 0x52;
+
+/// the service code of a response has the high bit set
+const SVC_RESPONSE: u8 = 0x80;
+
+/// requests wrapped deeper than this are not unwrapped
+const MAX_DEPTH: usize = 3;
+
+/// true for the encapsulation commands of the specification
+pub fn is_command(command: u16) -> bool {
+    matches!(command,
+        0x0000 | 0x0004 | CMD_LIST_IDENTITY | 0x0064 | 0x0065 | 0x0066
+        | CMD_SEND_RR_DATA | CMD_SEND_UNIT_DATA | 0x0072 | 0x0073)
+}
+
+/// get the name of an encapsulation command
+pub fn command_name(command: u16) -> String {
+    match command {
+        0x0001             => "NOP".to_string(),
+        0x0004             => "ListServices".to_string(),
+        CMD_LIST_IDENTITY  => "ListIdentity".to_string(),
+        0x0064             => "ListInterfaces".to_string(),
+        0x0065             => "RegisterSession".to_string(),
+        0x0066             => "UnRegisterSession".to_string(),
+        CMD_SEND_RR_DATA   => "SendRRData".to_string(),
+        CMD_SEND_UNIT_DATA => "SendUnitData".to_string(),
+        _                  => format!("Command 0x{:04x}", command),
+    }
+}
+
+/// get the name of a CIP service, services above 0x4a are class specific
+pub fn service_name(service: u8, class: Option<u16>) -> String {
+    let name = match (service, class) {
+        (0x01, _) => "Get_Attributes_All",
+        (0x02, _) => "Set_Attributes_All",
+        (0x03, _) => "Get_Attribute_List",
+        (0x04, _) => "Set_Attribute_List",
+        (0x05, _) => "Reset",
+        (0x06, _) => "Start",
+        (0x07, _) => "Stop",
+        (0x08, _) => "Create",
+        (0x09, _) => "Delete",
+        (0x0a, _) => "Multiple_Service_Packet",
+        (0x0d, _) => "Apply_Attributes",
+        (0x0e, _) => "Get_Attribute_Single",
+        (0x10, _) => "Set_Attribute_Single",
+        (0x11, _) => "Find_Next_Object_Instance",
+        (0x4b, _) => "Execute_PCCC",
+        (0x4e, Some(CLASS_CONN_MANAGER)) => "Forward_Close",
+        (0x52, Some(CLASS_CONN_MANAGER)) => "Unconnected_Send",
+        (0x54, Some(CLASS_CONN_MANAGER)) => "Forward_Open",
+        (0x5b, Some(CLASS_CONN_MANAGER)) => "Large_Forward_Open",
+        // Logix controllers address tags by name
+        (0x4c, _) => "Read_Tag",
+        (0x4d, _) => "Write_Tag",
+        (0x4e, _) => "Read_Modify_Write_Tag",
+        (0x52, _) => "Read_Tag_Fragmented",
+        (0x53, _) => "Write_Tag_Fragmented",
+        (0x55, _) => "Get_Instance_Attribute_List",
+        _ => return format!("Service 0x{:02x}", service),
+    };
+    name.to_string()
+}
+
+/// get the name of a CIP class
+pub fn class_name(class: u16) -> String {
+    match class {
+        CLASS_IDENTITY     => "Identity".to_string(),
+        0x02               => "Message Router".to_string(),
+        0x04               => "Assembly".to_string(),
+        CLASS_CONN_MANAGER => "Connection Manager".to_string(),
+        0x37               => "File".to_string(),
+        0x47               => "Device Level Ring".to_string(),
+        0x48               => "QoS".to_string(),
+        0x6b               => "Symbol".to_string(),
+        0x6c               => "Template".to_string(),
+        0xf4               => "Port".to_string(),
+        0xf5               => "TCP/IP Interface".to_string(),
+        0xf6               => "Ethernet Link".to_string(),
+        _                  => format!("Class 0x{:02x}", class),
+    }
+}
+
+/// get the name of a vendor id, only the common ones are known
+pub fn vendor_name(vendor: u16) -> String {
+    match vendor {
+        1  => "Rockwell Automation/Allen-Bradley".to_string(),
+        40 => "WAGO".to_string(),
+        47 => "OMRON".to_string(),
+        90 => "HMS Industrial Networks".to_string(),
+        _  => format!("vendor {}", vendor),
+    }
+}
+
+/// get the name of a device type
+pub fn device_type_name(device_type: u16) -> String {
+    match device_type {
+        0x00 => "Generic Device".to_string(),
+        0x02 => "AC Drive".to_string(),
+        0x03 => "Motor Overload".to_string(),
+        0x07 => "General Purpose Discrete I/O".to_string(),
+        0x0c => "Communications Adapter".to_string(),
+        0x0e => "Programmable Logic Controller".to_string(),
+        0x13 => "DC Drive".to_string(),
+        0x18 => "Human-Machine Interface".to_string(),
+        0x2b => "Generic Device (keyable)".to_string(),
+        _    => format!("device type 0x{:02x}", device_type),
+    }
+}
+
+/// the identity of a device, from ListIdentity or the Identity object
+#[derive(Clone)]
+pub struct Identity {
+    pub vendor:         u16,
+    pub device_type:    u16,
+    pub product_code:   u16,
+    pub revision:       (u8, u8),
+    pub serial:         u32,
+    pub product_name:   String,
+    pub source:         &'static str,
+}
+
+/// parse the identity attributes, the same layout is used by
+/// ListIdentity and Get_Attributes_All of the Identity object
+fn parse_identity(buf: &[u8], source: &'static str) -> Option<Identity> {
+    let name_len = *buf.get(14)? as usize;
+    let name = buf.get(15..15+name_len)?;
+    Some(Identity {
+        vendor:         le16(buf, 0)?,
+        device_type:    le16(buf, 2)?,
+        product_code:   le16(buf, 4)?,
+        revision:       (buf[6], buf[7]),
+        serial:         le32(buf, 10)?,
+        product_name:   String::from_utf8_lossy(name).to_string(),
+        source,
+    })
+}
+
+/// a decoded CIP request
+pub struct CipRequest {
+    pub service:    u8,
+    pub class:      Option<u16>,
+    /// symbolic segment of the path, the tag name on Logix controllers
+    pub tag:        Option<String>,
+}
+
+/// walk a padded EPATH, returns the class and the tag name
+fn parse_path(path: &[u8]) -> (Option<u16>, Option<String>) {
+
+    let mut class = None;
+    let mut tag: Option<String> = None;
+    let mut pos = 0;
+
+    while pos < path.len() {
+        let segment = path[pos];
+        match segment {
+            0x20 => {
+                class = path.get(pos+1).map(|c| *c as u16);
+                pos += 2;
+            },
+            0x21 => {
+                class = le16(path, pos+2);
+                pos += 4;
+            },
+            // 8 bit instance, attribute, member and connection point
+            0x24 | 0x28 | 0x2c | 0x30 => pos += 2,
+            0x25 | 0x29 | 0x2d | 0x31 => pos += 4,
+            // ANSI extended symbol, padded to an even length
+            0x91 => {
+                let Some(&len) = path.get(pos+1) else {
+                    break;
+                };
+                let len = len as usize;
+                let Some(name) = path.get(pos+2..pos+2+len) else {
+                    break;
+                };
+                let name = String::from_utf8_lossy(name);
+                tag = Some(match tag {
+                    Some(t) => format!("{}.{}", t, name),
+                    None => name.to_string(),
+                });
+                pos += 2 + len + len % 2;
+            },
+            _ => break,
+        }
+    }
+
+    (class, tag)
+}
+
+/// parse a CIP request, Unconnected_Send and Multiple_Service_Packet are
+/// unwrapped so `out` gets the requests they carry
+pub fn parse_request(buf: &[u8], out: &mut Vec<CipRequest>) {
+    request(buf, 0, out);
+}
+
+/// parse a CIP request `depth` wrappers deep
+fn request(buf: &[u8], depth: usize, out: &mut Vec<CipRequest>) {
+
+    let (Some(&service), Some(&words)) = (buf.first(), buf.get(1)) else {
+        return;
+    };
+    if service & SVC_RESPONSE != 0 {
+        return;
+    }
+
+    let path_end = 2 + words as usize * 2;
+    let Some(path) = buf.get(2..path_end) else {
+        return;
+    };
+    let (class, tag) = parse_path(path);
+    let data = &buf[path_end..];
+
+    match (service, class) {
+        (SVC_UNCONNECTED_SEND, Some(CLASS_CONN_MANAGER)) => {
+            if depth >= MAX_DEPTH {
+                return;
+            }
+            // priority, timeout ticks, size, embedded request
+            if let Some(size) = le16(data, 2) {
+                if let Some(embedded) = data.get(4..4+size as usize) {
+                    request(embedded, depth + 1, out);
+                }
+            }
+        },
+        (SVC_MULTIPLE_SERVICE, _) => {
+            if depth >= MAX_DEPTH {
+                return;
+            }
+            // count, then offsets relative to the count. the requests
+            // follow the offset table in ascending order, anything else
+            // is not a valid packet
+            let count = (le16(data, 0).unwrap_or(0) as usize)
+                .min(data.len().saturating_sub(2) / 2);
+            let table_end = 2 + count * 2;
+            let mut previous = 0;
+            for i in 0..count {
+                let Some(offset) = le16(data, 2 + i*2).map(|o| o as usize) else {
+                    break;
+                };
+                if offset < table_end || offset <= previous {
+                    continue;
+                }
+                previous = offset;
+                if let Some(embedded) = data.get(offset..) {
+                    request(embedded, depth + 1, out);
+                }
+            }
+        },
+        _ => out.push(CipRequest { service, class, tag }),
+    }
+}
+
+/// get the items of a common packet format
+fn cpf_items(buf: &[u8]) -> Vec<(u16, &[u8])> {
+
+    let mut items = Vec::new();
+    let count = le16(buf, 0).unwrap_or(0);
+    let mut pos = 2;
+
+    for _ in 0..count {
+        let (Some(kind), Some(len)) = (le16(buf, pos), le16(buf, pos+2)) else {
+            break;
+        };
+        let Some(data) = buf.get(pos+4..pos+4+len as usize) else {
+            break;
+        };
+        items.push((kind, data));
+        pos += 4 + len as usize;
+    }
+
+    items
+}
+
+/// everything we saw between a client and a server
+#[derive(Default)]
+pub struct Pair {
+    pub commands:   BTreeMap<u16, u64>,
+    /// CIP requests by service and class
+    pub services:   BTreeMap<(u8, Option<u16>), u64>,
+    pub tags:       BTreeSet<String>,
+    /// implicit I/O packets by connection id
+    pub io:         BTreeMap<u32, u64>,
+}
+
+/// what we learned from the EtherNet/IP traffic in the capture
+#[derive(Default)]
+pub struct EnipInfo {
+    /// keyed by client and server, or producer and consumer for I/O
+    pub pairs:      BTreeMap<(Ipv4Addr, Ipv4Addr), Pair>,
+    pub devices:    BTreeMap<Ipv4Addr, Identity>,
+    /// servers we asked for the Identity object and wait for
+    pending:        HashMap<(Ipv4Addr, Ipv4Addr), bool>,
+}
+
+impl EnipInfo {
+
+    /// handle whole encapsulation packets from TCP or a UDP datagram on
+    /// the explicit messaging port, there may be several
+    pub fn data(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let request = flow.dport == ENIP_PORT;
+        let mut pos = 0;
+
+        while payload.len() >= pos + ENCAP_LEN {
+
+            let (Some(command), Some(len)) =
+                (le16(payload, pos), le16(payload, pos+2)) else {
+                break;
+            };
+            let start = pos + ENCAP_LEN;
+            let Some(data) = payload.get(start..start+len as usize) else {
+                break;
+            };
+
+            if request {
+                *self.pairs.entry((flow.sip, flow.dip)).or_default()
+                    .commands.entry(command).or_insert(0) += 1;
+            }
+
+            match command {
+                CMD_LIST_IDENTITY if !request => self.list_identity(flow, data),
+                CMD_SEND_RR_DATA | CMD_SEND_UNIT_DATA => {
+                    // interface handle and timeout precede the items
+                    if let Some(cpf) = data.get(6..) {
+                        self.cip(flow, request, cpf);
+                    }
+                },
+                _ => (),
+            }
+
+            pos = start + len as usize;
+        }
+    }
+
+    /// handle a datagram of implicit I/O
+    pub fn io(&mut self, flow: &Flow, payload: &[u8]) {
+        for (kind, data) in cpf_items(payload) {
+            if kind == ITEM_SEQUENCED_ADDR {
+                if let Some(conn) = le32(data, 0) {
+                    *self.pairs.entry((flow.sip, flow.dip)).or_default()
+                        .io.entry(conn).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    /// identities in a ListIdentity response
+    fn list_identity(&mut self, flow: &Flow, data: &[u8]) {
+        for (kind, item) in cpf_items(data) {
+            // protocol version and socket address come first
+            if kind != ITEM_IDENTITY {
+                continue;
+            }
+            if let Some(identity) = item.get(18..)
+                .and_then(|i| parse_identity(i, "ListIdentity")) {
+                self.devices.insert(flow.sip, identity);
+            }
+        }
+    }
+
+    /// the CIP messages of SendRRData and SendUnitData
+    fn cip(&mut self, flow: &Flow, request: bool, cpf: &[u8]) {
+
+        for (kind, item) in cpf_items(cpf) {
+
+            let message = match kind {
+                ITEM_UNCONNECTED => item,
+                // connected data starts with a sequence count
+                ITEM_CONNECTED_DATA => match item.get(2..) {
+                    Some(m) => m,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            if request {
+                self.request(flow, message);
+            } else {
+                self.response(flow, message);
+            }
+        }
+    }
+
+    fn request(&mut self, flow: &Flow, message: &[u8]) {
+
+        let mut requests = Vec::new();
+        parse_request(message, &mut requests);
+
+        let key = (flow.sip, flow.dip);
+        let pair = self.pairs.entry(key).or_default();
+        let mut identity = false;
+
+        for r in requests {
+            *pair.services.entry((r.service, r.class)).or_insert(0) += 1;
+            if let Some(tag) = r.tag {
+                pair.tags.insert(tag);
+            }
+            identity |= r.service == SVC_GET_ATTRIBUTES_ALL
+                && r.class == Some(CLASS_IDENTITY);
+        }
+
+        self.pending.insert(key, identity);
+    }
+
+    fn response(&mut self, flow: &Flow, message: &[u8]) {
+
+        let key = (flow.dip, flow.sip);
+        if self.pending.remove(&key) != Some(true) {
+            return;
+        }
+
+        // service, reserved, general status, size of additional status
+        let (Some(&service), Some(&status), Some(&extra)) =
+            (message.first(), message.get(2), message.get(3)) else {
+            return;
+        };
+        if service != SVC_GET_ATTRIBUTES_ALL | SVC_RESPONSE || status != 0 {
+            return;
+        }
+
+        let data = message.get(4 + extra as usize * 2..).unwrap_or(&[]);
+        if let Some(identity) = parse_identity(data, "Identity object") {
+            // ListIdentity is what the device says about itself
+            self.devices.entry(flow.sip).or_insert(identity);
+        }
+    }
+
+    /// write the EtherNet/IP sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if !self.pairs.is_empty() {
+
+            write!(file, "\n\n-- EtherNet/IP and CIP (client -> server)\n")?;
+
+            for ((client, server), pair) in &self.pairs {
+
+                write!(file, "{} -> {}\n", client, server)?;
+
+                for (command, count) in &pair.commands {
+                    write!(file, "    command:  {} ({}x)\n",
+                        command_name(*command), count)?;
+                }
+                for ((service, class), count) in &pair.services {
+                    write!(file, "    cip:      {} @ {} ({}x)\n",
+                        service_name(*service, *class),
+                        class.map(class_name).unwrap_or_else(|| "-".to_string()),
+                        count)?;
+                }
+                if !pair.tags.is_empty() {
+                    write!(file, "    tags:     {}\n",
+                        pair.tags.iter().cloned()
+                            .collect::<Vec<_>>().join(", "))?;
+                }
+                for (conn, count) in &pair.io {
+                    write!(file, "    i/o:      connection 0x{:08x} ({} packets)\n",
+                        conn, count)?;
+                }
+            }
+        }
+
+        if !self.devices.is_empty() {
+
+            write!(file, "\n\n-- EtherNet/IP Asset Inventory\n")?;
+
+            for (ip, id) in &self.devices {
+                write!(file, "{}\n", ip)?;
+                write!(file, "    product:  {}\n", id.product_name)?;
+                write!(file, "    vendor:   {}\n", vendor_name(id.vendor))?;
+                write!(file, "    type:     {} (product code {})\n",
+                    device_type_name(id.device_type), id.product_code)?;
+                write!(file, "    revision: {}.{}\n",
+                    id.revision.0, id.revision.1)?;
+                write!(file, "    serial:   0x{:08x}\n", id.serial)?;
+                write!(file, "    source:   {}\n", id.source)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
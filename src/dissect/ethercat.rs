@@ -0,0 +1,213 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! EtherCAT frames. a frame holds a chain of datagrams, each addresses
+//! slaves by position, by configured station address, by broadcast or
+//! through the logical process image. all fields are little endian.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::pinfo::MacAddr;
+use crate::dissect::{Frame, le16, le32};
+
+/// ethertype of EtherCAT
+pub const ETHERTYPE_ETHERCAT: u16 = 0x88a4;
+
+/// frame type of EtherCAT datagrams
+const TYPE_COMMANDS: u8 = 1;
+
+/// datagram header and working counter
+const DATAGRAM_HEADER_LEN: usize = 10;
+const WKC_LEN: usize = 2;
+
+/// another datagram follows
+const MORE_DATAGRAMS: u16 = 0x8000;
+
+/// get the name of a datagram command
+pub fn command_name(cmd: u8) -> &'static str {
+    match cmd {
+        0  => "NOP",
+        1  => "APRD",
+        2  => "APWR",
+        3  => "APRW",
+        4  => "FPRD",
+        5  => "FPWR",
+        6  => "FPRW",
+        7  => "BRD",
+        8  => "BWR",
+        9  => "BRW",
+        10 => "LRD",
+        11 => "LWR",
+        12 => "LRW",
+        13 => "ARMW",
+        14 => "FRMW",
+        _  => "unknown",
+    }
+}
+
+/// how a command addresses slaves
+enum Addressing {
+    /// auto increment, by position in the ring
+    Position(u16),
+    /// configured station address
+    Station(u16),
+    Broadcast,
+    /// logical address and length
+    Logical(u32, u16),
+    None,
+}
+
+fn addressing(cmd: u8, adp: u16, logical: u32, len: u16) -> Addressing {
+    match cmd {
+        // the position is sent as negative number
+        1..=3 | 13 => Addressing::Position(adp.wrapping_neg()),
+        4..=6 | 14 => Addressing::Station(adp),
+        7..=9 => Addressing::Broadcast,
+        10..=12 => Addressing::Logical(logical, len),
+        _ => Addressing::None,
+    }
+}
+
+/// everything a master sent
+#[derive(Default)]
+pub struct Master {
+    pub frames:     u64,
+    pub commands:   BTreeMap<u8, u64>,
+    pub positions:  BTreeSet<u16>,
+    pub stations:   BTreeSet<u16>,
+    /// logical address ranges, start and length
+    pub logical:    BTreeSet<(u32, u16)>,
+    pub first:      f64,
+    pub last:       f64,
+}
+
+/// what we learned from the EtherCAT traffic in the capture
+#[derive(Default)]
+pub struct EthercatInfo {
+    /// keyed by the source MAC of the master
+    pub masters:    BTreeMap<MacAddr, Master>,
+}
+
+impl EthercatInfo {
+
+    /// handle the payload of an EtherCAT frame
+    pub fn ethernet(&mut self, frame: &Frame, payload: &[u8]) {
+
+        let Some(header) = le16(payload, 0) else {
+            return;
+        };
+        if (header >> 12) as u8 != TYPE_COMMANDS {
+            return;
+        }
+
+        // slaves set the locally administered bit of the source MAC on
+        // the way back. the returning frame repeats the datagrams of the
+        // master, so only the frames the master sent are counted
+        let smac = frame.smac;
+        if smac.is_local() && self.masters.contains_key(&smac.flip_local()) {
+            return;
+        }
+        if !smac.is_local() && !self.masters.contains_key(&smac) {
+            // the capture started with a returning frame
+            self.masters.remove(&smac.flip_local());
+        }
+
+        let time = frame.time();
+        let master = self.masters.entry(smac).or_insert_with(|| Master {
+            first: time,
+            ..Default::default()
+        });
+        master.frames += 1;
+        master.last = time;
+
+        let mut pos = 2;
+
+        while let (Some(&cmd), Some(len)) =
+            (payload.get(pos), le16(payload, pos+6)) {
+
+            let adp = le16(payload, pos+2).unwrap_or(0);
+            let logical = le32(payload, pos+2).unwrap_or(0);
+            let data_len = len & 0x07ff;
+
+            *master.commands.entry(cmd).or_insert(0) += 1;
+            match addressing(cmd, adp, logical, data_len) {
+                Addressing::Position(p) => { master.positions.insert(p); },
+                Addressing::Station(s) => { master.stations.insert(s); },
+                Addressing::Logical(a, l) => { master.logical.insert((a, l)); },
+                Addressing::Broadcast | Addressing::None => (),
+            }
+
+            if len & MORE_DATAGRAMS == 0 {
+                break;
+            }
+            pos += DATAGRAM_HEADER_LEN + data_len as usize + WKC_LEN;
+        }
+    }
+
+    /// write the EtherCAT section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.masters.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- EtherCAT Masters\n")?;
+
+        let join = |v: Vec<String>| v.join(", ");
+
+        for (mac, m) in &self.masters {
+
+            write!(file, "{}\n", mac)?;
+
+            let span = m.last - m.first;
+            if m.frames > 1 && span > 0.0 {
+                let cycle = span / (m.frames - 1) as f64 * 1000.0;
+                write!(file, "    frames:    {} (every {:.3} ms)\n", m.frames, cycle)?;
+            } else {
+                write!(file, "    frames:    {}\n", m.frames)?;
+            }
+
+            let commands = m.commands.iter()
+                .map(|(cmd, count)| format!("{} {}x", command_name(*cmd), count))
+                .collect();
+            write!(file, "    commands:  {}\n", join(commands))?;
+
+            if !m.positions.is_empty() {
+                let positions = m.positions.iter().map(|p| p.to_string()).collect();
+                write!(file, "    positions: {}\n", join(positions))?;
+            }
+            if !m.stations.is_empty() {
+                let stations = m.stations.iter()
+                    .map(|s| format!("0x{:04x}", s))
+                    .collect();
+                write!(file, "    stations:  {}\n", join(stations))?;
+            }
+            for (addr, len) in &m.logical {
+                write!(file, "    logical:   0x{:08x} ({} bytes)\n", addr, len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,1040 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! file extraction. files are rebuilt from HTTP bodies, FTP data
+//! connections, TFTP transfers and SMB2 reads and writes, and written
+//! to an output folder with a manifest of their names, endpoints and
+//! hashes.
+//!
+//! extraction only runs if an output folder is set. files are kept in
+//! memory until they are complete, data missing from a stream is
+//! filled with zeros and the file is marked incomplete.
+//!
+
+use std::fs::{self, File};
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::util;
+use crate::dissect::{Flow, http, be16, be32, le16, le32};
+use crate::dissect::creds::FTP_PORT;
+
+pub const TFTP_PORT:    u16 = 69;
+pub const SMB_PORT:     u16 = 445;
+
+/// the manifest written next to the files
+const MANIFEST: &str = "manifest.csv";
+
+/// files larger than this are cut off
+const MAX_FILE: usize = 1 << 28;
+
+/// longest HTTP head we wait for
+const MAX_HEAD: usize = 1 << 16;
+
+/// TFTP opcodes and the block size without options
+const TFTP_RRQ:     u16 = 1;
+const TFTP_WRQ:     u16 = 2;
+const TFTP_DATA:    u16 = 3;
+const TFTP_ERROR:   u16 = 5;
+const TFTP_OACK:    u16 = 6;
+const TFTP_BLKSIZE: usize = 512;
+
+/// SMB2 commands and status codes we need
+const SMB2_CREATE:      u16 = 5;
+const SMB2_CLOSE:       u16 = 6;
+const SMB2_READ:        u16 = 8;
+const SMB2_WRITE:       u16 = 9;
+const SMB2_HEADER:      usize = 64;
+const SMB2_RESPONSE:    u32 = 0x01;
+const STATUS_PENDING:   u32 = 0x103;
+
+/// a TCP connection from the sender's point of view
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// an address and port
+type Endpoint = (Ipv4Addr, u16);
+
+/// an SMB2 file handle
+type FileId = [u8; 16];
+
+/// a file being rebuilt
+struct Transfer {
+    protocol:       &'static str,
+    name:           String,
+    source:         Endpoint,
+    destination:    Endpoint,
+    time:           f64,
+    data:           Vec<u8>,
+    complete:       bool,
+}
+
+impl Transfer {
+
+    /// a file sent along `flow`
+    fn new(protocol: &'static str, name: String, flow: &Flow) -> Self {
+        Transfer {
+            protocol, name,
+            source:         (flow.sip, flow.sport),
+            destination:    (flow.dip, flow.dport),
+            time:           flow.time(),
+            data:           Vec::new(),
+            complete:       true,
+        }
+    }
+
+    /// add the next bytes of the file
+    fn append(&mut self, data: &[u8]) {
+        let room = MAX_FILE - self.data.len();
+        if data.len() > room {
+            self.complete = false;
+        }
+        self.data.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    /// `len` bytes of the file were not captured
+    fn missing(&mut self, len: usize) {
+        let len = len.min(MAX_FILE - self.data.len());
+        self.data.resize(self.data.len() + len, 0);
+        self.complete = false;
+    }
+
+}
+
+/// a file written to the output folder
+pub struct Extracted {
+    /// the name in the output folder
+    pub file:           String,
+    /// the name the file was transferred under
+    pub name:           String,
+    pub protocol:       &'static str,
+    pub size:           usize,
+    pub md5:            String,
+    pub sha256:         String,
+    pub source:         Endpoint,
+    pub destination:    Endpoint,
+    pub time:           f64,
+    pub complete:       bool,
+}
+
+/// how the end of an HTTP body is found
+enum Body {
+    /// bytes left of a Content-Length body
+    Length(usize),
+    Chunked(Chunk),
+    /// the body ends with the connection
+    Close,
+}
+
+/// where we are in a chunked body
+enum Chunk {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailer,
+}
+
+/// a direction carrying HTTP
+#[derive(Default)]
+struct HttpStream {
+    /// data not consumed yet
+    buf:    Vec<u8>,
+    /// the body being read
+    body:   Option<(Body, Transfer)>,
+}
+
+/// an FTP data connection announced on the control connection
+struct Channel {
+    /// the control connection, client side first
+    control:    ConnKey,
+    /// the file of the last RETR, STOR, STOU or APPE
+    name:       Option<String>,
+}
+
+/// a TFTP read or write request and its blocks
+struct TftpTransfer {
+    name:       String,
+    blksize:    usize,
+    /// blocks by number, counted on when the 16 bit number wraps
+    blocks:     BTreeMap<u32, Vec<u8>>,
+    last:       u16,
+    wraps:      u32,
+    /// the first DATA packet tells the direction
+    transfer:   Option<Transfer>,
+}
+
+/// an SMB2 request waiting for its response
+enum SmbRequest {
+    Create(String),
+    Read(FileId, u64),
+}
+
+/// an opened SMB2 file and the parts read or written
+struct SmbFile {
+    name:           String,
+    /// the size the server reported on CREATE
+    end_of_file:    u64,
+    chunks:         BTreeMap<u64, Vec<u8>>,
+    written:        bool,
+    time:           f64,
+}
+
+/// rebuilds transferred files and writes them out
+#[derive(Default)]
+pub struct FilesInfo {
+    /// the output folder, nothing is extracted without it
+    pub dir:        Option<PathBuf>,
+    pub extracted:  Vec<Extracted>,
+    /// HTTP directions
+    http:           HashMap<ConnKey, HttpStream>,
+    /// method and URI of requests waiting for a response, per
+    /// connection client side first
+    requests:       HashMap<ConnKey, VecDeque<(String, String)>>,
+    /// FTP control lines not complete yet
+    ftp_lines:      HashMap<ConnKey, Vec<u8>>,
+    /// FTP data connections by the listening endpoint
+    channels:       HashMap<Endpoint, Channel>,
+    /// the last data connection announced per control connection
+    last_channel:   HashMap<ConnKey, Endpoint>,
+    /// FTP data directions
+    ftp_data:       HashMap<ConnKey, (Endpoint, Transfer)>,
+    /// TFTP transfers by client endpoint
+    tftp:           HashMap<Endpoint, TftpTransfer>,
+    /// SMB data not consumed yet
+    smb_bufs:       HashMap<ConnKey, Vec<u8>>,
+    /// SMB2 requests by connection and message id
+    smb_requests:   HashMap<(ConnKey, u64), SmbRequest>,
+    /// open SMB2 files by connection and file id
+    smb_files:      HashMap<(ConnKey, FileId), SmbFile>,
+    /// directions we lost track of after a gap
+    lost:           HashSet<ConnKey>,
+}
+
+impl FilesInfo {
+
+    /// handle the next data of a direction, in order from the
+    /// reassembly
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        if self.dir.is_none() {
+            return;
+        }
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        if self.lost.contains(&key) {
+            return;
+        }
+
+        if flow.has_port(FTP_PORT) {
+            self.ftp_control(flow, payload);
+        } else if flow.has_port(SMB_PORT) {
+            self.smb(flow, payload);
+        } else if self.ftp_data.contains_key(&key) || self.channel(flow).is_some() {
+            self.ftp_data(flow, payload);
+        } else {
+            self.http(flow, payload);
+        }
+    }
+
+    /// data is missing from a direction
+    pub fn gap(&mut self, flow: &Flow, len: u32) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+
+        if let Some((_, transfer)) = self.ftp_data.get_mut(&key) {
+            transfer.missing(len as usize);
+        }
+
+        if self.smb_bufs.remove(&key).is_some() {
+            self.lost.insert(key);
+        }
+
+        let Some(mut stream) = self.http.remove(&key) else {
+            return;
+        };
+
+        // a hole in a body of known length keeps us in step with the
+        // messages, anywhere else we can't find the next head
+        let mut len = len as usize;
+        match stream.body.take() {
+            Some((Body::Length(left), mut transfer)) if stream.buf.is_empty() => {
+                transfer.missing(len.min(left));
+                if len < left {
+                    stream.body = Some((Body::Length(left - len), transfer));
+                    self.http.insert(key, stream);
+                    return;
+                }
+                len -= left;
+                self.save(transfer);
+                if len == 0 {
+                    self.http.insert(key, stream);
+                    return;
+                }
+            },
+            Some((Body::Close, mut transfer)) => {
+                transfer.missing(len);
+                stream.body = Some((Body::Close, transfer));
+                self.http.insert(key, stream);
+                return;
+            },
+            Some((_, mut transfer)) => {
+                transfer.complete = false;
+                self.save(transfer);
+            },
+            None => (),
+        }
+        self.lost.insert(key);
+    }
+
+    /// a direction ended, files that end with it are written
+    pub fn end(&mut self, flow: &Flow) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        self.lost.remove(&key);
+        self.ftp_lines.remove(&key);
+        self.smb_bufs.remove(&key);
+
+        if let Some(mut stream) = self.http.remove(&key) {
+            if let Some((body, mut transfer)) = stream.body.take() {
+                if !matches!(body, Body::Close) {
+                    transfer.complete = false;
+                }
+                self.save(transfer);
+            }
+        }
+        // the responses are over once the server's direction ended
+        self.requests.remove(&(flow.dip, flow.dport, flow.sip, flow.sport));
+
+        if let Some((endpoint, transfer)) = self.ftp_data.remove(&key) {
+            self.channels.remove(&endpoint);
+            self.save(transfer);
+        }
+
+        if flow.sport == SMB_PORT {
+            let conn = (flow.dip, flow.dport, flow.sip, flow.sport);
+            let ids: Vec<_> = self.smb_files.keys()
+                .filter(|(c, _)| *c == conn)
+                .copied()
+                .collect();
+            for id in ids {
+                self.smb_close(id);
+            }
+            self.smb_requests.retain(|(c, _), _| *c != conn);
+        }
+    }
+
+    /// write what is left at the end of the capture
+    pub fn finish(&mut self) {
+
+        let tftp: Vec<_> = self.tftp.drain().map(|(_, t)| t).collect();
+        for t in tftp {
+            self.tftp_save(t);
+        }
+
+        let ids: Vec<_> = self.smb_files.keys().copied().collect();
+        for id in ids {
+            self.smb_close(id);
+        }
+    }
+
+    /// follow HTTP messages and read their bodies
+    fn http(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+
+        // only directions starting with an HTTP head are followed
+        if !self.http.contains_key(&key) && http::parse(payload).is_none() {
+            return;
+        }
+
+        let mut stream = self.http.remove(&key).unwrap_or_default();
+        stream.buf.extend_from_slice(payload);
+
+        loop {
+            if let Some((body, transfer)) = &mut stream.body {
+                if !read_body(body, &mut stream.buf, transfer) {
+                    break;
+                }
+                if let Some((_, transfer)) = stream.body.take() {
+                    self.save(transfer);
+                }
+                continue;
+            }
+
+            let Some(end) = stream.buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if stream.buf.len() > MAX_HEAD {
+                    self.lost.insert(key);
+                    return;
+                }
+                break;
+            };
+
+            let head: Vec<u8> = stream.buf.drain(..end+4).collect();
+            match http::parse(&head) {
+                Some(http::HttpMessage::Request { method, uri, headers }) => {
+                    let name = file_name(&headers, &uri);
+                    self.requests.entry(key).or_default().push_back((method, uri));
+                    // requests only have a body if they say so
+                    let body = match body_length(&headers) {
+                        Some(Body::Close) | Some(Body::Length(0)) | None => None,
+                        body => body,
+                    };
+                    stream.body = body.map(|b| (b, Transfer::new("HTTP", name, flow)));
+                },
+                Some(http::HttpMessage::Response { status, headers }) => {
+                    let conn = (flow.dip, flow.dport, flow.sip, flow.sport);
+                    let (method, uri) = self.requests.get_mut(&conn)
+                        .and_then(|r| r.pop_front())
+                        .unwrap_or_default();
+                    if method == "HEAD" || status < 200 || status == 204 || status == 304 {
+                        continue;
+                    }
+                    let name = file_name(&headers, &uri);
+                    stream.body = match body_length(&headers) {
+                        Some(Body::Length(0)) => None,
+                        Some(body) => Some((body, Transfer::new("HTTP", name, flow))),
+                        None => Some((Body::Close, Transfer::new("HTTP", name, flow))),
+                    };
+                },
+                None => {
+                    self.lost.insert(key);
+                    return;
+                },
+            }
+        }
+
+        self.http.insert(key, stream);
+    }
+
+    /// follow the FTP control connection for data connections and the
+    /// files sent over them
+    fn ftp_control(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        let to_server = flow.dport == FTP_PORT;
+        let control = if to_server {
+            key
+        } else {
+            (flow.dip, flow.dport, flow.sip, flow.sport)
+        };
+
+        let mut buf = self.ftp_lines.remove(&key).unwrap_or_default();
+        buf.extend_from_slice(payload);
+
+        while let Some(eol) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=eol).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+
+            if to_server {
+                let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+                match command.to_uppercase().as_str() {
+                    "PORT" => self.announce(control, port_endpoint(arg)),
+                    "EPRT" => self.announce(control, eprt_endpoint(arg)),
+                    "RETR" | "STOR" | "STOU" | "APPE" => {
+                        let name = if arg.is_empty() { "stou" } else { arg };
+                        let channel = self.last_channel.get(&control)
+                            .and_then(|e| self.channels.get_mut(e));
+                        if let Some(channel) = channel {
+                            channel.name = Some(name.to_string());
+                        }
+                    },
+                    _ => (),
+                }
+            } else if line.starts_with("227") {
+                let arg = line.split('(').nth(1).unwrap_or("").trim_end_matches(')');
+                self.announce(control, port_endpoint(arg));
+            } else if line.starts_with("229") {
+                // the server's address with the port in (|||port|)
+                let port = line.split("|||").nth(1)
+                    .and_then(|p| p.split('|').next())
+                    .and_then(|p| p.parse::<u16>().ok());
+                self.announce(control, port.map(|p| (flow.sip, p)));
+            }
+        }
+
+        // a line this long is not FTP
+        if buf.len() < MAX_HEAD {
+            self.ftp_lines.insert(key, buf);
+        }
+    }
+
+    /// remember a data connection announced on `control`
+    fn announce(&mut self, control: ConnKey, endpoint: Option<Endpoint>) {
+        if let Some(endpoint) = endpoint {
+            self.channels.insert(endpoint, Channel { control, name: None });
+            self.last_channel.insert(control, endpoint);
+        }
+    }
+
+    /// the announced endpoint of the data connection `flow` belongs to
+    fn channel(&self, flow: &Flow) -> Option<Endpoint> {
+        [(flow.sip, flow.sport), (flow.dip, flow.dport)].into_iter()
+            .find(|e| self.channels.contains_key(e))
+    }
+
+    /// collect the data of an FTP data connection
+    fn ftp_data(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+
+        if !self.ftp_data.contains_key(&key) {
+            let Some(endpoint) = self.channel(flow) else {
+                return;
+            };
+            let name = self.channels.get(&endpoint)
+                .and_then(|c| c.name.clone())
+                .unwrap_or_else(|| "ftp-data".to_string());
+            if let Some(channel) = self.channels.get(&endpoint) {
+                // the next transfer gets its own data connection
+                self.last_channel.remove(&channel.control);
+            }
+            self.ftp_data.insert(key, (endpoint, Transfer::new("FTP", name, flow)));
+        }
+
+        if let Some((_, transfer)) = self.ftp_data.get_mut(&key) {
+            transfer.append(payload);
+        }
+    }
+
+    /// true if `flow` belongs to a TFTP transfer
+    pub fn is_tftp(&self, flow: &Flow) -> bool {
+        self.tftp.contains_key(&(flow.sip, flow.sport))
+            || self.tftp.contains_key(&(flow.dip, flow.dport))
+    }
+
+    /// handle a TFTP packet
+    pub fn tftp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        if self.dir.is_none() {
+            return;
+        }
+
+        let Some(opcode) = be16(payload, 0) else {
+            return;
+        };
+
+        // the client is the side that sent the request, the server
+        // answers from a port of its own
+        let client = if self.tftp.contains_key(&(flow.sip, flow.sport)) {
+            (flow.sip, flow.sport)
+        } else {
+            (flow.dip, flow.dport)
+        };
+
+        match opcode {
+            TFTP_RRQ | TFTP_WRQ if flow.dport == TFTP_PORT => {
+                let fields: Vec<_> = payload[2..].split(|b| *b == 0)
+                    .map(|f| String::from_utf8_lossy(f).to_string())
+                    .collect();
+                let Some(name) = fields.first() else {
+                    return;
+                };
+                // options in the request only apply once the server
+                // confirms them with an OACK
+                let transfer = TftpTransfer {
+                    name:       name.clone(),
+                    blksize:    TFTP_BLKSIZE,
+                    blocks:     BTreeMap::new(),
+                    last:       0,
+                    wraps:      0,
+                    transfer:   None,
+                };
+                if let Some(old) = self.tftp.insert((flow.sip, flow.sport), transfer) {
+                    self.tftp_save(old);
+                }
+            },
+            TFTP_OACK => {
+                let fields: Vec<_> = payload[2..].split(|b| *b == 0)
+                    .map(|f| String::from_utf8_lossy(f).to_string())
+                    .collect();
+                if let Some(t) = self.tftp.get_mut(&client) {
+                    t.blksize = blksize(&fields);
+                }
+            },
+            TFTP_DATA => {
+                let (Some(block), Some(data)) = (be16(payload, 2), payload.get(4..)) else {
+                    return;
+                };
+                let Some(t) = self.tftp.get_mut(&client) else {
+                    return;
+                };
+                if block < t.last && t.last - block > u16::MAX / 2 {
+                    t.wraps += 1;
+                }
+                t.last = block;
+                let number = (t.wraps << 16) | block as u32;
+                let name = t.name.clone();
+                t.transfer.get_or_insert_with(|| Transfer::new("TFTP", name, flow));
+                t.blocks.entry(number).or_insert_with(|| data.to_vec());
+
+                // a short block ends the transfer
+                if data.len() < t.blksize {
+                    if let Some(t) = self.tftp.remove(&client) {
+                        self.tftp_save(t);
+                    }
+                }
+            },
+            TFTP_ERROR => {
+                self.tftp.remove(&client);
+            },
+            _ => (),
+        }
+    }
+
+    /// put the blocks of a TFTP transfer together and write it
+    fn tftp_save(&mut self, t: TftpTransfer) {
+
+        let Some(mut transfer) = t.transfer else {
+            return;
+        };
+
+        // blocks count from 1, missing blocks are filled with zeros as
+        // long as that doesn't make up more than was captured
+        let captured: usize = t.blocks.values().map(|d| d.len()).sum();
+        let mut filled = 0;
+        let mut expected: u32 = 1;
+        for (number, data) in &t.blocks {
+            let Some(hole) = number.checked_sub(expected) else {
+                continue;
+            };
+            let hole = hole as usize * t.blksize;
+            filled += hole;
+            if filled > captured {
+                transfer.complete = false;
+                break;
+            }
+            if hole > 0 {
+                transfer.missing(hole);
+            }
+            transfer.append(data);
+            expected = number + 1;
+        }
+        if t.blocks.values().last().map(|d| d.len() >= t.blksize).unwrap_or(true) {
+            transfer.complete = false;
+        }
+
+        self.save(transfer);
+    }
+
+    /// take the NetBIOS frames of an SMB direction
+    fn smb(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        let mut buf = self.smb_bufs.remove(&key).unwrap_or_default();
+        buf.extend_from_slice(payload);
+
+        let mut pos = 0;
+        while pos + 4 <= buf.len() {
+            let len = (be32(&buf, pos).unwrap_or(0) & 0x00ff_ffff) as usize;
+            let Some(frame) = buf.get(pos+4..pos+4+len) else {
+                break;
+            };
+            // 0x00 is a session message, the rest is session setup
+            if buf[pos] == 0 {
+                self.smb_frame(flow, frame);
+            }
+            pos += 4 + len;
+        }
+        buf.drain(..pos);
+
+        self.smb_bufs.insert(key, buf);
+    }
+
+    /// handle the SMB2 messages of a frame, there may be several
+    /// compounded ones
+    fn smb_frame(&mut self, flow: &Flow, frame: &[u8]) {
+
+        let conn = if flow.dport == SMB_PORT {
+            (flow.sip, flow.sport, flow.dip, flow.dport)
+        } else {
+            (flow.dip, flow.dport, flow.sip, flow.sport)
+        };
+
+        let mut pos = 0;
+        while let Some(msg) = frame.get(pos..) {
+            if !msg.starts_with(b"\xfeSMB") || msg.len() < SMB2_HEADER {
+                return;
+            }
+            let next = le32(msg, 20).unwrap_or(0) as usize;
+            let msg = if next > 0 { &msg[..next.min(msg.len())] } else { msg };
+            self.smb_message(flow, conn, msg);
+            if next == 0 {
+                return;
+            }
+            pos += next;
+        }
+    }
+
+    /// handle an SMB2 message, offsets in it count from its header
+    fn smb_message(&mut self, flow: &Flow, conn: ConnKey, msg: &[u8]) -> Option<()> {
+
+        let status = le32(msg, 8)?;
+        let command = le16(msg, 12)?;
+        let response = le32(msg, 16)? & SMB2_RESPONSE != 0;
+        let id = u64::from_le_bytes(msg.get(24..32)?.try_into().ok()?);
+        let body = SMB2_HEADER;
+
+        if response {
+            if status == STATUS_PENDING {
+                return Some(());
+            }
+            let request = self.smb_requests.remove(&(conn, id))?;
+            if status != 0 {
+                return Some(());
+            }
+            match (command, request) {
+                (SMB2_CREATE, SmbRequest::Create(name)) => {
+                    let end_of_file = u64::from_le_bytes(msg.get(body+48..body+56)?.try_into().ok()?);
+                    let file_id: FileId = msg.get(body+64..body+80)?.try_into().ok()?;
+                    self.smb_files.insert((conn, file_id), SmbFile {
+                        name, end_of_file,
+                        chunks:     BTreeMap::new(),
+                        written:    false,
+                        time:       flow.time(),
+                    });
+                },
+                (SMB2_READ, SmbRequest::Read(file_id, offset)) => {
+                    let start = *msg.get(body+2)? as usize;
+                    let len = le32(msg, body+4)? as usize;
+                    let data = msg.get(start..start+len)?;
+                    let file = self.smb_files.get_mut(&(conn, file_id))?;
+                    file.chunks.insert(offset, data.to_vec());
+                },
+                _ => (),
+            }
+            return Some(());
+        }
+
+        match command {
+            SMB2_CREATE => {
+                let start = le16(msg, body+44)? as usize;
+                let len = le16(msg, body+46)? as usize;
+                let name = utf16(msg.get(start..start+len)?);
+                self.smb_requests.insert((conn, id), SmbRequest::Create(name));
+            },
+            SMB2_READ => {
+                let offset = u64::from_le_bytes(msg.get(body+8..body+16)?.try_into().ok()?);
+                let file_id: FileId = msg.get(body+16..body+32)?.try_into().ok()?;
+                self.smb_requests.insert((conn, id), SmbRequest::Read(file_id, offset));
+            },
+            SMB2_WRITE => {
+                let start = le16(msg, body+2)? as usize;
+                let len = le32(msg, body+4)? as usize;
+                let offset = u64::from_le_bytes(msg.get(body+8..body+16)?.try_into().ok()?);
+                let file_id: FileId = msg.get(body+16..body+32)?.try_into().ok()?;
+                let data = msg.get(start..start+len)?;
+                let file = self.smb_files.get_mut(&(conn, file_id))?;
+                file.chunks.insert(offset, data.to_vec());
+                file.written = true;
+            },
+            SMB2_CLOSE => {
+                let file_id: FileId = msg.get(body+8..body+24)?.try_into().ok()?;
+                self.smb_close((conn, file_id));
+            },
+            _ => (),
+        }
+
+        Some(())
+    }
+
+    /// put the parts of an SMB2 file together and write it
+    fn smb_close(&mut self, id: (ConnKey, FileId)) {
+
+        let Some(file) = self.smb_files.remove(&id) else {
+            return;
+        };
+        if file.chunks.is_empty() {
+            return;
+        }
+
+        // written files go to the server, read files to the client
+        let ((client, cport, server, sport), _) = id;
+        let (source, destination) = if file.written {
+            ((client, cport), (server, sport))
+        } else {
+            ((server, sport), (client, cport))
+        };
+
+        let mut transfer = Transfer {
+            protocol:   "SMB2",
+            name:       file.name,
+            source, destination,
+            time:       file.time,
+            data:       Vec::new(),
+            complete:   true,
+        };
+
+        // parts may overlap when a file is read twice. holes are filled
+        // with zeros within the size the server reported, and never with
+        // more than was captured
+        let captured: u64 = file.chunks.values().map(|d| d.len() as u64).sum();
+        let mut filled = 0;
+        for (offset, data) in &file.chunks {
+            let len = transfer.data.len() as u64;
+            if *offset > len {
+                filled += offset - len;
+                if filled > captured || *offset > file.end_of_file.max(captured) {
+                    transfer.complete = false;
+                    break;
+                }
+                transfer.missing((offset - len) as usize);
+            }
+            let (offset, len) = (*offset as usize, len as usize);
+            if let Some(data) = data.get(len.saturating_sub(offset)..) {
+                transfer.append(data);
+            }
+        }
+        if (transfer.data.len() as u64) < file.end_of_file && !file.written {
+            transfer.complete = false;
+        }
+
+        self.save(transfer);
+    }
+
+    /// write a file to the output folder and add it to the manifest
+    fn save(&mut self, transfer: Transfer) {
+
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if transfer.data.is_empty() {
+            return;
+        }
+
+        let file = format!("{:04}-{}", self.extracted.len() + 1, safe_name(&transfer.name));
+        let path = dir.join(&file);
+        if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, &transfer.data)) {
+            eprint!("error: cannot write {}: {}\n", path.display(), e);
+            return;
+        }
+
+        self.extracted.push(Extracted {
+            file,
+            name:           transfer.name,
+            protocol:       transfer.protocol,
+            size:           transfer.data.len(),
+            md5:            util::hex(&Md5::digest(&transfer.data)),
+            sha256:         util::hex(&Sha256::digest(&transfer.data)),
+            source:         transfer.source,
+            destination:    transfer.destination,
+            time:           transfer.time,
+            complete:       transfer.complete,
+        });
+    }
+
+    /// write the manifest of the extracted files to the output folder
+    pub fn manifest(&self) -> Result<(), Error> {
+
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(dir)?;
+        let mut file = File::create(dir.join(MANIFEST))?;
+
+        write!(file, "file,name,protocol,size,md5,sha256,source,destination,time,complete\n")?;
+        for e in &self.extracted {
+            write!(file, "{},{},{},{},{},{},{}:{},{}:{},{:.6},{}\n",
+                util::csv_field(&e.file), util::csv_field(&e.name), e.protocol,
+                e.size, e.md5, e.sha256, e.source.0, e.source.1,
+                e.destination.0, e.destination.1, e.time, e.complete)?;
+        }
+
+        Ok(())
+    }
+
+    /// write the extracted files to the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.extracted.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- Extracted Files (source -> destination)\n")?;
+        for e in &self.extracted {
+            let incomplete = if e.complete { "" } else { "  [INCOMPLETE]" };
+            write!(file, "{}:{} -> {}:{} {}\n",
+                e.source.0, e.source.1, e.destination.0, e.destination.1, e.protocol)?;
+            write!(file, "    file:    {}  ({} bytes){}\n", e.file, e.size, incomplete)?;
+            write!(file, "    sha256:  {}\n", e.sha256)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+/// read as much of an HTTP body from `buf` as there is, returns true
+/// once the body is complete
+fn read_body(body: &mut Body, buf: &mut Vec<u8>, transfer: &mut Transfer) -> bool {
+
+    match body {
+        Body::Length(left) => {
+            let len = (*left).min(buf.len());
+            transfer.append(&buf[..len]);
+            buf.drain(..len);
+            *left -= len;
+            *left == 0
+        },
+        Body::Close => {
+            transfer.append(buf);
+            buf.clear();
+            false
+        },
+        Body::Chunked(chunk) => loop {
+            match chunk {
+                Chunk::Size => {
+                    let Some(eol) = buf.windows(2).position(|w| w == b"\r\n") else {
+                        return false;
+                    };
+                    let line = String::from_utf8_lossy(&buf[..eol]).to_string();
+                    buf.drain(..eol+2);
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    match usize::from_str_radix(size, 16) {
+                        Ok(0) => *chunk = Chunk::Trailer,
+                        Ok(size) => *chunk = Chunk::Data(size),
+                        Err(_) => {
+                            transfer.complete = false;
+                            return true;
+                        },
+                    }
+                },
+                Chunk::Data(left) => {
+                    let len = (*left).min(buf.len());
+                    transfer.append(&buf[..len]);
+                    buf.drain(..len);
+                    *left -= len;
+                    if *left > 0 {
+                        return false;
+                    }
+                    *chunk = Chunk::DataEnd;
+                },
+                Chunk::DataEnd => {
+                    if buf.len() < 2 {
+                        return false;
+                    }
+                    buf.drain(..2);
+                    *chunk = Chunk::Size;
+                },
+                Chunk::Trailer => {
+                    let Some(eol) = buf.windows(2).position(|w| w == b"\r\n") else {
+                        return false;
+                    };
+                    buf.drain(..eol+2);
+                    if eol == 0 {
+                        return true;
+                    }
+                },
+            }
+        },
+    }
+}
+
+/// how the body after a head with `headers` ends, `None` if the head
+/// doesn't say
+fn body_length(headers: &[(String, String)]) -> Option<Body> {
+
+    let chunked = http::header(headers, "Transfer-Encoding")
+        .map(|v| v.to_lowercase().contains("chunked"))
+        .unwrap_or(false);
+    if chunked {
+        return Some(Body::Chunked(Chunk::Size));
+    }
+
+    http::header(headers, "Content-Length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(Body::Length)
+}
+
+/// the name of an HTTP body, from Content-Disposition or the last part
+/// of the path
+fn file_name(headers: &[(String, String)], uri: &str) -> String {
+
+    let disposition = http::header(headers, "Content-Disposition")
+        .and_then(|v| v.split(';').find_map(|p| p.trim().strip_prefix("filename=")))
+        .map(|n| n.trim_matches('"'));
+    if let Some(name) = disposition {
+        return name.to_string();
+    }
+
+    let path = uri.split(['?', '#']).next().unwrap_or("");
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => "index".to_string(),
+    }
+}
+
+/// the endpoint of a PORT command or a 227 reply, "h1,h2,h3,h4,p1,p2"
+fn port_endpoint(arg: &str) -> Option<Endpoint> {
+    let n: Vec<u8> = arg.split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if n.len() != 6 {
+        return None;
+    }
+    Some((Ipv4Addr::new(n[0], n[1], n[2], n[3]), u16::from_be_bytes([n[4], n[5]])))
+}
+
+/// the endpoint of an EPRT command, "|1|address|port|"
+fn eprt_endpoint(arg: &str) -> Option<Endpoint> {
+    let fields: Vec<&str> = arg.split('|').collect();
+    if fields.get(1) != Some(&"1") {
+        return None;
+    }
+    Some((fields.get(2)?.parse().ok()?, fields.get(3)?.parse().ok()?))
+}
+
+/// the block size from the options of a TFTP OACK, given as name and
+/// value fields
+fn blksize(options: &[String]) -> usize {
+    options.chunks(2)
+        .find(|o| o[0].eq_ignore_ascii_case("blksize"))
+        .and_then(|o| o.get(1))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(TFTP_BLKSIZE)
+}
+
+/// decode an UTF-16LE string
+fn utf16(buf: &[u8]) -> String {
+    let units: Vec<u16> = buf.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// the last part of a path, made safe to use in the output folder
+fn safe_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+        .take(100)
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name.to_string()
+    }
+}
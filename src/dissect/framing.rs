@@ -0,0 +1,407 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! message framing on top of the reassembled TCP streams. the data of
+//! a direction is held back until the messages in it are complete, so
+//! the dissectors only see whole messages even if a message was split
+//! over several segments.
+//!
+//! data that doesn't look like the expected protocol is handed on as it
+//! is, the dissectors check what they get anyway.
+//!
+
+use std::net::Ipv4Addr;
+use std::collections::HashMap;
+
+use crate::dissect::{Flow, be16, le16, le32, enip, http, mqtt};
+
+/// messages longer than this are not held back
+const MAX_MESSAGE: usize = 1 << 20;
+
+/// HTTP and SIP bodies up to this size are kept with their head,
+/// larger ones are skipped
+const MAX_BODY: usize = 1 << 16;
+
+/// longest head or line we wait for
+const MAX_HEAD: usize = 1 << 16;
+
+/// SIP request methods, the HTTP ones come from `http::METHODS`
+const SIP_METHODS: [&str; 14] = [
+    "INVITE", "ACK", "BYE", "CANCEL", "REGISTER", "OPTIONS", "PRACK",
+    "SUBSCRIBE", "NOTIFY", "PUBLISH", "INFO", "REFER", "MESSAGE", "UPDATE",
+];
+
+/// a TCP connection from the sender's point of view
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// how a protocol marks where its messages end
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Framing {
+    /// DNS over TCP, a 2 byte length before each message
+    Dns,
+    /// Modbus/TCP MBAP header
+    Mbap,
+    /// TPKT as used by ISO-TSAP
+    Tpkt,
+    /// EtherNet/IP encapsulation header
+    Encap,
+    /// IEC 60870-5-104 APCI
+    Apci,
+    /// DNP3 link frames with their CRCs
+    Dnp3,
+    /// MQTT fixed header
+    Mqtt,
+    /// OPC UA TCP message header
+    OpcUa,
+    /// HTTP and SIP heads and their Content-Length bodies
+    Http,
+    /// text lines ending with LF
+    Lines,
+}
+
+impl Framing {
+
+    const ALL: [Framing; 10] = [
+        Framing::Dns, Framing::Mbap, Framing::Tpkt, Framing::Encap,
+        Framing::Apci, Framing::Dnp3, Framing::Mqtt, Framing::OpcUa,
+        Framing::Http, Framing::Lines,
+    ];
+
+    /// the length of the message at the start of `buf` and the number
+    /// of bytes after it to skip. `Ok(None)` if more data is needed,
+    /// `Err` if `buf` doesn't start with such a message. `scanned`
+    /// keeps how far the search for the end of a head got.
+    fn frame(self, buf: &[u8], scanned: &mut usize) -> Result<Option<(usize, usize)>, ()> {
+
+        let len = match self {
+            Framing::Dns => be16(buf, 0).map(|len| 2 + len as usize),
+            Framing::Mbap => match (be16(buf, 2), be16(buf, 4)) {
+                (Some(0), Some(len)) => Some(6 + len as usize),
+                (Some(0), None) | (None, _) => None,
+                (Some(_), _) => return Err(()),
+            },
+            Framing::Tpkt => {
+                if buf.first().is_some_and(|b| *b != 3) {
+                    return Err(());
+                }
+                match be16(buf, 2) {
+                    Some(len) if len < 4 => return Err(()),
+                    len => len.map(|len| len as usize),
+                }
+            },
+            Framing::Encap => {
+                if le16(buf, 0).is_some_and(|cmd| !enip::is_command(cmd)) {
+                    return Err(());
+                }
+                le16(buf, 2).filter(|_| buf.len() >= 24)
+                    .map(|len| 24 + len as usize)
+            },
+            Framing::Apci => {
+                if buf.first().is_some_and(|b| *b != 0x68) {
+                    return Err(());
+                }
+                buf.get(1).map(|len| 2 + *len as usize)
+            },
+            Framing::Dnp3 => {
+                if !buf.iter().zip([0x05, 0x64]).all(|(b, s)| *b == s) {
+                    return Err(());
+                }
+                // the length counts control, addresses and user data,
+                // the header and each 16 byte block carry a CRC
+                match buf.get(2) {
+                    Some(len) if *len < 5 => return Err(()),
+                    Some(len) => {
+                        let user = *len as usize - 5;
+                        Some(10 + user + user.div_ceil(16) * 2)
+                    },
+                    None => None,
+                }
+            },
+            Framing::Mqtt => match mqtt::varint(buf, 1) {
+                Some((len, size)) => Some(1 + size + len),
+                None if buf.len() >= 5 => return Err(()),
+                None => None,
+            },
+            Framing::OpcUa => {
+                if buf.len() >= 4 && !b"FCA".contains(&buf[3]) {
+                    return Err(());
+                }
+                match le32(buf, 4) {
+                    Some(len) if len < 8 => return Err(()),
+                    len => len.map(|len| len as usize),
+                }
+            },
+            Framing::Http => return http_frame(buf, scanned),
+            Framing::Lines => match buf.iter().position(|b| *b == b'\n') {
+                Some(eol) => Some(eol + 1),
+                None if buf.len() > MAX_HEAD => return Err(()),
+                None => None,
+            },
+        };
+
+        match len {
+            Some(len) if len > MAX_MESSAGE => Err(()),
+            len => Ok(len.map(|len| (len, 0))),
+        }
+    }
+
+}
+
+/// the length of an HTTP or SIP head at the start of `buf`, with its
+/// body if that is small enough
+fn http_frame(buf: &[u8], scanned: &mut usize) -> Result<Option<(usize, usize)>, ()> {
+
+    // a start line begins with a method we know or the protocol
+    // version, wait for more if `buf` is too short to tell
+    let methods = || http::METHODS.iter().chain(SIP_METHODS.iter());
+    let versions = [&b"HTTP/"[..], b"SIP/"];
+    match buf.iter().position(|b| *b == b' ') {
+        _ if versions.iter().any(|v| buf.starts_with(v)) => (),
+        Some(end) if methods().any(|m| m.as_bytes() == &buf[..end]) => (),
+        None if methods().any(|m| m.as_bytes().starts_with(buf))
+            || versions.iter().any(|v| v.starts_with(buf)) => return Ok(None),
+        _ => return Err(()),
+    }
+
+    // the end of the head can't be in what was looked at before, but
+    // may start in its last three bytes
+    let from = scanned.saturating_sub(3).min(buf.len());
+    let Some(end) = buf[from..].windows(4).position(|w| w == b"\r\n\r\n") else {
+        *scanned = buf.len();
+        return if buf.len() > MAX_HEAD { Err(()) } else { Ok(None) };
+    };
+    let end = from + end;
+    let head = end + 4;
+
+    // SIP may use the compact form "l"
+    let body = String::from_utf8_lossy(&buf[..end]).split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| {
+            let name = name.trim();
+            name.eq_ignore_ascii_case("Content-Length") || name == "l"
+        })
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    // these responses have no body whatever they say, neither has the
+    // response to HEAD, which we notice by the next message following
+    let status = buf.strip_prefix(b"HTTP/")
+        .and_then(|b| b.get(4..7))
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .and_then(|s| s.parse::<u16>().ok());
+    let body = match status {
+        Some(s) if s < 200 || s == 204 || s == 304 => 0,
+        _ => body,
+    };
+    let rest = &buf[head..];
+    if rest.len() < body.min(5) {
+        return Ok(None);
+    }
+    let body = if rest.starts_with(b"HTTP/") || rest.starts_with(b"SIP/") { 0 } else { body };
+
+    if body <= MAX_BODY {
+        Ok(Some((head + body, 0)))
+    } else {
+        Ok(Some((head, body)))
+    }
+}
+
+/// data of a direction that doesn't make a complete message yet
+#[derive(Default)]
+struct Partial {
+    buf:        Vec<u8>,
+    /// bytes still to skip
+    skip:       usize,
+    /// bytes of `buf` looked at without finding the end of the message
+    scanned:    usize,
+}
+
+/// holds back the data of each direction until its messages are
+/// complete
+#[derive(Default)]
+pub struct Framer {
+    partial: HashMap<(ConnKey, Framing), Partial>,
+}
+
+impl Framer {
+
+    /// add the next data of the direction `flow`, returns the messages
+    /// it completes. data we can't frame comes back as it is.
+    pub fn messages(&mut self, framing: Framing, flow: &Flow, data: &[u8]) -> Vec<Vec<u8>> {
+
+        let key = ((flow.sip, flow.sport, flow.dip, flow.dport), framing);
+        let mut partial = self.partial.remove(&key).unwrap_or_default();
+
+        // a skipped body cut short by the next response
+        if framing == Framing::Http && data.starts_with(b"HTTP/") {
+            partial.skip = 0;
+        }
+        let skip = partial.skip.min(data.len());
+        partial.skip -= skip;
+        partial.buf.extend_from_slice(&data[skip..]);
+
+        let buf = &partial.buf;
+        let mut out = Vec::new();
+        let mut pos = 0;
+        let mut scanned = partial.scanned;
+
+        while pos < buf.len() {
+            match framing.frame(&buf[pos..], &mut scanned) {
+                Ok(Some((len, skip))) if pos + len <= buf.len() => {
+                    out.push(buf[pos..pos+len].to_vec());
+                    pos += len;
+                    let skipped = skip.min(buf.len() - pos);
+                    pos += skipped;
+                    partial.skip = skip - skipped;
+                    scanned = 0;
+                },
+                Ok(_) => break,
+                Err(()) => {
+                    // not what we expected, the dissectors decide
+                    out.push(buf[pos..].to_vec());
+                    pos = buf.len();
+                    scanned = 0;
+                },
+            }
+        }
+
+        partial.buf.drain(..pos);
+        partial.scanned = scanned;
+
+        if !partial.buf.is_empty() || partial.skip > 0 {
+            self.partial.insert(key, partial);
+        }
+
+        out
+    }
+
+    /// forget what is held back for the direction `flow`, after a gap
+    /// or at its end
+    pub fn reset(&mut self, flow: &Flow) {
+        let conn = (flow.sip, flow.sport, flow.dip, flow.dport);
+        for framing in Framing::ALL {
+            self.partial.remove(&(conn, framing));
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::pinfo::MacAddr;
+
+    fn flow() -> Flow {
+        Flow {
+            smac:   MacAddr::new(&[0, 1, 2, 3, 4, 5]),
+            dmac:   MacAddr::new(&[6, 7, 8, 9, 10, 11]),
+            sip:    Ipv4Addr::new(10, 0, 0, 1),
+            dip:    Ipv4Addr::new(10, 0, 0, 2),
+            sport:  40000,
+            dport:  502,
+            ts:     0,
+            usec:   0,
+        }
+    }
+
+    #[test]
+    fn split_message() {
+        let mut f = Framer::default();
+        let adu = [0, 1, 0, 0, 0, 6, 1, 3, 0, 0, 0, 10];
+        assert!(f.messages(Framing::Mbap, &flow(), &adu[..5]).is_empty());
+        assert_eq!(f.messages(Framing::Mbap, &flow(), &adu[5..]), vec![adu.to_vec()]);
+        assert!(f.partial.is_empty());
+    }
+
+    #[test]
+    fn several_messages() {
+        let mut f = Framer::default();
+        let data = [3, 0, 0, 5, 1, 3, 0, 0, 6, 1, 2, 3, 0];
+        let messages = f.messages(Framing::Tpkt, &flow(), &data);
+        assert_eq!(messages, vec![vec![3, 0, 0, 5, 1], vec![3, 0, 0, 6, 1, 2]]);
+        assert_eq!(f.partial.len(), 1);
+        f.reset(&flow());
+        assert!(f.partial.is_empty());
+    }
+
+    #[test]
+    fn not_framed() {
+        let mut f = Framer::default();
+        let data = b"\x16\x03\x01 not a TPKT";
+        assert_eq!(f.messages(Framing::Tpkt, &flow(), data), vec![data.to_vec()]);
+        assert!(f.partial.is_empty());
+    }
+
+    #[test]
+    fn http_bodies() {
+        let mut f = Framer::default();
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n".to_vec();
+        let mut data = head.clone();
+        data.extend_from_slice(b"bo");
+        assert!(f.messages(Framing::Http, &flow(), &data).is_empty());
+        let messages = f.messages(Framing::Http, &flow(), b"dyHTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].ends_with(b"\r\n\r\nbody"));
+
+        // a response to HEAD has no body whatever it says
+        let mut data = head.clone();
+        data.extend_from_slice(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(f.messages(Framing::Http, &flow(), &data).len(), 2);
+
+        // large bodies are skipped
+        let big = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 10);
+        let mut data = big.as_bytes().to_vec();
+        data.extend_from_slice(&vec![0x41; MAX_BODY]);
+        assert_eq!(f.messages(Framing::Http, &flow(), &data), vec![big.as_bytes().to_vec()]);
+        let messages = f.messages(Framing::Http, &flow(), b"AAAAAAAAAAGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(messages, vec![b"GET / HTTP/1.1\r\n\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn http_start_lines() {
+        let mut f = Framer::default();
+
+        // other protocols are not held back
+        let data = b"USER x\r\n";
+        assert_eq!(f.messages(Framing::Http, &flow(), data), vec![data.to_vec()]);
+        assert!(f.partial.is_empty());
+
+        // a head arriving in small pieces
+        let head = b"GET /index.html HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut messages = Vec::new();
+        for piece in head.chunks(3) {
+            messages.extend(f.messages(Framing::Http, &flow(), piece));
+        }
+        assert_eq!(messages, vec![head.to_vec()]);
+        assert!(f.partial.is_empty());
+    }
+
+    #[test]
+    fn encap_commands() {
+        let mut f = Framer::default();
+        let mut header = vec![0x65, 0, 4, 0];
+        header.extend_from_slice(&[0; 20]);
+        assert!(f.messages(Framing::Encap, &flow(), &header).is_empty());
+        f.reset(&flow());
+        let data = b"\x16\x03\x01\x00\x10 not encapsulated";
+        assert_eq!(f.messages(Framing::Encap, &flow(), data), vec![data.to_vec()]);
+    }
+
+}
@@ -0,0 +1,306 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use crate::util;
+use crate::dissect::Flow;
+
+/// request methods we recognize at the start of a message
+pub const METHODS: [&str; 9] = [
+    "GET", "POST", "HEAD", "PUT", "DELETE",
+    "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// a decoded request or response head
+pub enum HttpMessage {
+    Request {
+        method:     String,
+        uri:        String,
+        headers:    Vec<(String, String)>,
+    },
+    Response {
+        status:     u16,
+        headers:    Vec<(String, String)>,
+    },
+}
+
+/// get the value of the header `name`, names are case insensitive
+pub fn header<'a>(headers: &'a [(String, String)], name: &str)
+    -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// parse the head of a request or response at the start of `buf`, the
+/// framing hands over whole heads. a head without the empty line at
+/// its end, from a stream the framing gave up on, gives the headers we
+/// get.
+pub fn parse(buf: &[u8]) -> Option<HttpMessage> {
+
+    // cheap check before we look at the data as text
+    if !buf.starts_with(b"HTTP/1.")
+        && !METHODS.iter().any(|m| buf.starts_with(m.as_bytes())) {
+        return None;
+    }
+
+    let end = buf.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(buf.len());
+    let head = String::from_utf8_lossy(&buf[..end]);
+    let mut lines = head.split("\r\n");
+
+    let start = lines.next()?;
+    let mut parts = start.splitn(3, ' ');
+    let first = parts.next()?;
+    let second = parts.next()?;
+    let third = parts.next().unwrap_or("");
+
+    let mut headers = Vec::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    if first.starts_with("HTTP/1.") {
+        let status = second.parse::<u16>().ok()?;
+        return Some(HttpMessage::Response { status, headers });
+    }
+
+    if !METHODS.contains(&first) || !third.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    Some(HttpMessage::Request {
+        method: first.to_string(),
+        uri:    second.to_string(),
+        headers,
+    })
+}
+
+/// a request and the response to it, if we saw one
+pub struct Transaction {
+    pub client:         Ipv4Addr,
+    pub server:         Ipv4Addr,
+    pub port:           u16,
+    pub method:         String,
+    pub host:           String,
+    pub uri:            String,
+    pub user_agent:     String,
+    pub status:         Option<u16>,
+    pub content_type:   String,
+    pub server_header:  String,
+}
+
+impl Transaction {
+
+    /// an empty transaction between `client` and `server`
+    pub fn new(client: Ipv4Addr, server: Ipv4Addr, port: u16) -> Self {
+        Transaction {
+            client, server, port,
+            method:         String::new(),
+            host:           String::new(),
+            uri:            String::new(),
+            user_agent:     String::new(),
+            status:         None,
+            content_type:   String::new(),
+            server_header:  String::new(),
+        }
+    }
+
+    /// the host a transaction is reported under, the Host header if the
+    /// client sent one
+    pub fn host_key(&self) -> String {
+        if self.host.is_empty() {
+            format!("{}:{}", self.server, self.port)
+        } else {
+            self.host.clone()
+        }
+    }
+
+}
+
+/// a TCP connection, client side first
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// what we learned from the cleartext HTTP traffic in the capture
+#[derive(Default)]
+pub struct HttpInfo {
+    pub transactions:   Vec<Transaction>,
+    /// requests still waiting for their response, per connection
+    pending:            HashMap<ConnKey, VecDeque<usize>>,
+}
+
+impl HttpInfo {
+
+    /// handle a message of a TCP direction, the head comes whole from
+    /// the framing
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        match parse(payload) {
+            Some(HttpMessage::Request { method, uri, headers }) => {
+                let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+                let mut t = Transaction::new(flow.sip, flow.dip, flow.dport);
+                t.host = header(&headers, "Host")
+                    .unwrap_or("").to_string();
+                t.user_agent = header(&headers, "User-Agent")
+                    .unwrap_or("").to_string();
+                t.method = method;
+                t.uri = uri;
+                self.transactions.push(t);
+                self.pending.entry(key).or_default()
+                    .push_back(self.transactions.len() - 1);
+            },
+            Some(HttpMessage::Response { status, headers }) => {
+
+                // a response travels from the server back to the client
+                let key = (flow.dip, flow.dport, flow.sip, flow.sport);
+
+                // 1xx responses are followed by the real one
+                let informational = status < 200;
+                let idx = match self.pending.get_mut(&key) {
+                    Some(queue) if informational => queue.front().copied(),
+                    Some(queue) => queue.pop_front(),
+                    None        => None,
+                };
+
+                let idx = match idx {
+                    Some(idx) => idx,
+                    None => {
+                        // we missed the request
+                        self.transactions.push(Transaction::new(
+                            flow.dip, flow.sip, flow.sport));
+                        self.transactions.len() - 1
+                    },
+                };
+
+                let t = &mut self.transactions[idx];
+                t.status = Some(status);
+                t.content_type = header(&headers, "Content-Type")
+                    .unwrap_or("").to_string();
+                t.server_header = header(&headers, "Server")
+                    .unwrap_or("").to_string();
+            },
+            None => (),
+        }
+    }
+
+    /// a direction ended. once the server is done no more responses
+    /// come, forget the requests still waiting for one
+    pub fn end(&mut self, flow: &Flow) {
+        self.pending.remove(&(flow.dip, flow.dport, flow.sip, flow.sport));
+    }
+
+    /// write all transactions as csv
+    pub fn csv(&self, file: &mut File) -> Result<(), Error> {
+
+        write!(file, "client,server,port,method,host,uri,user_agent,\
+                      status,content_type,server_header\n")?;
+
+        for t in &self.transactions {
+            let status = t.status.map(|s| s.to_string())
+                .unwrap_or_default();
+            write!(file, "{},{},{},{},{},{},{},{},{},{}\n",
+                t.client, t.server, t.port,
+                util::csv_field(&t.method),
+                util::csv_field(&t.host),
+                util::csv_field(&t.uri),
+                util::csv_field(&t.user_agent),
+                status,
+                util::csv_field(&t.content_type),
+                util::csv_field(&t.server_header))?;
+        }
+
+        Ok(())
+    }
+
+    /// write the HTTP section of the report, grouped by host
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.transactions.is_empty() {
+            return Ok(());
+        }
+
+        // host -> (method uri status content-type) -> count
+        let mut requests: BTreeMap<String, BTreeMap<String, u64>> =
+            BTreeMap::new();
+        let mut servers: BTreeMap<String, BTreeSet<String>> =
+            BTreeMap::new();
+        let mut agents: BTreeMap<String, BTreeSet<String>> =
+            BTreeMap::new();
+        let mut clients: BTreeMap<String, BTreeSet<Ipv4Addr>> =
+            BTreeMap::new();
+
+        for t in &self.transactions {
+            let host = t.host_key();
+
+            let status = t.status.map(|s| s.to_string())
+                .unwrap_or("-".to_string());
+            let line = format!("{:<7} {} -> {} {}",
+                if t.method.is_empty() { "?" } else { &t.method },
+                t.uri, status, t.content_type);
+            *requests.entry(host.clone()).or_default()
+                .entry(line).or_insert(0) += 1;
+
+            clients.entry(host.clone()).or_default().insert(t.client);
+            if !t.server_header.is_empty() {
+                servers.entry(host.clone()).or_default()
+                    .insert(t.server_header.clone());
+            }
+            if !t.user_agent.is_empty() {
+                agents.entry(host).or_default()
+                    .insert(t.user_agent.clone());
+            }
+        }
+
+        write!(file, "\n\n-- HTTP per Host\n")?;
+
+        for (host, lines) in &requests {
+            write!(file, "{}\n", host)?;
+
+            let c = clients[host].iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(file, "    clients:    {}\n", c)?;
+
+            if let Some(servers) = servers.get(host) {
+                for s in servers {
+                    write!(file, "    server:     {}\n", s)?;
+                }
+            }
+            if let Some(agents) = agents.get(host) {
+                for a in agents {
+                    write!(file, "    user-agent: {}\n", a)?;
+                }
+            }
+            for (line, count) in lines {
+                write!(file, "    {} ({}x)\n", line, count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,361 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! identify the application protocol of a flow from its first payload
+//! bytes, whatever port it runs on. each direction gets one look, the
+//! first signature that matches names the protocol and tells whether
+//! the sender is the server.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::dissect::{Flow, be16, le16, http, sip, snmp};
+
+/// label of flows no signature matched
+const UNKNOWN: &str = "unknown";
+
+/// transport protocols
+pub const TCP: &str = "tcp";
+pub const UDP: &str = "udp";
+
+/// the protocol usually found on a server port, named like the
+/// signatures name them
+pub fn port_protocol(port: u16, transport: &str) -> Option<&'static str> {
+    let tcp = transport == TCP;
+    Some(match port {
+        21 if tcp               => "FTP",
+        22 if tcp               => "SSH",
+        23 if tcp               => "Telnet",
+        25 | 587 if tcp         => "SMTP",
+        53                      => "DNS",
+        67 | 68 if !tcp         => "DHCP",
+        69 if !tcp              => "TFTP",
+        80 | 8000 | 8080 if tcp => "HTTP",
+        102 if tcp              => "ISO-TSAP",
+        110 if tcp              => "POP3",
+        123 if !tcp             => "NTP",
+        139 | 445 if tcp        => "SMB",
+        143 if tcp              => "IMAP",
+        161 | 162 if !tcp       => "SNMP",
+        443 | 465 | 636 | 853 | 993 | 995 | 8443 | 8883 if tcp => "TLS",
+        502 if tcp              => "Modbus/TCP",
+        1883 if tcp             => "MQTT",
+        1900 if !tcp            => "SSDP",
+        2404 if tcp             => "IEC 104",
+        3389 if tcp             => "RDP",
+        4840 if tcp             => "OPC UA",
+        5060                    => "SIP",
+        20000                   => "DNP3",
+        44818                   => "EtherNet/IP",
+        47808 if !tcp           => "BACnet/IP",
+        _                       => return None,
+    })
+}
+
+/// match a TCP payload, returns the protocol and whether the sender is
+/// the server
+fn identify_tcp(p: &[u8]) -> Option<(&'static str, bool)> {
+
+    let len = p.len();
+    let starts = |s: &[u8]| p.starts_with(s);
+
+    // TLS handshake record, ClientHello or ServerHello
+    if len >= 6 && p[0] == 0x16 && p[1] == 3 && p[2] <= 4 {
+        return Some(("TLS", p[5] == 2));
+    }
+    if starts(b"SSH-") {
+        // servers usually send their banner first
+        return Some(("SSH", true));
+    }
+    if let Some(msg) = sip::parse(p) {
+        return Some(("SIP", msg.method.is_none()));
+    }
+    match http::parse(p) {
+        Some(http::HttpMessage::Request { .. }) => return Some(("HTTP", false)),
+        Some(http::HttpMessage::Response { .. }) => return Some(("HTTP", true)),
+        None => (),
+    }
+    if starts(b"RTSP/1.0") {
+        return Some(("RTSP", true));
+    }
+
+    // NetBIOS session message carrying SMB1 or SMB2
+    if len >= 8 && p[0] == 0 && &p[5..8] == b"SMB" && (p[4] == 0xff || p[4] == 0xfe) {
+        return Some(("SMB", false));
+    }
+
+    // TPKT with a COTP connection request, RDP adds a cookie
+    if len >= 11 && p[0] == 3 && p[1] == 0 && be16(p, 2) == Some(len as u16) && p[5] == 0xe0 {
+        let rdp = p.windows(9).any(|w| w == b"mstshash=");
+        return Some((if rdp { "RDP" } else { "ISO-TSAP" }, false));
+    }
+
+    // OPC UA Hello and Acknowledge
+    if len >= 8 && starts(b"HELF") {
+        return Some(("OPC UA", false));
+    }
+    if len >= 8 && starts(b"ACKF") {
+        return Some(("OPC UA", true));
+    }
+
+    // MQTT CONNECT has the protocol name after the fixed header
+    if len >= 8 && p[0] == 0x10 && p.windows(4).take(6).any(|w| w == b"MQTT" || w == b"MQIs") {
+        return Some(("MQTT", false));
+    }
+
+    // the greetings of line based servers
+    if starts(b"220") && len > 4 {
+        let banner = String::from_utf8_lossy(p).to_uppercase();
+        let protocol = if banner.contains("FTP") { "FTP" } else { "SMTP" };
+        return Some((protocol, true));
+    }
+    if starts(b"+OK") {
+        return Some(("POP3", true));
+    }
+    if starts(b"* OK") || starts(b"* PREAUTH") {
+        return Some(("IMAP", true));
+    }
+
+    // telnet starts with option negotiation
+    if len >= 3 && p[0] == 0xff && (0xfb..=0xfe).contains(&p[1]) {
+        return Some(("Telnet", true));
+    }
+
+    // Modbus/TCP, protocol id 0 and a length covering the rest
+    if len >= 8 && be16(p, 2) == Some(0) && be16(p, 4) == Some(len as u16 - 6)
+        && matches!(p[7] & 0x7f, 1..=8 | 11 | 12 | 15..=17 | 20..=24 | 43) {
+        return Some(("Modbus/TCP", false));
+    }
+
+    // DNP3 link layer start bytes
+    if len >= 10 && p[0] == 0x05 && p[1] == 0x64 {
+        return Some(("DNP3", false));
+    }
+
+    // IEC 104 APCI with a length covering the rest
+    if len >= 6 && p[0] == 0x68 && p[1] as usize == len - 2 {
+        return Some(("IEC 104", false));
+    }
+
+    // EtherNet/IP encapsulation with a known command
+    if len >= 24 && le16(p, 2) == Some(len as u16 - 24)
+        && matches!(le16(p, 0), Some(0x0004 | 0x0063 | 0x0064 | 0x0065 | 0x0066 | 0x006f | 0x0070)) {
+        return Some(("EtherNet/IP", false));
+    }
+
+    // DNS over TCP, length prefix and a single question
+    if len >= 14 && be16(p, 0) == Some(len as u16 - 2) && be16(p, 6) == Some(1) {
+        return Some(("DNS", false));
+    }
+
+    None
+}
+
+/// match a UDP payload, returns the protocol and whether the sender is
+/// the server
+fn identify_udp(p: &[u8]) -> Option<(&'static str, bool)> {
+
+    let len = p.len();
+    let starts = |s: &[u8]| p.starts_with(s);
+
+    if let Some(msg) = sip::parse(p) {
+        return Some(("SIP", msg.method.is_none()));
+    }
+    if starts(b"M-SEARCH * HTTP/1.1") || starts(b"NOTIFY * HTTP/1.1") {
+        return Some(("SSDP", false));
+    }
+    if let Some(message) = snmp::parse(p) {
+        return Some(("SNMP", message.pdu == Some(0xa2)));
+    }
+
+    // DHCP has a magic cookie behind the BOOTP header
+    if len >= 240 && p[236..240] == [0x63, 0x82, 0x53, 0x63] {
+        return Some(("DHCP", p[0] == 2));
+    }
+
+    // DTLS handshake
+    if len >= 13 && p[0] == 0x16 && p[1] == 0xfe {
+        return Some(("DTLS", false));
+    }
+
+    // BACnet virtual link control with the length of the datagram
+    if len >= 4 && p[0] == 0x81 && be16(p, 2) == Some(len as u16) {
+        return Some(("BACnet/IP", false));
+    }
+
+    // NTP versions 3 and 4, modes 1 to 5
+    let version = (p.first().copied().unwrap_or(0) >> 3) & 0x07;
+    let mode = p.first().copied().unwrap_or(0) & 0x07;
+    if len == 48 && (3..=4).contains(&version) && (1..=5).contains(&mode) {
+        return Some(("NTP", mode == 4 || mode == 5));
+    }
+
+    // TFTP read and write requests, file name and mode
+    if len >= 4 && p[0] == 0 && (p[1] == 1 || p[1] == 2) && p.ends_with(b"\0") {
+        let text = String::from_utf8_lossy(&p[2..]).to_lowercase();
+        if text.contains("\0octet\0") || text.contains("\0netascii\0") {
+            return Some(("TFTP", false));
+        }
+    }
+
+    // DNS header with a single question and a sane first label
+    if len >= 17 && be16(p, 4) == Some(1) && p[2] & 0x78 == 0 && p[12] > 0 && p[12] < 64 {
+        return Some(("DNS", p[2] & 0x80 != 0));
+    }
+
+    None
+}
+
+/// a flow, once it was identified or had its chances
+struct Connection {
+    /// payloads looked at
+    inspected:  u8,
+    client:     Ipv4Addr,
+    server:     (Ipv4Addr, u16),
+    protocol:   Option<&'static str>,
+}
+
+/// a service as seen through all flows to it
+#[derive(Default)]
+pub struct Service {
+    pub clients:        BTreeSet<Ipv4Addr>,
+    pub connections:    u64,
+}
+
+/// what we learned about the application protocols in the capture
+#[derive(Default)]
+pub struct IdentInfo {
+    /// keyed by the lower and the higher endpoint and the transport
+    connections: HashMap<(Ipv4Addr, u16, Ipv4Addr, u16, &'static str), Connection>,
+}
+
+impl IdentInfo {
+
+    /// handle a TCP payload
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+        self.inspect(flow, TCP, payload, identify_tcp);
+    }
+
+    /// handle a UDP payload
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+        self.inspect(flow, UDP, payload, identify_udp);
+    }
+
+    fn inspect(&mut self, flow: &Flow, transport: &'static str, payload: &[u8],
+        identify: fn(&[u8]) -> Option<(&'static str, bool)>) {
+
+        if payload.is_empty() {
+            return;
+        }
+
+        let a = (flow.sip, flow.sport);
+        let b = (flow.dip, flow.dport);
+        let key = if a <= b {
+            (a.0, a.1, b.0, b.1, transport)
+        } else {
+            (b.0, b.1, a.0, a.1, transport)
+        };
+
+        // until we know better the side with the lower port serves
+        let conn = self.connections.entry(key).or_insert_with(|| {
+            let (client, server) = if flow.sport < flow.dport { (b, a) } else { (a, b) };
+            Connection { inspected: 0, client: client.0, server, protocol: None }
+        });
+
+        // one look per direction
+        if conn.protocol.is_some() || conn.inspected >= 2 {
+            return;
+        }
+        conn.inspected += 1;
+
+        if let Some((protocol, sender_is_server)) = identify(payload) {
+            let (client, server) = if sender_is_server { (b, a) } else { (a, b) };
+            conn.client = client.0;
+            conn.server = server;
+            conn.protocol = Some(protocol);
+        }
+    }
+
+    /// services keyed by server, port, transport and protocol
+    pub fn services(&self) -> BTreeMap<(Ipv4Addr, u16, &'static str, &'static str), Service> {
+
+        let mut services: BTreeMap<_, Service> = BTreeMap::new();
+
+        for ((.., transport), conn) in &self.connections {
+            let protocol = conn.protocol.unwrap_or(UNKNOWN);
+            let key = (conn.server.0, conn.server.1, *transport, protocol);
+            let service = services.entry(key).or_default();
+            service.clients.insert(conn.client);
+            service.connections += 1;
+        }
+
+        services
+    }
+
+    /// the protocols identified between two hosts, in both directions
+    pub fn edge_labels(&self) -> Vec<((Ipv4Addr, Ipv4Addr), String)> {
+        self.connections.values()
+            .filter_map(|c| c.protocol.map(|p| (c.client, c.server.0, p)))
+            .flat_map(|(client, server, p)| [
+                ((client, server), p.to_string()),
+                ((server, client), p.to_string()),
+            ])
+            .collect()
+    }
+
+    /// write the protocol identification section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        let services = self.services();
+        if services.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- Application Protocols by Content (server)\n")?;
+
+        for ((server, port, transport, protocol), s) in &services {
+
+            // like the port list, unknown traffic between high ports is
+            // left out
+            if *protocol == UNKNOWN && *port >= 32768 {
+                continue;
+            }
+
+            let flag = match (port_protocol(*port, transport), *protocol) {
+                (_, UNKNOWN) => String::new(),
+                (Some(expected), p) if expected != p => {
+                    format!("  [PORT MISMATCH, expected {}]", expected)
+                },
+                (None, _) => "  [NON-STANDARD PORT]".to_string(),
+                _ => String::new(),
+            };
+
+            let service = format!("{}:{}/{}", server, port, transport);
+            write!(file, "{:<27} {:<12} {} flows from {} clients{}\n",
+                service, protocol, s.connections, s.clients.len(), flag)?;
+        }
+
+        Ok(())
+    }
+
+}
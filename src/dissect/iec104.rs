@@ -0,0 +1,400 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, le16};
+
+/// IEC 60870-5-104 port
+pub const IEC104_PORT: u16 = 2404;
+
+/// start byte of an APCI
+const START: u8 = 0x68;
+
+/// size of the APCI including start byte and length
+const APCI_LEN: usize = 6;
+
+/// information object address size
+const IOA_LEN: usize = 3;
+
+/// negative confirmation bit of the cause of transmission
+const COT_NEGATIVE: u8 = 0x40;
+
+/// the kind of an APCI frame
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Frame {
+    /// numbered information transfer, carries an ASDU
+    I,
+    /// numbered supervisory, acknowledges I frames
+    S,
+    /// unnumbered control function
+    U(u8),
+}
+
+impl Frame {
+
+    fn name(&self) -> String {
+        match self {
+            Frame::I => "I (information)".to_string(),
+            Frame::S => "S (supervisory)".to_string(),
+            Frame::U(f) => format!("U {}", match f {
+                0x07 => "STARTDT act",
+                0x0b => "STARTDT con",
+                0x13 => "STOPDT act",
+                0x23 => "STOPDT con",
+                0x43 => "TESTFR act",
+                0x83 => "TESTFR con",
+                _    => "unknown",
+            }),
+        }
+    }
+
+}
+
+/// get the name of an ASDU type id
+pub fn type_name(type_id: u8) -> String {
+    let name = match type_id {
+        1   => "M_SP_NA_1 single-point",
+        3   => "M_DP_NA_1 double-point",
+        5   => "M_ST_NA_1 step position",
+        7   => "M_BO_NA_1 bitstring",
+        9   => "M_ME_NA_1 normalized value",
+        11  => "M_ME_NB_1 scaled value",
+        13  => "M_ME_NC_1 short float",
+        15  => "M_IT_NA_1 integrated totals",
+        20  => "M_PS_NA_1 packed single-point",
+        21  => "M_ME_ND_1 normalized value",
+        30  => "M_SP_TB_1 single-point with time",
+        31  => "M_DP_TB_1 double-point with time",
+        32  => "M_ST_TB_1 step position with time",
+        33  => "M_BO_TB_1 bitstring with time",
+        34  => "M_ME_TD_1 normalized value with time",
+        35  => "M_ME_TE_1 scaled value with time",
+        36  => "M_ME_TF_1 short float with time",
+        37  => "M_IT_TB_1 integrated totals with time",
+        38  => "M_EP_TD_1 protection event",
+        45  => "C_SC_NA_1 single command",
+        46  => "C_DC_NA_1 double command",
+        47  => "C_RC_NA_1 regulating step command",
+        48  => "C_SE_NA_1 setpoint normalized",
+        49  => "C_SE_NB_1 setpoint scaled",
+        50  => "C_SE_NC_1 setpoint short float",
+        51  => "C_BO_NA_1 bitstring command",
+        58  => "C_SC_TA_1 single command with time",
+        59  => "C_DC_TA_1 double command with time",
+        60  => "C_RC_TA_1 regulating step command with time",
+        61  => "C_SE_TA_1 setpoint normalized with time",
+        62  => "C_SE_TB_1 setpoint scaled with time",
+        63  => "C_SE_TC_1 setpoint short float with time",
+        64  => "C_BO_TA_1 bitstring command with time",
+        70  => "M_EI_NA_1 end of initialization",
+        100 => "C_IC_NA_1 interrogation",
+        101 => "C_CI_NA_1 counter interrogation",
+        102 => "C_RD_NA_1 read",
+        103 => "C_CS_NA_1 clock synchronization",
+        104 => "C_TS_NA_1 test",
+        105 => "C_RP_NA_1 reset process",
+        106 => "C_CD_NA_1 delay acquisition",
+        107 => "C_TS_TA_1 test with time",
+        110 => "P_ME_NA_1 parameter normalized",
+        111 => "P_ME_NB_1 parameter scaled",
+        112 => "P_ME_NC_1 parameter short float",
+        113 => "P_AC_NA_1 parameter activation",
+        _   => return format!("type {}", type_id),
+    };
+    name.to_string()
+}
+
+/// get the name of a cause of transmission
+pub fn cot_name(cot: u8) -> String {
+    let name = match cot {
+        1  => "per/cyc",
+        2  => "back",
+        3  => "spont",
+        4  => "init",
+        5  => "req",
+        6  => "act",
+        7  => "actcon",
+        8  => "deact",
+        9  => "deactcon",
+        10 => "actterm",
+        11 => "retrem",
+        12 => "retloc",
+        13 => "file",
+        20 => "inrogen",
+        21..=36 => return format!("inro{}", cot - 20),
+        37 => "reqcogen",
+        38..=41 => return format!("reqco{}", cot - 37),
+        44 => "unknown type",
+        45 => "unknown cause",
+        46 => "unknown common address",
+        47 => "unknown IOA",
+        _  => return format!("cot {}", cot),
+    };
+    name.to_string()
+}
+
+/// true for type ids sent in control direction: commands, system
+/// commands and parameters
+pub fn is_command(type_id: u8) -> bool {
+    matches!(type_id, 45..=69 | 100..=113)
+}
+
+/// size of an information element without its address, `None` for the
+/// types we don't walk
+fn element_size(type_id: u8) -> Option<usize> {
+    let size = match type_id {
+        1 | 3 | 45 | 46 | 47 | 70 | 100 | 101 | 105 | 113 => 1,
+        5 | 21 | 104 | 106 => 2,
+        9 | 11 | 48 | 49 | 110 | 111 => 3,
+        51 => 4,
+        7 | 13 | 15 | 20 | 50 | 112 => 5,
+        103 => 7,
+        30 | 31 | 58 | 59 | 60 => 8,
+        32 | 107 => 9,
+        34 | 35 | 38 | 61 | 62 => 10,
+        64 => 11,
+        33 | 36 | 37 | 63 => 12,
+        102 => 0,
+        _ => return None,
+    };
+    Some(size)
+}
+
+/// a decoded ASDU
+pub struct Asdu {
+    pub type_id:        u8,
+    pub cot:            u8,
+    pub negative:       bool,
+    pub common_addr:    u16,
+    pub ioas:           Vec<u32>,
+}
+
+/// read a three byte information object address
+fn ioa(buf: &[u8], pos: usize) -> Option<u32> {
+    let b = buf.get(pos..pos+IOA_LEN)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+/// parse an ASDU with the default field sizes of IEC-104: two bytes
+/// cause of transmission, two bytes common address, three bytes IOA
+pub fn parse_asdu(buf: &[u8]) -> Option<Asdu> {
+
+    let type_id = *buf.first()?;
+    let vsq = *buf.get(1)?;
+    let cot = *buf.get(2)?;
+
+    let mut asdu = Asdu {
+        type_id,
+        cot:            cot & 0x3f,
+        negative:       cot & COT_NEGATIVE != 0,
+        common_addr:    le16(buf, 4)?,
+        ioas:           Vec::new(),
+    };
+
+    let count = (vsq & 0x7f) as usize;
+    let sequence = vsq & 0x80 != 0;
+    let mut pos = 6;
+
+    // a sequence shares one address for consecutive elements
+    if sequence {
+        if let Some(first) = ioa(buf, pos) {
+            asdu.ioas.extend((0..count as u32).map(|i| first + i));
+        }
+        return Some(asdu);
+    }
+
+    for _ in 0..count {
+        let Some(addr) = ioa(buf, pos) else {
+            break;
+        };
+        asdu.ioas.push(addr);
+        let Some(size) = element_size(type_id) else {
+            break;
+        };
+        pos += IOA_LEN + size;
+    }
+
+    Some(asdu)
+}
+
+/// merge consecutive addresses and format them as `100-104, 200`
+fn ioa_string(ioas: &BTreeSet<u32>) -> String {
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for ioa in ioas {
+        match ranges.last_mut() {
+            Some(last) if *ioa == last.1 + 1 => last.1 = *ioa,
+            _ => ranges.push((*ioa, *ioa)),
+        }
+    }
+
+    ranges.iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// everything we saw between a controlling and a controlled station
+#[derive(Default)]
+pub struct Link {
+    pub frames:         BTreeMap<Frame, u64>,
+    pub common_addrs:   BTreeSet<u16>,
+    /// commands by type id and cause of transmission
+    pub commands:       BTreeMap<(u8, u8), u64>,
+    /// addresses the commands of a type id touched
+    pub command_ioas:   BTreeMap<u8, BTreeSet<u32>>,
+    /// monitored data sent by the controlled station, by type id
+    pub monitor:        BTreeMap<u8, BTreeSet<u32>>,
+    /// negative confirmations of commands
+    pub negative:       BTreeMap<u8, u64>,
+}
+
+/// what we learned from the IEC-104 traffic in the capture
+#[derive(Default)]
+pub struct Iec104Info {
+    /// keyed by controlling station (the client) and controlled station
+    pub links:  BTreeMap<Ipv4Addr, BTreeMap<Ipv4Addr, Link>>,
+}
+
+impl Iec104Info {
+
+    /// handle whole APDUs of a TCP direction, there may be several
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let control = flow.dport == IEC104_PORT;
+        let (master, outstation) = if control {
+            (flow.sip, flow.dip)
+        } else {
+            (flow.dip, flow.sip)
+        };
+
+        let link = self.links.entry(master).or_default()
+            .entry(outstation).or_default();
+
+        let mut pos = 0;
+
+        while payload.len() >= pos + APCI_LEN && payload[pos] == START {
+
+            let len = payload[pos+1] as usize;
+            let Some(apdu) = payload.get(pos+2..pos+2+len) else {
+                break;
+            };
+            if len < 4 {
+                break;
+            }
+
+            let cf1 = apdu[0];
+            let frame = if cf1 & 0x01 == 0 {
+                Frame::I
+            } else if cf1 & 0x03 == 0x01 {
+                Frame::S
+            } else {
+                Frame::U(cf1)
+            };
+            *link.frames.entry(frame).or_insert(0) += 1;
+
+            if frame == Frame::I {
+                if let Some(asdu) = parse_asdu(&apdu[4..]) {
+                    Self::asdu(link, control, asdu);
+                }
+            }
+
+            pos += 2 + len;
+        }
+    }
+
+    fn asdu(link: &mut Link, control: bool, asdu: Asdu) {
+
+        link.common_addrs.insert(asdu.common_addr);
+
+        if is_command(asdu.type_id) {
+            if control {
+                *link.commands.entry((asdu.type_id, asdu.cot)).or_insert(0) += 1;
+                link.command_ioas.entry(asdu.type_id).or_default()
+                    .extend(asdu.ioas);
+            } else if asdu.negative {
+                *link.negative.entry(asdu.type_id).or_insert(0) += 1;
+            }
+        } else if !control {
+            link.monitor.entry(asdu.type_id).or_default().extend(asdu.ioas);
+        }
+    }
+
+    /// write the IEC-104 section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.links.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- IEC 60870-5-104 (controlling -> controlled station)\n")?;
+
+        for (master, outstations) in &self.links {
+
+            write!(file, "{}\n", master)?;
+
+            for (outstation, link) in outstations {
+
+                let addrs = link.common_addrs.iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(file, "    -> {}  common address {}\n",
+                    outstation, addrs)?;
+
+                for (frame, count) in &link.frames {
+                    write!(file, "        frame:   {} ({}x)\n",
+                        frame.name(), count)?;
+                }
+                for (type_id, ioas) in &link.command_ioas {
+                    let causes = link.commands.range((*type_id, 0)..=(*type_id, 0xff))
+                        .map(|((_, cot), count)| format!("{} {}x", cot_name(*cot), count))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(file, "        command: {} ({})\n",
+                        type_name(*type_id), causes)?;
+                    if !ioas.is_empty() {
+                        write!(file, "            ioa: {}\n", ioa_string(ioas))?;
+                    }
+                }
+                for (type_id, count) in &link.negative {
+                    write!(file, "        negative confirmation: {} ({}x)\n",
+                        type_name(*type_id), count)?;
+                }
+                for (type_id, ioas) in &link.monitor {
+                    write!(file, "        monitor: {} ({} objects)\n",
+                        type_name(*type_id), ioas.len())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
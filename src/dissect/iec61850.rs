@@ -0,0 +1,365 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! IEC 61850 GOOSE and Sampled Values, both are BER encoded PDUs sent
+//! straight on ethernet to multicast groups.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::pinfo::MacAddr;
+use crate::dissect::{Frame, ber, be16};
+
+/// ethertypes of GOOSE and Sampled Values
+pub const ETHERTYPE_GOOSE: u16 = 0x88b8;
+pub const ETHERTYPE_SV:    u16 = 0x88ba;
+
+/// APPID, length and two reserved fields
+const HEADER_LEN: usize = 8;
+
+/// tags of the PDUs
+const TAG_GOOSE_PDU:    u8 = 0x61;
+const TAG_SAV_PDU:      u8 = 0x60;
+const TAG_SEQ_ASDU:     u8 = 0xa2;
+
+/// examples of anomalies kept per stream
+const MAX_EXAMPLES: usize = 5;
+
+/// the fields of a GOOSE PDU we track
+#[derive(Default)]
+pub struct GoosePdu {
+    pub gocb_ref:   String,
+    pub tal:        u64,
+    pub dat_set:    String,
+    pub go_id:      String,
+    pub st_num:     u64,
+    pub sq_num:     u64,
+    pub test:       bool,
+    pub conf_rev:   u64,
+}
+
+/// parse a GOOSE PDU
+pub fn parse_goose(buf: &[u8]) -> Option<GoosePdu> {
+
+    let (pdu, _) = ber::read(buf)?;
+    if pdu.tag != TAG_GOOSE_PDU {
+        return None;
+    }
+
+    let mut goose = GoosePdu::default();
+    for field in pdu.children() {
+        match field.tag {
+            0x80 => goose.gocb_ref = field.string(),
+            0x81 => goose.tal = field.uint()?,
+            0x82 => goose.dat_set = field.string(),
+            0x83 => goose.go_id = field.string(),
+            0x85 => goose.st_num = field.uint()?,
+            0x86 => goose.sq_num = field.uint()?,
+            0x87 => goose.test = field.value.first().is_some_and(|b| *b != 0),
+            0x88 => goose.conf_rev = field.uint()?,
+            _ => (),
+        }
+    }
+
+    Some(goose)
+}
+
+/// the fields of a Sampled Values ASDU we track
+#[derive(Default)]
+pub struct SvAsdu {
+    pub sv_id:      String,
+    pub smp_cnt:    u64,
+    pub conf_rev:   u64,
+    pub smp_synch:  u64,
+}
+
+/// parse a Sampled Values PDU, returns its ASDUs
+pub fn parse_sv(buf: &[u8]) -> Option<Vec<SvAsdu>> {
+
+    let (pdu, _) = ber::read(buf)?;
+    if pdu.tag != TAG_SAV_PDU {
+        return None;
+    }
+
+    let seq = pdu.children().into_iter().find(|t| t.tag == TAG_SEQ_ASDU)?;
+
+    let mut asdus = Vec::new();
+    for asdu in seq.children() {
+        let mut sv = SvAsdu::default();
+        for field in asdu.children() {
+            match field.tag {
+                0x80 => sv.sv_id = field.string(),
+                0x82 => sv.smp_cnt = field.uint()?,
+                0x83 => sv.conf_rev = field.uint()?,
+                0x85 => sv.smp_synch = field.uint()?,
+                _ => (),
+            }
+        }
+        asdus.push(sv);
+    }
+
+    Some(asdus)
+}
+
+/// the state of a GOOSE control block as seen on the wire
+pub struct GooseStream {
+    pub appid:      u16,
+    pub dat_set:    String,
+    pub go_id:      String,
+    pub tal:        u64,
+    pub conf_rev:   u64,
+    pub test:       bool,
+    /// multicast groups the publisher sends to
+    pub groups:     BTreeSet<MacAddr>,
+    pub frames:     u64,
+    /// state changes, stNum increments
+    pub changes:    u64,
+    st_num:         u64,
+    sq_num:         u64,
+    last_time:      f64,
+    pub anomalies:  BTreeMap<&'static str, u64>,
+    pub examples:   Vec<String>,
+}
+
+impl GooseStream {
+
+    fn anomaly(&mut self, kind: &'static str, detail: String) {
+        *self.anomalies.entry(kind).or_insert(0) += 1;
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(detail);
+        }
+    }
+
+    /// check the counters of the next frame against the last one. a
+    /// publisher increments stNum and resets sqNum on every state change,
+    /// and increments sqNum on every retransmission in between
+    fn update(&mut self, time: f64, goose: &GoosePdu) {
+
+        let (st, sq) = (goose.st_num, goose.sq_num);
+        let (last_st, last_sq) = (self.st_num, self.sq_num);
+        let at = format!("at {:.6}: stNum {} sqNum {} after stNum {} sqNum {}",
+            time, st, sq, last_st, last_sq);
+
+        if st < last_st {
+            self.anomaly("stNum went back (replay?)", at);
+        } else if st > last_st {
+            self.changes += 1;
+            if st > last_st + 1 {
+                self.anomaly("stNum skipped (injection?)", at);
+            } else if sq > 1 {
+                self.anomaly("new stNum without sqNum reset", at);
+            }
+        } else if sq == 0 && last_sq != 0 {
+            self.anomaly("sqNum reset without stNum change", at);
+        } else if sq <= last_sq {
+            self.anomaly("sqNum repeated or went back (replay?)", at);
+        } else if sq > last_sq + 1 {
+            self.anomaly("sqNum gap (frames lost?)", at);
+        }
+
+        // TAL is in milliseconds, the next frame is due before it ends
+        let gap = (time - self.last_time) * 1000.0;
+        if self.tal > 0 && gap > self.tal as f64 {
+            self.anomaly("frame later than timeAllowedtoLive",
+                format!("at {:.6}: {:.0} ms after the last frame, TAL {} ms",
+                    time, gap, self.tal));
+        }
+
+        self.st_num = st;
+        self.sq_num = sq;
+        self.last_time = time;
+        self.tal = goose.tal;
+    }
+
+}
+
+/// a Sampled Values stream
+pub struct SvStream {
+    pub appid:      u16,
+    pub conf_rev:   u64,
+    pub smp_synch:  u64,
+    pub groups:     BTreeSet<MacAddr>,
+    pub asdus:      u64,
+    pub first:      f64,
+    pub last:       f64,
+    smp_cnt:        u64,
+    /// smpCnt not following the last one, other than a wrap to 0
+    pub jumps:      u64,
+}
+
+/// what we learned from GOOSE and Sampled Values in the capture
+#[derive(Default)]
+pub struct Iec61850Info {
+    /// GOOSE streams keyed by publisher and gocbRef
+    pub goose:  BTreeMap<(MacAddr, String), GooseStream>,
+    /// SV streams keyed by publisher and svID
+    pub sv:     BTreeMap<(MacAddr, String), SvStream>,
+}
+
+impl Iec61850Info {
+
+    /// handle the payload of a GOOSE frame
+    pub fn goose(&mut self, frame: &Frame, payload: &[u8]) {
+
+        let Some(appid) = be16(payload, 0) else {
+            return;
+        };
+        let Some(goose) = payload.get(HEADER_LEN..).and_then(parse_goose) else {
+            return;
+        };
+
+        let time = frame.time();
+        let key = (frame.smac, goose.gocb_ref.clone());
+
+        let stream = self.goose.entry(key).or_insert_with(|| GooseStream {
+            appid,
+            dat_set:    goose.dat_set.clone(),
+            go_id:      goose.go_id.clone(),
+            tal:        goose.tal,
+            conf_rev:   goose.conf_rev,
+            test:       false,
+            groups:     BTreeSet::new(),
+            frames:     0,
+            changes:    0,
+            st_num:     goose.st_num,
+            sq_num:     goose.sq_num,
+            last_time:  time,
+            anomalies:  BTreeMap::new(),
+            examples:   Vec::new(),
+        });
+
+        if stream.frames > 0 {
+            stream.update(time, &goose);
+        }
+        stream.frames += 1;
+        stream.groups.insert(frame.dmac);
+        stream.test |= goose.test;
+        stream.conf_rev = goose.conf_rev;
+    }
+
+    /// handle the payload of a Sampled Values frame
+    pub fn sv(&mut self, frame: &Frame, payload: &[u8]) {
+
+        let Some(appid) = be16(payload, 0) else {
+            return;
+        };
+        let Some(asdus) = payload.get(HEADER_LEN..).and_then(parse_sv) else {
+            return;
+        };
+
+        let time = frame.time();
+
+        for asdu in asdus {
+            let key = (frame.smac, asdu.sv_id.clone());
+            let stream = self.sv.entry(key).or_insert_with(|| SvStream {
+                appid,
+                conf_rev:   asdu.conf_rev,
+                smp_synch:  asdu.smp_synch,
+                groups:     BTreeSet::new(),
+                asdus:      0,
+                first:      time,
+                last:       time,
+                smp_cnt:    asdu.smp_cnt,
+                jumps:      0,
+            });
+
+            // smpCnt is 16 bits on the wire and wraps to 0 once per
+            // second, compare modulo 65536 so a bogus value can't overflow
+            let expected = stream.smp_cnt.wrapping_add(1) & 0xffff;
+            if stream.asdus > 0 && asdu.smp_cnt & 0xffff != expected
+                && asdu.smp_cnt != 0 {
+                stream.jumps += 1;
+            }
+            stream.smp_cnt = asdu.smp_cnt;
+            stream.asdus += 1;
+            stream.last = time;
+            stream.smp_synch = asdu.smp_synch;
+            stream.groups.insert(frame.dmac);
+        }
+    }
+
+    /// write the GOOSE and SV sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        let groups = |groups: &BTreeSet<MacAddr>| {
+            groups.iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if !self.goose.is_empty() {
+
+            write!(file, "\n\n-- IEC 61850 GOOSE Publishers\n")?;
+
+            for ((mac, gocb_ref), s) in &self.goose {
+
+                let flag = if s.anomalies.is_empty() { "" } else { "  [ANOMALY]" };
+                write!(file, "{} {}{}\n", mac, gocb_ref, flag)?;
+                write!(file, "    appid:       0x{:04x}\n", s.appid)?;
+                write!(file, "    datSet:      {}\n", s.dat_set)?;
+                write!(file, "    goID:        {}\n", s.go_id)?;
+                write!(file, "    confRev:     {}\n", s.conf_rev)?;
+                write!(file, "    TAL:         {} ms\n", s.tal)?;
+                write!(file, "    subscribers: {}\n", groups(&s.groups))?;
+                write!(file, "    frames:      {} ({} state changes)\n",
+                    s.frames, s.changes)?;
+                if s.test {
+                    write!(file, "    test flag set\n")?;
+                }
+                for (kind, count) in &s.anomalies {
+                    write!(file, "    anomaly:     {} ({}x)\n", kind, count)?;
+                }
+                for example in &s.examples {
+                    write!(file, "        {}\n", example)?;
+                }
+            }
+        }
+
+        if !self.sv.is_empty() {
+
+            write!(file, "\n\n-- IEC 61850 Sampled Values Publishers\n")?;
+
+            for ((mac, sv_id), s) in &self.sv {
+                write!(file, "{} {}\n", mac, sv_id)?;
+                write!(file, "    appid:       0x{:04x}\n", s.appid)?;
+                write!(file, "    confRev:     {}\n", s.conf_rev)?;
+                write!(file, "    smpSynch:    {}\n", s.smp_synch)?;
+                write!(file, "    subscribers: {}\n", groups(&s.groups))?;
+                let span = s.last - s.first;
+                if span > 0.0 {
+                    write!(file, "    asdus:       {} ({:.0}/s)\n",
+                        s.asdus, (s.asdus - 1) as f64 / span)?;
+                } else {
+                    write!(file, "    asdus:       {}\n", s.asdus)?;
+                }
+                if s.jumps > 0 {
+                    write!(file, "    anomaly:     smpCnt discontinuity ({}x)\n",
+                        s.jumps)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
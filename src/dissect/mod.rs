@@ -0,0 +1,420 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! application layer dissectors. `dumpreader::parse` hands every
+//! UDP payload and TCP segment to `Dissectors`, TCP segments are put in
+//! order by the reassembly first and cut into whole messages by the
+//! framing. payloads are routed to the protocol modules by port. each
+//! module keeps its own summary, which is written to the report once
+//! the capture is done.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::pinfo::MacAddr;
+use reassembly::{Segment, StreamConsumer};
+use framing::{Framer, Framing};
+
+pub mod ber;
+pub mod x509;
+
+pub mod dns;
+pub mod dhcp;
+pub mod http;
+pub mod tls;
+pub mod ssh;
+pub mod nbns;
+pub mod ssdp;
+pub mod discovery;
+pub mod modbus;
+pub mod dnp3;
+pub mod s7comm;
+pub mod enip;
+pub mod bacnet;
+pub mod iec104;
+pub mod opcua;
+pub mod profinet;
+pub mod iec61850;
+pub mod ethercat;
+pub mod ptp;
+pub mod mqtt;
+pub mod coap;
+pub mod snmp;
+pub mod ntp;
+pub mod sip;
+pub mod creds;
+pub mod ident;
+pub mod files;
+pub mod reassembly;
+pub mod framing;
+
+/// addressing information and capture time of a frame that doesn't
+/// carry IPv4
+pub struct Frame {
+    pub smac:   MacAddr,
+    pub dmac:   MacAddr,
+    /// capture time in seconds since the epoch
+    pub ts:     i64,
+    /// microseconds of the capture time
+    pub usec:   i64,
+}
+
+impl Frame {
+
+    /// capture time in seconds with fractions
+    pub fn time(&self) -> f64 {
+        self.ts as f64 + self.usec as f64 / 1e6
+    }
+
+}
+
+/// addressing information of the packet a payload was taken from
+#[derive(Clone, Copy)]
+pub struct Flow {
+    pub smac:   MacAddr,
+    pub dmac:   MacAddr,
+    pub sip:    Ipv4Addr,
+    pub dip:    Ipv4Addr,
+    pub sport:  u16,
+    pub dport:  u16,
+    /// capture time in seconds since the epoch
+    pub ts:     i64,
+    /// microseconds of the capture time
+    pub usec:   i64,
+}
+
+impl Flow {
+
+    /// capture time in seconds with fractions
+    pub fn time(&self) -> f64 {
+        self.ts as f64 + self.usec as f64 / 1e6
+    }
+
+    /// true if either side of the flow uses `port`
+    pub fn has_port(&self, port: u16) -> bool {
+        self.sport == port || self.dport == port
+    }
+
+}
+
+/// holds the state of all dissectors
+#[derive(Default)]
+pub struct Dissectors {
+    pub dns: dns::DnsInfo,
+    pub dhcp: dhcp::DhcpInfo,
+    pub http: http::HttpInfo,
+    pub tls: tls::TlsInfo,
+    pub ssh: ssh::SshInfo,
+    pub discovery: discovery::DiscoveryInfo,
+    pub modbus: modbus::ModbusInfo,
+    pub dnp3: dnp3::Dnp3Info,
+    pub s7comm: s7comm::S7Info,
+    pub enip: enip::EnipInfo,
+    pub bacnet: bacnet::BacnetInfo,
+    pub iec104: iec104::Iec104Info,
+    pub opcua: opcua::OpcUaInfo,
+    pub profinet: profinet::ProfinetInfo,
+    pub iec61850: iec61850::Iec61850Info,
+    pub ethercat: ethercat::EthercatInfo,
+    pub ptp: ptp::PtpInfo,
+    pub mqtt: mqtt::MqttInfo,
+    pub coap: coap::CoapInfo,
+    pub snmp: snmp::SnmpInfo,
+    pub ntp: ntp::NtpInfo,
+    pub sip: sip::SipInfo,
+    pub creds: creds::CredsInfo,
+    pub ident: ident::IdentInfo,
+    pub files: files::FilesInfo,
+    pub streams: reassembly::Reassembler,
+    framer: Framer,
+}
+
+impl Dissectors {
+
+    /// hand a UDP payload to the dissectors
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+        self.ident.udp(flow, payload);
+        if flow.has_port(dns::DNS_PORT) {
+            self.dns.udp(flow, payload);
+        }
+        if flow.has_port(dhcp::DHCP_SERVER_PORT) 
+            || flow.has_port(dhcp::DHCP_CLIENT_PORT) {
+            self.dhcp.udp(flow, payload);
+        }
+        self.discovery.udp(flow, payload);
+        if flow.has_port(dnp3::DNP3_PORT) {
+            self.dnp3.data(flow, payload);
+        }
+        if flow.has_port(enip::ENIP_PORT) {
+            self.enip.data(flow, payload);
+        }
+        if flow.dport == enip::ENIP_IO_PORT {
+            self.enip.io(flow, payload);
+        }
+        if flow.has_port(bacnet::BACNET_PORT) {
+            self.bacnet.udp(flow, payload);
+        }
+        if flow.has_port(ptp::PTP_EVENT_PORT)
+            || flow.has_port(ptp::PTP_GENERAL_PORT) {
+            self.ptp.udp(flow, payload);
+        }
+        if flow.has_port(coap::COAP_PORT) {
+            self.coap.udp(flow, payload);
+        }
+        if flow.has_port(snmp::SNMP_PORT) || flow.has_port(snmp::SNMP_TRAP_PORT) {
+            self.snmp.udp(flow, payload);
+            self.creds.snmp(flow, payload);
+        }
+        if flow.has_port(ntp::NTP_PORT) {
+            self.ntp.udp(flow, payload);
+        }
+        if flow.has_port(sip::SIP_PORT) {
+            self.sip.sip(flow, payload);
+        } else if self.sip.is_media(flow) {
+            self.sip.rtp(flow, payload);
+        }
+        if flow.dport == files::TFTP_PORT || self.files.is_tftp(flow) {
+            self.files.tftp(flow, payload);
+        }
+    }
+
+    /// hand a TCP segment to the reassembly, the dissectors get the
+    /// ordered data of each direction
+    pub fn segment(&mut self, flow: &Flow, segment: &Segment) {
+        let mut streams = std::mem::take(&mut self.streams);
+        streams.segment(flow, segment, self);
+        self.streams = streams;
+    }
+
+    /// end the TCP streams still open at the end of the capture
+    pub fn finish(&mut self) {
+        let mut streams = std::mem::take(&mut self.streams);
+        streams.finish(self);
+        self.streams = streams;
+        self.files.finish();
+    }
+
+    /// hand ordered TCP data to the dissectors. protocols with messages
+    /// get them one by one from the framing, the rest gets the data as
+    /// it is
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        if flow.has_port(dns::DNS_PORT) {
+            for message in self.framer.messages(Framing::Dns, flow, payload) {
+                self.dns.tcp(flow, &message);
+            }
+        }
+        if flow.has_port(modbus::MODBUS_PORT) {
+            for message in self.framer.messages(Framing::Mbap, flow, payload) {
+                self.modbus.tcp(flow, &message);
+            }
+        }
+        if flow.has_port(dnp3::DNP3_PORT) {
+            for message in self.framer.messages(Framing::Dnp3, flow, payload) {
+                self.dnp3.data(flow, &message);
+            }
+        }
+        if flow.has_port(s7comm::ISO_TSAP_PORT) {
+            for message in self.framer.messages(Framing::Tpkt, flow, payload) {
+                self.s7comm.tcp(flow, &message);
+            }
+        }
+        if flow.has_port(enip::ENIP_PORT) {
+            for message in self.framer.messages(Framing::Encap, flow, payload) {
+                self.enip.data(flow, &message);
+            }
+        }
+        if flow.has_port(iec104::IEC104_PORT) {
+            for message in self.framer.messages(Framing::Apci, flow, payload) {
+                self.iec104.tcp(flow, &message);
+            }
+        }
+        let mut packets = Vec::new();
+        if flow.has_port(mqtt::MQTT_PORT) {
+            packets = self.framer.messages(Framing::Mqtt, flow, payload);
+            for packet in &packets {
+                self.mqtt.tcp(flow, packet);
+            }
+        } else if flow.has_port(mqtt::MQTTS_PORT) {
+            self.mqtt.tcp(flow, payload);
+        }
+
+        // HTTP, OPC UA and the credentials run on all kinds of ports,
+        // the parsers check the start of the messages. the ports of the
+        // mail, FTP, telnet and MQTT logins don't carry HTTP
+        let creds_framing = creds::framing(flow);
+        if creds_framing == Some(Framing::Http) {
+            for head in self.framer.messages(Framing::Http, flow, payload) {
+                if flow.has_port(sip::SIP_PORT) {
+                    self.sip.sip(flow, &head);
+                }
+                self.http.tcp(flow, &head);
+                self.discovery.tcp(flow, &head);
+                self.creds.tcp(flow, &head);
+            }
+        }
+        match creds_framing {
+            Some(Framing::Http) => (),
+            Some(Framing::Mqtt) => {
+                for packet in &packets {
+                    self.creds.tcp(flow, packet);
+                }
+            },
+            Some(framing) => {
+                for message in self.framer.messages(framing, flow, payload) {
+                    self.creds.tcp(flow, &message);
+                }
+            },
+            None => self.creds.tcp(flow, payload),
+        }
+        for message in self.framer.messages(Framing::OpcUa, flow, payload) {
+            self.opcua.tcp(flow, &message);
+        }
+
+        // these follow the start of a direction themselves
+        self.tls.tcp(flow, payload);
+        self.ssh.tcp(flow, payload);
+        self.ident.tcp(flow, payload);
+        self.files.tcp(flow, payload);
+    }
+
+    /// hand the payload of a layer 2 frame to the dissectors, VLAN tags
+    /// are already removed
+    pub fn ethernet(&mut self, frame: &Frame, ethertype: u16, payload: &[u8]) {
+        match ethertype {
+            profinet::ETHERTYPE_PROFINET => self.profinet.ethernet(frame, payload),
+            iec61850::ETHERTYPE_GOOSE => self.iec61850.goose(frame, payload),
+            iec61850::ETHERTYPE_SV => self.iec61850.sv(frame, payload),
+            ethercat::ETHERTYPE_ETHERCAT => self.ethercat.ethernet(frame, payload),
+            ptp::ETHERTYPE_PTP => self.ptp.ethernet(frame, payload),
+            _ => (),
+        }
+    }
+
+    /// all IPv4 addresses we learned a name for. names from DNS answers
+    /// win over the names hosts gave themselves
+    pub fn hostnames(&self) -> HashMap<Ipv4Addr, String> {
+        let mut names = self.dhcp.hostnames();
+        names.extend(self.discovery.hostnames());
+        for (ip, name) in &self.dns.names {
+            if let IpAddr::V4(ip) = ip {
+                names.insert(*ip, name.clone());
+            }
+        }
+        names
+    }
+
+    /// labels for the edges of the graph, keyed by source and destination
+    pub fn edge_labels(&self) -> HashMap<(Ipv4Addr, Ipv4Addr), String> {
+        let mut labels: HashMap<_, BTreeSet<String>> = HashMap::new();
+        for (edge, label) in self.tls.edge_labels() {
+            labels.entry(edge).or_default().insert(label);
+        }
+        for (edge, label) in self.ident.edge_labels() {
+            labels.entry(edge).or_default().insert(label);
+        }
+        labels.into_iter()
+            .map(|(edge, l)| (edge, l.into_iter().collect::<Vec<_>>().join(" ")))
+            .collect()
+    }
+
+    /// append the sections of all dissectors to the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+        self.ident.report(file)?;
+        self.dns.report(file)?;
+        self.dhcp.report(file)?;
+        self.http.report(file)?;
+        self.tls.report(file)?;
+        self.ssh.report(file)?;
+        self.discovery.report(file)?;
+        self.modbus.report(file)?;
+        self.dnp3.report(file)?;
+        self.s7comm.report(file)?;
+        self.enip.report(file)?;
+        self.bacnet.report(file)?;
+        self.iec104.report(file)?;
+        self.opcua.report(file)?;
+        self.profinet.report(file)?;
+        self.iec61850.report(file)?;
+        self.ethercat.report(file)?;
+        self.ptp.report(file)?;
+        self.mqtt.report(file)?;
+        self.coap.report(file)?;
+        self.snmp.report(file, self.creds.reveal)?;
+        self.ntp.report(file)?;
+        self.sip.report(file)?;
+        self.creds.report(file)?;
+        self.files.report(file)?;
+        self.streams.report(file)?;
+        Ok(())
+    }
+
+}
+
+impl StreamConsumer for Dissectors {
+
+    fn data(&mut self, flow: &Flow, data: &[u8]) {
+        self.tcp(flow, data);
+    }
+
+    fn gap(&mut self, flow: &Flow, len: u32) {
+        self.framer.reset(flow);
+        self.tls.gap(flow);
+        self.ssh.gap(flow);
+        self.files.gap(flow, len);
+    }
+
+    fn end(&mut self, flow: &Flow) {
+        self.framer.reset(flow);
+        self.http.end(flow);
+        self.tls.end(flow);
+        self.ssh.end(flow);
+        self.files.end(flow);
+    }
+
+}
+
+/// read a big endian u16 at `offset`, `None` if `buf` is too short
+pub fn be16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset+2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// read a big endian u32 at `offset`, `None` if `buf` is too short
+pub fn be32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset+4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// read a little endian u16 at `offset`, `None` if `buf` is too short
+pub fn le16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset+2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// read a little endian u32 at `offset`, `None` if `buf` is too short
+pub fn le32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset+4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
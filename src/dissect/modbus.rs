@@ -0,0 +1,314 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, be16};
+
+/// Modbus/TCP server port
+pub const MODBUS_PORT: u16 = 502;
+
+/// size of the MBAP header
+const MBAP_LEN: usize = 7;
+
+/// function codes
+const READ_COILS:               u8 = 1;
+const READ_DISCRETE_INPUTS:     u8 = 2;
+const READ_HOLDING_REGISTERS:   u8 = 3;
+const READ_INPUT_REGISTERS:     u8 = 4;
+const WRITE_SINGLE_COIL:        u8 = 5;
+const WRITE_SINGLE_REGISTER:    u8 = 6;
+const WRITE_MULTIPLE_COILS:     u8 = 15;
+const WRITE_MULTIPLE_REGISTERS: u8 = 16;
+const MASK_WRITE_REGISTER:      u8 = 22;
+const READ_WRITE_REGISTERS:     u8 = 23;
+
+/// the four data tables of a Modbus device
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    InputRegisters,
+    HoldingRegisters,
+}
+
+impl Table {
+
+    fn name(&self) -> &'static str {
+        match self {
+            Table::Coils            => "coils",
+            Table::DiscreteInputs   => "discrete inputs",
+            Table::InputRegisters   => "input registers",
+            Table::HoldingRegisters => "holding registers",
+        }
+    }
+
+}
+
+/// read or write access to a table
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// a range of a table a request accesses, `end` is exclusive
+pub struct Range {
+    pub table:  Table,
+    pub access: Access,
+    pub start:  u16,
+    pub end:    u32,
+}
+
+/// get the name of a function code
+pub fn function_name(fc: u8) -> String {
+    match fc {
+        READ_COILS               => "Read Coils".to_string(),
+        READ_DISCRETE_INPUTS     => "Read Discrete Inputs".to_string(),
+        READ_HOLDING_REGISTERS   => "Read Holding Registers".to_string(),
+        READ_INPUT_REGISTERS     => "Read Input Registers".to_string(),
+        WRITE_SINGLE_COIL        => "Write Single Coil".to_string(),
+        WRITE_SINGLE_REGISTER    => "Write Single Register".to_string(),
+        7                        => "Read Exception Status".to_string(),
+        8                        => "Diagnostics".to_string(),
+        11                       => "Get Comm Event Counter".to_string(),
+        12                       => "Get Comm Event Log".to_string(),
+        WRITE_MULTIPLE_COILS     => "Write Multiple Coils".to_string(),
+        WRITE_MULTIPLE_REGISTERS => "Write Multiple Registers".to_string(),
+        17                       => "Report Server ID".to_string(),
+        20                       => "Read File Record".to_string(),
+        21                       => "Write File Record".to_string(),
+        MASK_WRITE_REGISTER      => "Mask Write Register".to_string(),
+        READ_WRITE_REGISTERS     => "Read/Write Multiple Registers".to_string(),
+        24                       => "Read FIFO Queue".to_string(),
+        43                       => "Encapsulated Interface Transport".to_string(),
+        _                        => "Unknown".to_string(),
+    }
+}
+
+/// get the name of an exception code
+pub fn exception_name(code: u8) -> String {
+    match code {
+        1  => "Illegal Function".to_string(),
+        2  => "Illegal Data Address".to_string(),
+        3  => "Illegal Data Value".to_string(),
+        4  => "Server Device Failure".to_string(),
+        5  => "Acknowledge".to_string(),
+        6  => "Server Device Busy".to_string(),
+        8  => "Memory Parity Error".to_string(),
+        10 => "Gateway Path Unavailable".to_string(),
+        11 => "Gateway Target Failed to Respond".to_string(),
+        _  => "Unknown".to_string(),
+    }
+}
+
+/// get the ranges a request PDU reads or writes
+pub fn request_ranges(fc: u8, data: &[u8]) -> Vec<Range> {
+
+    let range = |table, access, start: u16, count: u16| Range {
+        table, access, start,
+        end: start as u32 + count as u32,
+    };
+
+    let (Some(addr), Some(qty)) = (be16(data, 0), be16(data, 2)) else {
+        return Vec::new();
+    };
+
+    match fc {
+        READ_COILS => vec![range(Table::Coils, Access::Read, addr, qty)],
+        READ_DISCRETE_INPUTS => {
+            vec![range(Table::DiscreteInputs, Access::Read, addr, qty)]
+        },
+        READ_HOLDING_REGISTERS => {
+            vec![range(Table::HoldingRegisters, Access::Read, addr, qty)]
+        },
+        READ_INPUT_REGISTERS => {
+            vec![range(Table::InputRegisters, Access::Read, addr, qty)]
+        },
+        // the second field is the value, not a quantity
+        WRITE_SINGLE_COIL => vec![range(Table::Coils, Access::Write, addr, 1)],
+        WRITE_SINGLE_REGISTER | MASK_WRITE_REGISTER => {
+            vec![range(Table::HoldingRegisters, Access::Write, addr, 1)]
+        },
+        WRITE_MULTIPLE_COILS => {
+            vec![range(Table::Coils, Access::Write, addr, qty)]
+        },
+        WRITE_MULTIPLE_REGISTERS => {
+            vec![range(Table::HoldingRegisters, Access::Write, addr, qty)]
+        },
+        READ_WRITE_REGISTERS => {
+            let (Some(waddr), Some(wqty)) = (be16(data, 4), be16(data, 6)) else {
+                return Vec::new();
+            };
+            vec![
+                range(Table::HoldingRegisters, Access::Read, addr, qty),
+                range(Table::HoldingRegisters, Access::Write, waddr, wqty),
+            ]
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// merge overlapping and adjacent ranges and format them as `0-9, 20`
+fn ranges_string(ranges: &BTreeSet<(u16, u32)>) -> String {
+
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (start, end) in ranges {
+        let start = *start as u32;
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(*end),
+            _ => merged.push((start, *end)),
+        }
+    }
+
+    merged.iter()
+        .map(|(start, end)| {
+            if end - start <= 1 {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end - 1)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// everything we saw between a master and a slave
+#[derive(Default)]
+pub struct Pair {
+    pub units:      BTreeSet<u8>,
+    pub functions:  BTreeMap<u8, u64>,
+    /// accessed ranges per unit, table and access
+    pub ranges:     BTreeMap<(u8, Table, Access), BTreeSet<(u16, u32)>>,
+    /// exceptions per unit, function and exception code
+    pub exceptions: BTreeMap<(u8, u8, u8), u64>,
+}
+
+/// what we learned from the Modbus/TCP traffic in the capture
+#[derive(Default)]
+pub struct ModbusInfo {
+    /// keyed by master and slave address
+    pub pairs:  BTreeMap<(Ipv4Addr, Ipv4Addr), Pair>,
+}
+
+impl ModbusInfo {
+
+    /// handle whole ADUs of a TCP direction, there may be several
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let request = flow.dport == MODBUS_PORT;
+        let key = if request {
+            (flow.sip, flow.dip)
+        } else {
+            (flow.dip, flow.sip)
+        };
+
+        let mut pos = 0;
+
+        while pos + MBAP_LEN < payload.len() {
+
+            let (Some(proto), Some(len)) =
+                (be16(payload, pos+2), be16(payload, pos+4)) else {
+                break;
+            };
+
+            // the length covers the unit id and the PDU
+            let len = len as usize;
+            if proto != 0 || len < 2 {
+                break;
+            }
+
+            let unit = payload[pos+6];
+            let Some(pdu) = payload.get(pos+MBAP_LEN..pos+6+len) else {
+                break;
+            };
+
+            self.pdu(key, request, unit, pdu);
+            pos += 6 + len;
+        }
+    }
+
+    /// add a single PDU to the summary of its pair
+    fn pdu(&mut self, key: (Ipv4Addr, Ipv4Addr), request: bool,
+        unit: u8, pdu: &[u8]) {
+
+        let pair = self.pairs.entry(key).or_default();
+        pair.units.insert(unit);
+
+        let fc = pdu[0];
+
+        if request {
+            *pair.functions.entry(fc).or_insert(0) += 1;
+            for r in request_ranges(fc, &pdu[1..]) {
+                pair.ranges.entry((unit, r.table, r.access)).or_default()
+                    .insert((r.start, r.end));
+            }
+        } else if fc & 0x80 != 0 {
+            let code = *pdu.get(1).unwrap_or(&0);
+            *pair.exceptions.entry((unit, fc & 0x7f, code)).or_insert(0) += 1;
+        }
+    }
+
+    /// write the Modbus section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- Modbus/TCP (master -> slave)\n")?;
+
+        for ((master, slave), pair) in &self.pairs {
+
+            write!(file, "{} -> {}\n", master, slave)?;
+
+            let units = pair.units.iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(file, "    unit ids:   {}\n", units)?;
+
+            for (fc, count) in &pair.functions {
+                write!(file, "    function:   {:>3} {} ({}x)\n",
+                    fc, function_name(*fc), count)?;
+            }
+
+            for ((unit, table, access), ranges) in &pair.ranges {
+                let access = match access {
+                    Access::Read  => "read ",
+                    Access::Write => "write",
+                };
+                write!(file, "    {} unit {:<3} {:<18} {}\n",
+                    access, unit, table.name(), ranges_string(ranges))?;
+            }
+
+            for ((unit, fc, code), count) in &pair.exceptions {
+                write!(file, "    exception:  unit {} {} -> {:02} {} ({}x)\n",
+                    unit, function_name(*fc), code,
+                    exception_name(*code), count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,358 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! MQTT 3.1, 3.1.1 and 5. a packet is a fixed header with a variable
+//! length, so one segment can hold several packets. MQTT over TLS is
+//! only counted.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, be16};
+
+/// MQTT ports, cleartext and TLS
+pub const MQTT_PORT:    u16 = 1883;
+pub const MQTTS_PORT:   u16 = 8883;
+
+/// packet types
+const CONNECT:      u8 = 1;
+const CONNACK:      u8 = 2;
+const PUBLISH:      u8 = 3;
+const SUBSCRIBE:    u8 = 8;
+
+/// CONNECT flags
+const FLAG_USERNAME:    u8 = 0x80;
+const FLAG_PASSWORD:    u8 = 0x40;
+const FLAG_WILL:        u8 = 0x04;
+
+/// protocol level of MQTT 5, which adds properties
+const VERSION_5: u8 = 5;
+
+/// get the meaning of a CONNACK return code
+fn connack_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "accepted",
+        0x01 => "refused (protocol version)",
+        0x02 => "refused (identifier rejected)",
+        0x03 => "refused (server unavailable)",
+        0x04 | 0x86 => "refused (bad user name or password)",
+        0x05 | 0x87 => "refused (not authorized)",
+        _ => "refused",
+    }
+}
+
+/// read a variable byte integer, returns the value and its size
+pub fn varint(buf: &[u8], off: usize) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for i in 0..4 {
+        let b = *buf.get(off + i)?;
+        value |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// reads the length prefixed fields of a packet
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let v = be16(self.buf, self.pos)?;
+        self.pos += 2;
+        Some(v)
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        let b = self.buf.get(self.pos..self.pos+len)?;
+        self.pos += len;
+        Some(b)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.bytes().map(|b| String::from_utf8_lossy(b).to_string())
+    }
+
+    /// skip the properties of MQTT 5
+    fn properties(&mut self) -> Option<()> {
+        let (len, size) = varint(self.buf, self.pos)?;
+        self.pos += size + len;
+        Some(())
+    }
+
+}
+
+/// what a client did with a broker
+#[derive(Default)]
+pub struct Session {
+    pub client_ids:     BTreeSet<String>,
+    pub usernames:      BTreeSet<String>,
+    /// a password was sent in a cleartext CONNECT
+    pub password:       bool,
+    pub versions:       BTreeSet<u8>,
+    pub results:        BTreeMap<&'static str, u64>,
+    pub published:      BTreeMap<String, u64>,
+    pub subscribed:     BTreeSet<String>,
+    /// topics the broker delivered to the client
+    pub received:       BTreeMap<String, u64>,
+    /// segments on the TLS port
+    pub tls:            u64,
+}
+
+/// what we learned from the MQTT traffic in the capture
+#[derive(Default)]
+pub struct MqttInfo {
+    /// keyed by broker and client
+    pub sessions:   BTreeMap<(Ipv4Addr, Ipv4Addr), Session>,
+    /// protocol level per client connection, properties depend on it
+    versions:       BTreeMap<(Ipv4Addr, u16, Ipv4Addr), u8>,
+}
+
+impl MqttInfo {
+
+    /// handle whole packets on the MQTT port, or TLS on the MQTTS port
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let to_broker = flow.dport == MQTT_PORT || flow.dport == MQTTS_PORT;
+        let (broker, client, client_port) = if to_broker {
+            (flow.dip, flow.sip, flow.sport)
+        } else {
+            (flow.sip, flow.dip, flow.dport)
+        };
+
+        if flow.has_port(MQTTS_PORT) {
+            self.sessions.entry((broker, client)).or_default().tls += 1;
+            return;
+        }
+
+        let connection = (client, client_port, broker);
+        for (first, body) in packets(payload) {
+            self.packet(connection, to_broker, first, body);
+        }
+    }
+
+    /// handle a single packet, `first` is the first byte of the fixed header
+    fn packet(&mut self, connection: (Ipv4Addr, u16, Ipv4Addr),
+        to_broker: bool, first: u8, body: &[u8]) {
+
+        let (client, _, broker) = connection;
+        let kind = first >> 4;
+        let version = self.versions.get(&connection).copied().unwrap_or(4);
+        let mut r = Reader { buf: body, pos: 0 };
+
+        match (kind, to_broker) {
+            (CONNECT, true) => {
+                let Some(connect) = parse_connect(&mut r) else {
+                    return;
+                };
+                self.versions.insert(connection, connect.version);
+                let session = self.sessions.entry((broker, client)).or_default();
+                session.versions.insert(connect.version);
+                session.client_ids.insert(connect.client_id);
+                session.usernames.extend(connect.username);
+                session.password |= connect.password.is_some();
+            },
+            (CONNACK, false) => {
+                if let Some(code) = body.get(1) {
+                    let session = self.sessions.entry((broker, client)).or_default();
+                    *session.results.entry(connack_name(*code)).or_insert(0) += 1;
+                }
+            },
+            (PUBLISH, _) => {
+                let Some(topic) = r.string() else {
+                    return;
+                };
+                let session = self.sessions.entry((broker, client)).or_default();
+                let topics = if to_broker {
+                    &mut session.published
+                } else {
+                    &mut session.received
+                };
+                *topics.entry(topic).or_insert(0) += 1;
+            },
+            (SUBSCRIBE, true) => {
+                if r.u16().is_none() {
+                    return;
+                }
+                if version == VERSION_5 && r.properties().is_none() {
+                    return;
+                }
+                let session = self.sessions.entry((broker, client)).or_default();
+                // each topic filter is followed by its options
+                while let Some(filter) = r.string() {
+                    session.subscribed.insert(filter);
+                    if r.u8().is_none() {
+                        break;
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// write the MQTT section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.sessions.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- MQTT Topics (broker <- client)\n")?;
+
+        let counted = |topics: &BTreeMap<String, u64>| {
+            topics.iter()
+                .map(|(topic, count)| format!("{} ({}x)", topic, count))
+                .collect::<Vec<_>>()
+        };
+
+        for ((broker, client), s) in &self.sessions {
+
+            let flag = if s.password { "  [CLEARTEXT PASSWORD]" } else { "" };
+            write!(file, "{} <- {}{}\n", broker, client, flag)?;
+
+            if !s.versions.is_empty() {
+                let versions = s.versions.iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(file, "    version:    {}\n", versions)?;
+            }
+            for id in &s.client_ids {
+                write!(file, "    client id:  {}\n", id)?;
+            }
+            for username in &s.usernames {
+                write!(file, "    username:   {}\n", username)?;
+            }
+            for (result, count) in &s.results {
+                write!(file, "    connect:    {} ({}x)\n", result, count)?;
+            }
+            for topic in counted(&s.published) {
+                write!(file, "    publishes:  {}\n", topic)?;
+            }
+            for filter in &s.subscribed {
+                write!(file, "    subscribes: {}\n", filter)?;
+            }
+            for topic in counted(&s.received) {
+                write!(file, "    receives:   {}\n", topic)?;
+            }
+            if s.tls > 0 {
+                write!(file, "    tls:        {} segments on port {}, not decoded\n",
+                    s.tls, MQTTS_PORT)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+/// the fields of a CONNECT packet we track
+struct Connect {
+    version:    u8,
+    client_id:  String,
+    username:   Option<String>,
+    password:   Option<String>,
+}
+
+/// parse a CONNECT packet
+fn parse_connect(r: &mut Reader) -> Option<Connect> {
+
+    let name = r.string()?;
+    if name != "MQTT" && name != "MQIsdp" {
+        return None;
+    }
+    let version = r.u8()?;
+    let flags = r.u8()?;
+    let _keep_alive = r.u16()?;
+    if version == VERSION_5 {
+        r.properties()?;
+    }
+
+    let client_id = r.string()?;
+
+    if flags & FLAG_WILL != 0 {
+        if version == VERSION_5 {
+            r.properties()?;
+        }
+        let _will_topic = r.bytes()?;
+        let _will_message = r.bytes()?;
+    }
+
+    let username = if flags & FLAG_USERNAME != 0 { r.string() } else { None };
+    // the password is binary data, but mostly text
+    let password = if flags & FLAG_PASSWORD != 0 {
+        r.bytes().map(|b| String::from_utf8_lossy(b).to_string())
+    } else {
+        None
+    };
+
+    Some(Connect {
+        version,
+        client_id,
+        username,
+        password,
+    })
+}
+
+/// split a segment into packets, the first byte of the fixed header and
+/// the data behind the length
+fn packets(payload: &[u8]) -> Vec<(u8, &[u8])> {
+
+    let mut packets = Vec::new();
+    let mut pos = 0;
+
+    while let (Some(&first), Some((len, size))) =
+        (payload.get(pos), varint(payload, pos+1)) {
+
+        let start = pos + 1 + size;
+        let Some(body) = payload.get(start..start+len) else {
+            break;
+        };
+        packets.push((first, body));
+        pos = start + len;
+    }
+
+    packets
+}
+
+/// user names and passwords of the CONNECT packets in a segment sent to
+/// a broker
+pub fn credentials(payload: &[u8]) -> Vec<(String, String)> {
+    packets(payload).into_iter()
+        .filter(|(first, _)| first >> 4 == CONNECT)
+        .filter_map(|(_, body)| parse_connect(&mut Reader { buf: body, pos: 0 }))
+        .filter_map(|c| Some((c.username.unwrap_or_default(), c.password?)))
+        .collect()
+}
@@ -0,0 +1,163 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::net::Ipv4Addr;
+
+use crate::dissect::{be16, be32};
+use crate::dissect::dns::read_name;
+
+/// NetBIOS name service port
+pub const NBNS_PORT: u16 = 137;
+
+/// record types
+const TYPE_NB:     u16 = 0x20;
+const TYPE_NBSTAT: u16 = 0x21;
+
+/// the group bit in the name flags
+const FLAG_GROUP: u16 = 0x8000;
+
+/// a NetBIOS name with the suffix that tells the service
+pub struct NbName {
+    pub name:   String,
+    pub suffix: u8,
+    pub group:  bool,
+}
+
+impl NbName {
+
+    /// true for the names of a machine, not of a workgroup or service
+    pub fn is_host(&self) -> bool {
+        !self.group && (self.suffix == 0x00 || self.suffix == 0x20)
+    }
+
+    /// true for the workgroup or domain a machine belongs to
+    pub fn is_workgroup(&self) -> bool {
+        self.group && self.suffix == 0x00
+    }
+
+}
+
+/// a name bound to an address, taken from registrations and answers
+pub struct Binding {
+    pub name:   NbName,
+    pub ip:     Ipv4Addr,
+}
+
+/// what a decoded name service packet tells us
+pub struct NbnsMessage {
+    pub response:   bool,
+    pub opcode:     u8,
+    /// names bound to addresses (NB records)
+    pub bindings:   Vec<Binding>,
+    /// names a node lists in a node status response (NBSTAT records)
+    pub status:     Vec<NbName>,
+}
+
+/// undo the first level encoding, every byte of the name is split into
+/// two nibbles which are added to 'A'
+fn decode_name(encoded: &str) -> Option<(String, u8)> {
+
+    let label = encoded.split('.').next()?.as_bytes();
+    if label.len() != 32 {
+        return None;
+    }
+
+    let mut raw = Vec::with_capacity(16);
+    for pair in label.chunks_exact(2) {
+        let hi = pair[0].checked_sub(b'A')?;
+        let lo = pair[1].checked_sub(b'A')?;
+        if hi > 0x0f || lo > 0x0f {
+            return None;
+        }
+        raw.push(hi << 4 | lo);
+    }
+
+    Some((name_string(&raw[..15]), raw[15]))
+}
+
+/// names are padded with spaces
+fn name_string(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .trim_end_matches([' ', '\0'])
+        .to_string()
+}
+
+/// parse a name service packet
+pub fn parse(buf: &[u8]) -> Option<NbnsMessage> {
+
+    let flags = be16(buf, 2)?;
+    let qdcount = be16(buf, 4)?;
+    let rrcount = be16(buf, 6)? as u32 + be16(buf, 8)? as u32
+        + be16(buf, 10)? as u32;
+
+    let mut msg = NbnsMessage {
+        response:   flags & 0x8000 != 0,
+        opcode:     ((flags >> 11) & 0x0f) as u8,
+        bindings:   Vec::new(),
+        status:     Vec::new(),
+    };
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let (_, next) = read_name(buf, pos)?;
+        pos = next + 4;
+    }
+
+    for _ in 0..rrcount {
+
+        let (encoded, next) = read_name(buf, pos)?;
+        let rtype = be16(buf, next)?;
+        let rdlen = be16(buf, next+8)? as usize;
+        let rdata = buf.get(next+10..next+10+rdlen)?;
+        pos = next + 10 + rdlen;
+
+        match rtype {
+            TYPE_NB => {
+                let Some((name, suffix)) = decode_name(&encoded) else {
+                    continue;
+                };
+                // one entry of flags and address per address
+                for entry in rdata.chunks_exact(6) {
+                    let nb_flags = be16(entry, 0)?;
+                    msg.bindings.push(Binding {
+                        name: NbName {
+                            name: name.clone(),
+                            suffix,
+                            group: nb_flags & FLAG_GROUP != 0,
+                        },
+                        ip: Ipv4Addr::from(be32(entry, 2)?),
+                    });
+                }
+            },
+            TYPE_NBSTAT => {
+                // count, then 15 bytes name, suffix and flags per name
+                let count = *rdata.first()? as usize;
+                for entry in rdata.get(1..)?.chunks_exact(18).take(count) {
+                    msg.status.push(NbName {
+                        name:   name_string(&entry[..15]),
+                        suffix: entry[15],
+                        group:  be16(entry, 16)? & FLAG_GROUP != 0,
+                    });
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Some(msg)
+}
@@ -0,0 +1,234 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! NTP time exchanges and the control (mode 6) and private (mode 7)
+//! requests, monlist among them.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, be32};
+
+/// NTP port
+pub const NTP_PORT: u16 = 123;
+
+/// modes
+const MODE_SYMMETRIC_ACTIVE:    u8 = 1;
+const MODE_SYMMETRIC_PASSIVE:   u8 = 2;
+const MODE_CLIENT:              u8 = 3;
+const MODE_SERVER:              u8 = 4;
+const MODE_BROADCAST:           u8 = 5;
+const MODE_CONTROL:             u8 = 6;
+const MODE_PRIVATE:             u8 = 7;
+
+/// size of a time packet without extensions
+const PACKET_LEN: usize = 48;
+
+/// private request codes that return the monitor list
+const REQ_MON_GETLIST:      u8 = 20;
+const REQ_MON_GETLIST_1:    u8 = 42;
+
+/// get the name of a mode
+fn mode_name(mode: u8) -> &'static str {
+    match mode {
+        MODE_SYMMETRIC_ACTIVE   => "symmetric active",
+        MODE_SYMMETRIC_PASSIVE  => "symmetric passive",
+        MODE_CLIENT             => "client",
+        MODE_SERVER             => "server",
+        MODE_BROADCAST          => "broadcast",
+        MODE_CONTROL            => "control",
+        MODE_PRIVATE            => "private",
+        _                       => "reserved",
+    }
+}
+
+/// get the name of a control message opcode
+fn control_name(opcode: u8) -> &'static str {
+    match opcode {
+        1 => "readstat",
+        2 => "readvar",
+        3 => "writevar",
+        4 => "readclock",
+        5 => "writeclock",
+        6 => "setTrap",
+        7 => "asyncmsg",
+        8 => "configure",
+        9 => "saveconfig",
+        10 => "readmru",
+        _ => "unknown",
+    }
+}
+
+/// the reference id is text for stratum 0 and 1, the address of the
+/// upstream server above
+fn refid_string(stratum: u8, refid: u32) -> String {
+    if stratum <= 1 {
+        let text: String = refid.to_be_bytes().iter()
+            .take_while(|b| **b != 0)
+            .map(|b| *b as char)
+            .collect();
+        // stratum 0 means kiss-o'-death
+        if stratum == 0 {
+            return format!("kiss code {}", text);
+        }
+        text
+    } else {
+        Ipv4Addr::from(refid).to_string()
+    }
+}
+
+/// a host that hands out time
+#[derive(Default)]
+pub struct Server {
+    pub modes:      BTreeSet<&'static str>,
+    pub versions:   BTreeSet<u8>,
+    pub strata:     BTreeSet<u8>,
+    pub refids:     BTreeSet<String>,
+    pub clients:    BTreeSet<Ipv4Addr>,
+    pub responses:  u64,
+}
+
+/// what we learned from the NTP traffic in the capture
+#[derive(Default)]
+pub struct NtpInfo {
+    /// time sources, including servers asked that never answered
+    pub servers:    BTreeMap<Ipv4Addr, Server>,
+    /// control and private requests keyed by requester and target
+    pub control:    BTreeMap<(Ipv4Addr, Ipv4Addr), BTreeMap<String, u64>>,
+}
+
+impl NtpInfo {
+
+    /// handle a UDP payload on the NTP port
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(&first) = payload.first() else {
+            return;
+        };
+        let version = (first >> 3) & 0x07;
+        let mode = first & 0x07;
+
+        match mode {
+            MODE_CLIENT => {
+                let server = self.servers.entry(flow.dip).or_default();
+                server.clients.insert(flow.sip);
+            },
+            MODE_SERVER | MODE_BROADCAST
+            | MODE_SYMMETRIC_ACTIVE | MODE_SYMMETRIC_PASSIVE => {
+                let Some(refid) = be32(payload, 12) else {
+                    return;
+                };
+                if payload.len() < PACKET_LEN {
+                    return;
+                }
+                let stratum = payload[1];
+                let server = self.servers.entry(flow.sip).or_default();
+                server.modes.insert(mode_name(mode));
+                server.versions.insert(version);
+                server.strata.insert(stratum);
+                server.refids.insert(refid_string(stratum, refid));
+                server.responses += 1;
+                if mode == MODE_SERVER {
+                    server.clients.insert(flow.dip);
+                }
+            },
+            MODE_CONTROL | MODE_PRIVATE => {
+                // the response bit is set in replies
+                let (request, name) = if mode == MODE_CONTROL {
+                    let Some(&op) = payload.get(1) else {
+                        return;
+                    };
+                    (op & 0x80 == 0, format!("mode 6 {}", control_name(op & 0x1f)))
+                } else {
+                    let Some(&code) = payload.get(3) else {
+                        return;
+                    };
+                    let name = match code {
+                        REQ_MON_GETLIST | REQ_MON_GETLIST_1 => "monlist".to_string(),
+                        _ => format!("request {}", code),
+                    };
+                    (first & 0x80 == 0, format!("mode 7 {}", name))
+                };
+                if request {
+                    let requests = self.control.entry((flow.sip, flow.dip)).or_default();
+                    *requests.entry(name).or_insert(0) += 1;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// write the NTP sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        let join = |v: Vec<String>| v.join(", ");
+
+        if !self.servers.is_empty() {
+
+            write!(file, "\n\n-- NTP Time Sources\n")?;
+
+            for (host, s) in &self.servers {
+
+                write!(file, "{}\n", host)?;
+
+                if s.responses == 0 {
+                    write!(file, "    no response seen\n")?;
+                } else {
+                    let modes = s.modes.iter().map(|m| m.to_string()).collect();
+                    write!(file, "    modes:    {}\n", join(modes))?;
+                    let versions = s.versions.iter().map(|v| v.to_string()).collect();
+                    write!(file, "    versions: {}\n", join(versions))?;
+                    let strata = s.strata.iter().map(|s| s.to_string()).collect();
+                    write!(file, "    stratum:  {}\n", join(strata))?;
+                    write!(file, "    refid:    {}\n", join(s.refids.iter().cloned().collect()))?;
+                }
+                if !s.clients.is_empty() {
+                    let clients = s.clients.iter().map(|c| c.to_string()).collect();
+                    write!(file, "    clients:  {}\n", join(clients))?;
+                }
+            }
+        }
+
+        if !self.control.is_empty() {
+
+            write!(file, "\n\n-- NTP Control Requests (requester -> target)\n")?;
+
+            for ((requester, target), requests) in &self.control {
+                // monlist answers are many times the request, a known
+                // amplification vector
+                let flag = if requests.keys().any(|r| r.ends_with("monlist")) {
+                    "  [MONLIST]"
+                } else {
+                    ""
+                };
+                write!(file, "{} -> {}{}\n", requester, target, flag)?;
+                for (request, count) in requests {
+                    write!(file, "    {} ({}x)\n", request, count)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,512 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! OPC UA binary protocol (UA TCP). only the messages that set up a
+//! connection are decoded: Hello, OpenSecureChannel, GetEndpoints and
+//! CreateSession. bodies of encrypted channels can't be read, so the
+//! security mode of a channel is taken from what we could read.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::dissect::{Flow, le16, le32};
+
+/// registered OPC UA port, servers listen on other ports as well
+pub const OPCUA_PORT: u16 = 4840;
+
+/// size of the message header: type, chunk type and size
+const HEADER_LEN: usize = 8;
+
+/// the policy uri without security
+const POLICY_NONE: &str = "http://opcfoundation.org/UA/SecurityPolicy#None";
+
+/// message security modes
+const MODE_NONE: u32 = 1;
+
+/// binary encoding ids of the services we decode
+const GET_ENDPOINTS_RESPONSE:   u32 = 431;
+const OPEN_CHANNEL_REQUEST:     u32 = 446;
+const CREATE_SESSION_REQUEST:   u32 = 461;
+const CREATE_SESSION_RESPONSE:  u32 = 464;
+
+/// get the name of a message security mode
+pub fn mode_name(mode: u32) -> String {
+    match mode {
+        0         => "Invalid".to_string(),
+        MODE_NONE => "None".to_string(),
+        2         => "Sign".to_string(),
+        3         => "SignAndEncrypt".to_string(),
+        _         => format!("mode {}", mode),
+    }
+}
+
+/// the part of a policy uri after the `#`
+pub fn policy_name(uri: &str) -> &str {
+    uri.rsplit_once('#').map(|(_, p)| p).unwrap_or(uri)
+}
+
+/// reads the binary encoding of OPC UA built-in types
+struct Reader<'a> {
+    buf:    &'a [u8],
+    pos:    usize,
+}
+
+impl<'a> Reader<'a> {
+
+    fn new(buf: &'a [u8], pos: usize) -> Reader<'a> {
+        Reader { buf, pos }
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        if self.pos + n > self.buf.len() {
+            return None;
+        }
+        self.pos += n;
+        Some(())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let v = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(v)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let v = le32(self.buf, self.pos)?;
+        self.pos += 4;
+        Some(v)
+    }
+
+    /// strings and byte strings have a signed length, -1 is null
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as i32;
+        if len < 0 {
+            return Some(&[]);
+        }
+        let v = self.buf.get(self.pos..self.pos + len as usize)?;
+        self.pos += len as usize;
+        Some(v)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        Some(String::from_utf8_lossy(self.bytes()?).to_string())
+    }
+
+    /// skip an array of strings
+    fn skip_strings(&mut self) -> Option<()> {
+        let count = self.u32()? as i32;
+        for _ in 0..count.max(0) {
+            self.bytes()?;
+        }
+        Some(())
+    }
+
+    /// read a node id, returns the numeric identifier if it has one
+    fn node_id(&mut self) -> Option<Option<u32>> {
+        match self.u8()? & 0x3f {
+            0x00 => Some(Some(self.u8()? as u32)),
+            0x01 => {
+                let id = le16(self.buf, self.pos + 1)?;
+                self.pos += 3;
+                Some(Some(id as u32))
+            },
+            0x02 => {
+                self.skip(2)?;
+                Some(Some(self.u32()?))
+            },
+            0x03 | 0x05 => {
+                self.skip(2)?;
+                self.bytes()?;
+                Some(None)
+            },
+            0x04 => {
+                self.skip(18)?;
+                Some(None)
+            },
+            _ => None,
+        }
+    }
+
+    fn skip_extension_object(&mut self) -> Option<()> {
+        self.node_id()?;
+        if self.u8()? != 0 {
+            self.bytes()?;
+        }
+        Some(())
+    }
+
+    fn skip_localized_text(&mut self) -> Option<()> {
+        let mask = self.u8()?;
+        if mask & 0x01 != 0 {
+            self.bytes()?;
+        }
+        if mask & 0x02 != 0 {
+            self.bytes()?;
+        }
+        Some(())
+    }
+
+    /// skip a request header, the service body follows
+    fn skip_request_header(&mut self) -> Option<()> {
+        self.node_id()?;
+        // timestamp, request handle, return diagnostics
+        self.skip(16)?;
+        self.bytes()?;
+        self.skip(4)?;
+        self.skip_extension_object()
+    }
+
+    /// skip a response header, only empty diagnostics are supported
+    fn skip_response_header(&mut self) -> Option<()> {
+        // timestamp, request handle, service result
+        self.skip(16)?;
+        if self.u8()? != 0 {
+            return None;
+        }
+        self.skip_strings()?;
+        self.skip_extension_object()
+    }
+
+    /// read an application description, returns its uri
+    fn application_description(&mut self) -> Option<String> {
+        let uri = self.string()?;
+        self.bytes()?;
+        self.skip_localized_text()?;
+        self.skip(4)?;
+        self.bytes()?;
+        self.bytes()?;
+        self.skip_strings()?;
+        Some(uri)
+    }
+
+    /// read an endpoint description
+    fn endpoint(&mut self) -> Option<Endpoint> {
+        let url = self.string()?;
+        self.application_description()?;
+        self.bytes()?;
+        let mode = self.u32()?;
+        let policy = self.string()?;
+        // user token policies
+        let count = self.u32()? as i32;
+        for _ in 0..count.max(0) {
+            self.bytes()?;
+            self.skip(4)?;
+            self.bytes()?;
+            self.bytes()?;
+            self.bytes()?;
+        }
+        self.bytes()?;
+        self.skip(1)?;
+        Some(Endpoint { url, policy, mode })
+    }
+
+}
+
+/// an endpoint a server offers
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub url:    String,
+    pub policy: String,
+    pub mode:   u32,
+}
+
+impl Endpoint {
+
+    fn insecure(&self) -> bool {
+        self.policy == POLICY_NONE || self.mode == MODE_NONE
+    }
+
+}
+
+/// what we saw on a single connection
+#[derive(Default)]
+struct Channel {
+    /// security policy of OpenSecureChannel
+    policy:     Option<String>,
+    /// mode of OpenSecureChannel, only readable without a policy
+    mode:       Option<u32>,
+    /// a service body could be read although the channel has a policy,
+    /// so the channel signs but doesn't encrypt
+    plaintext:  bool,
+}
+
+/// everything we saw between a client and a server
+#[derive(Default)]
+pub struct Pair {
+    pub urls:       BTreeSet<String>,
+    pub policies:   BTreeSet<String>,
+    pub modes:      BTreeSet<String>,
+    /// session names with the application uri of the client
+    pub sessions:   BTreeSet<(String, String)>,
+    pub insecure:   bool,
+    pub errors:     BTreeSet<String>,
+}
+
+/// (client, client port, server, server port)
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// what we learned from the OPC UA traffic in the capture
+#[derive(Default)]
+pub struct OpcUaInfo {
+    /// keyed by client and server
+    pub pairs:      BTreeMap<(Ipv4Addr, Ipv4Addr), Pair>,
+    /// endpoints servers offered in GetEndpoints and CreateSession
+    pub endpoints:  BTreeMap<Ipv4Addr, BTreeSet<Endpoint>>,
+    channels:       HashMap<ConnKey, Channel>,
+}
+
+impl OpcUaInfo {
+
+    /// handle a whole message of a TCP direction. a connection is
+    /// picked up at its hello, or on the registered port
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(kind) = payload.get(..3) else {
+            return;
+        };
+        if payload.len() < HEADER_LEN || !b"FCA".contains(&payload[3]) {
+            return;
+        }
+
+        let forward = (flow.sip, flow.sport, flow.dip, flow.dport);
+        let reverse = (flow.dip, flow.dport, flow.sip, flow.sport);
+
+        let (key, request) = if kind == b"HEL" {
+            self.channels.insert(forward, Channel::default());
+            (forward, true)
+        } else if self.channels.contains_key(&forward) {
+            (forward, true)
+        } else if self.channels.contains_key(&reverse) {
+            (reverse, false)
+        } else if flow.dport == OPCUA_PORT {
+            self.channels.insert(forward, Channel::default());
+            (forward, true)
+        } else if flow.sport == OPCUA_PORT {
+            self.channels.insert(reverse, Channel::default());
+            (reverse, false)
+        } else {
+            return;
+        };
+
+        match kind {
+            b"HEL" => self.hello(key, payload),
+            b"OPN" if request => self.open(key, payload),
+            b"MSG" => self.message(key, request, payload),
+            b"ERR" => {
+                let mut r = Reader::new(payload, HEADER_LEN);
+                if let (Some(code), Some(reason)) = (r.u32(), r.string()) {
+                    self.pair(key).errors.insert(
+                        format!("0x{:08x} {}", code, reason));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn pair(&mut self, key: ConnKey) -> &mut Pair {
+        self.pairs.entry((key.0, key.2)).or_default()
+    }
+
+    /// endpoint url of the hello
+    fn hello(&mut self, key: ConnKey, buf: &[u8]) {
+        // version and buffer sizes
+        let mut r = Reader::new(buf, HEADER_LEN + 20);
+        if let Some(url) = r.string() {
+            self.pair(key).urls.insert(url);
+        }
+    }
+
+    /// security policy of OpenSecureChannel, the mode can only be read
+    /// if the policy is None, everything else is encrypted
+    fn open(&mut self, key: ConnKey, buf: &[u8]) {
+
+        // secure channel id, then the asymmetric security header
+        let mut r = Reader::new(buf, HEADER_LEN + 4);
+        let Some(policy) = r.string() else {
+            return;
+        };
+
+        let mode = if policy == POLICY_NONE {
+            open_mode(&mut r)
+        } else {
+            None
+        };
+
+        let channel = self.channels.entry(key).or_default();
+        channel.policy = Some(policy.clone());
+        channel.mode = mode;
+
+        let pair = self.pair(key);
+        pair.policies.insert(policy.clone());
+        if let Some(mode) = mode {
+            pair.modes.insert(mode_name(mode));
+        }
+        if policy == POLICY_NONE || mode == Some(MODE_NONE) {
+            pair.insecure = true;
+        }
+    }
+
+    /// CreateSession and GetEndpoints in symmetric messages
+    fn message(&mut self, key: ConnKey, request: bool, buf: &[u8]) {
+
+        // secure channel id, token id, sequence number and request id
+        let mut r = Reader::new(buf, HEADER_LEN + 16);
+        let Some(Some(service)) = r.node_id() else {
+            return;
+        };
+
+        match (service, request) {
+            (CREATE_SESSION_REQUEST, true) => {
+                let Some(app) = r.skip_request_header()
+                    .and_then(|_| r.application_description()) else {
+                    return;
+                };
+                // server uri, endpoint url, session name
+                let (Some(_), Some(url), Some(name)) =
+                    (r.bytes(), r.string(), r.string()) else {
+                    return;
+                };
+                self.readable(key);
+                let pair = self.pair(key);
+                pair.urls.insert(url);
+                pair.sessions.insert((name, app));
+            },
+            (CREATE_SESSION_RESPONSE, false) => {
+                if let Some(endpoints) = session_endpoints(&mut r) {
+                    self.readable(key);
+                    self.endpoints.entry(key.2).or_default().extend(endpoints);
+                }
+            },
+            (GET_ENDPOINTS_RESPONSE, false) => {
+                let endpoints = r.skip_response_header()
+                    .and_then(|_| endpoints(&mut r));
+                if let Some(endpoints) = endpoints {
+                    self.endpoints.entry(key.2).or_default().extend(endpoints);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// a session service could be read, so the channel is not encrypted
+    fn readable(&mut self, key: ConnKey) {
+        let Some(channel) = self.channels.get_mut(&key) else {
+            return;
+        };
+        if channel.plaintext || channel.mode.is_some() {
+            return;
+        }
+        channel.plaintext = true;
+        let policy = channel.policy.clone();
+        if policy.as_deref().is_some_and(|p| p != POLICY_NONE) {
+            self.pair(key).modes.insert("Sign (inferred)".to_string());
+        }
+    }
+
+    /// write the OPC UA sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if !self.pairs.is_empty() {
+
+            write!(file, "\n\n-- OPC UA Sessions (client -> server)\n")?;
+
+            for ((client, server), pair) in &self.pairs {
+
+                let flag = if pair.insecure { "  [INSECURE]" } else { "" };
+                write!(file, "{} -> {}{}\n", client, server, flag)?;
+
+                for url in &pair.urls {
+                    write!(file, "    endpoint: {}\n", url)?;
+                }
+                for policy in &pair.policies {
+                    let flag = if policy == POLICY_NONE {
+                        "  [POLICY NONE]"
+                    } else {
+                        ""
+                    };
+                    write!(file, "    policy:   {}{}\n", policy_name(policy), flag)?;
+                }
+                for mode in &pair.modes {
+                    let flag = if mode == "None" { "  [MODE NONE]" } else { "" };
+                    write!(file, "    mode:     {}{}\n", mode, flag)?;
+                }
+                for (name, app) in &pair.sessions {
+                    write!(file, "    session:  {} ({})\n", name, app)?;
+                }
+                for error in &pair.errors {
+                    write!(file, "    error:    {}\n", error)?;
+                }
+            }
+        }
+
+        if !self.endpoints.is_empty() {
+
+            write!(file, "\n\n-- OPC UA Server Endpoints\n")?;
+
+            for (server, endpoints) in &self.endpoints {
+                write!(file, "{}\n", server)?;
+                for e in endpoints {
+                    let flag = if e.insecure() { "  [INSECURE]" } else { "" };
+                    write!(file, "    {} {} {}{}\n", e.url,
+                        policy_name(&e.policy), mode_name(e.mode), flag)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+/// read an array of endpoint descriptions
+fn endpoints(r: &mut Reader) -> Option<Vec<Endpoint>> {
+    let count = r.u32()? as i32;
+    (0..count.max(0)).map(|_| r.endpoint()).collect()
+}
+
+/// the security mode an unencrypted OpenSecureChannel request asks for
+fn open_mode(r: &mut Reader) -> Option<u32> {
+    // certificate and thumbprint, sequence number and request id
+    r.bytes()?;
+    r.bytes()?;
+    r.skip(8)?;
+    if r.node_id()? != Some(OPEN_CHANNEL_REQUEST) {
+        return None;
+    }
+    // client protocol version and request type
+    r.skip_request_header()?;
+    r.skip(8)?;
+    r.u32()
+}
+
+/// the endpoints of a CreateSession response
+fn session_endpoints(r: &mut Reader) -> Option<Vec<Endpoint>> {
+    r.skip_response_header()?;
+    // session id, authentication token, timeout, nonce and certificate
+    r.node_id()?;
+    r.node_id()?;
+    r.skip(8)?;
+    r.bytes()?;
+    r.bytes()?;
+    endpoints(r)
+}
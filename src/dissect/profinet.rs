@@ -0,0 +1,356 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! PROFINET on layer 2: DCP (discovery and configuration) and the
+//! cyclic real time frames. the frame id after the ethertype tells
+//! them apart.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+
+use crate::pinfo::MacAddr;
+use crate::dissect::{Frame, be16, be32};
+
+/// ethertype of PROFINET
+pub const ETHERTYPE_PROFINET: u16 = 0x8892;
+
+/// DCP frame ids
+const FRAME_DCP_HELLO:      u16 = 0xfefc;
+const FRAME_DCP_GET_SET:    u16 = 0xfefd;
+const FRAME_DCP_IDENT_REQ:  u16 = 0xfefe;
+const FRAME_DCP_IDENT_RES:  u16 = 0xfeff;
+
+/// DCP service ids
+const SERVICE_GET:      u8 = 3;
+const SERVICE_SET:      u8 = 4;
+const SERVICE_IDENTIFY: u8 = 5;
+const SERVICE_HELLO:    u8 = 6;
+
+/// DCP service type of requests
+const TYPE_REQUEST: u8 = 0;
+
+/// size of the DCP header after the frame id
+const DCP_HEADER_LEN: usize = 10;
+
+/// DCP block options and suboptions
+const OPT_IP:               u8 = 1;
+const OPT_DEVICE:           u8 = 2;
+const OPT_CONTROL:          u8 = 5;
+const SUB_IP_PARAMETER:     u8 = 2;
+const SUB_IP_SUITE:         u8 = 3;
+const SUB_TYPE_OF_STATION:  u8 = 1;
+const SUB_NAME_OF_STATION:  u8 = 2;
+const SUB_DEVICE_ID:        u8 = 3;
+const SUB_DEVICE_ROLE:      u8 = 4;
+
+/// get the class of a real time frame id
+pub fn frame_class(id: u16) -> &'static str {
+    match id {
+        0x0000..=0x00ff => "time synchronization",
+        0x0100..=0x7fff => "RT_CLASS_3 (IRT)",
+        0x8000..=0xbfff => "RT_CLASS_1",
+        0xc000..=0xfbff => "RT_CLASS_UDP",
+        0xfc01          => "alarm high",
+        0xfe01          => "alarm low",
+        _               => "other",
+    }
+}
+
+/// get the name of a DCP service
+fn service_name(service: u8) -> &'static str {
+    match service {
+        SERVICE_GET      => "Get",
+        SERVICE_SET      => "Set",
+        SERVICE_IDENTIFY => "Identify",
+        SERVICE_HELLO    => "Hello",
+        _                => "unknown",
+    }
+}
+
+/// get the names of the bits of a device role
+fn role_names(role: u8) -> String {
+    let names = [(0x01, "IO-Device"), (0x02, "IO-Controller"),
+                 (0x04, "IO-Multidevice"), (0x08, "PN-Supervisor")];
+    names.iter()
+        .filter(|(bit, _)| role & bit != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// IP address, netmask and gateway
+fn ip_settings(data: &[u8]) -> Option<String> {
+    let ip = Ipv4Addr::from(be32(data, 0)?);
+    let mask = Ipv4Addr::from(be32(data, 4)?);
+    let gateway = Ipv4Addr::from(be32(data, 8)?);
+    Some(format!("{}/{} gw {}", ip, mask, gateway))
+}
+
+/// what a device told us about itself in DCP responses
+#[derive(Default)]
+pub struct Device {
+    pub name:           Option<String>,
+    pub station_type:   Option<String>,
+    pub ids:            Option<(u16, u16)>,
+    pub role:           Option<u8>,
+    pub ip:             Option<String>,
+}
+
+/// real time frames between two stations
+pub struct Cyclic {
+    pub frames: u64,
+    pub first:  f64,
+    pub last:   f64,
+}
+
+impl Cyclic {
+
+    /// frames per second, `None` before a second frame arrived
+    fn rate(&self) -> Option<f64> {
+        let span = self.last - self.first;
+        if self.frames < 2 || span <= 0.0 {
+            return None;
+        }
+        Some((self.frames - 1) as f64 / span)
+    }
+
+}
+
+/// what we learned from the PROFINET traffic in the capture
+#[derive(Default)]
+pub struct ProfinetInfo {
+    pub devices:    BTreeMap<MacAddr, Device>,
+    /// DCP services seen per source, service and whether it was a request
+    pub dcp:        BTreeMap<(MacAddr, u8, bool), u64>,
+    /// settings written with DCP Set, keyed by source and target
+    pub sets:       BTreeMap<(MacAddr, MacAddr), Vec<String>>,
+    /// real time frames keyed by source, destination and frame class
+    pub cyclic:     BTreeMap<(MacAddr, MacAddr, &'static str), Cyclic>,
+}
+
+impl ProfinetInfo {
+
+    /// handle the payload of a PROFINET frame
+    pub fn ethernet(&mut self, frame: &Frame, payload: &[u8]) {
+
+        let Some(id) = be16(payload, 0) else {
+            return;
+        };
+
+        match id {
+            FRAME_DCP_HELLO | FRAME_DCP_GET_SET
+            | FRAME_DCP_IDENT_REQ | FRAME_DCP_IDENT_RES => {
+                self.dcp(frame, &payload[2..]);
+            },
+            _ => {
+                let time = frame.time();
+                let key = (frame.smac, frame.dmac, frame_class(id));
+                let cyclic = self.cyclic.entry(key).or_insert(Cyclic {
+                    frames: 0,
+                    first: time,
+                    last: time,
+                });
+                cyclic.frames += 1;
+                cyclic.last = time;
+            },
+        }
+    }
+
+    /// handle a DCP PDU
+    fn dcp(&mut self, frame: &Frame, buf: &[u8]) {
+
+        let (Some(&service), Some(&kind), Some(len)) =
+            (buf.first(), buf.get(1), be16(buf, 8)) else {
+            return;
+        };
+        let request = kind == TYPE_REQUEST;
+
+        *self.dcp.entry((frame.smac, service, request)).or_insert(0) += 1;
+
+        let end = (DCP_HEADER_LEN + len as usize).min(buf.len());
+        let Some(blocks) = buf.get(DCP_HEADER_LEN..end) else {
+            return;
+        };
+
+        match (service, request) {
+            (SERVICE_SET, true) => self.set(frame, blocks),
+            // identify requests hold filters, get requests option lists
+            (SERVICE_IDENTIFY | SERVICE_GET, true) => (),
+            // responses and hellos describe the sender
+            _ => self.describe(frame.smac, blocks),
+        }
+    }
+
+    /// record what a DCP Set request writes
+    fn set(&mut self, frame: &Frame, blocks: &[u8]) {
+
+        let mut changes = Vec::new();
+
+        for (option, sub, data) in dcp_blocks(blocks) {
+            // the block qualifier comes first
+            let value = data.get(2..).unwrap_or(&[]);
+            let change = match (option, sub) {
+                (OPT_IP, SUB_IP_PARAMETER | SUB_IP_SUITE) => {
+                    format!("ip {}", ip_settings(value).unwrap_or_default())
+                },
+                (OPT_DEVICE, SUB_NAME_OF_STATION) => {
+                    format!("name of station \"{}\"", String::from_utf8_lossy(value))
+                },
+                (OPT_CONTROL, 1) => "start transaction".to_string(),
+                (OPT_CONTROL, 2) => "end transaction".to_string(),
+                (OPT_CONTROL, 3) => "signal (flash LED)".to_string(),
+                (OPT_CONTROL, 5) => "factory reset".to_string(),
+                (OPT_CONTROL, 6) => "reset to factory".to_string(),
+                _ => format!("option {}/{}", option, sub),
+            };
+            changes.push(change);
+        }
+
+        self.sets.entry((frame.smac, frame.dmac)).or_default().extend(changes);
+    }
+
+    /// add the blocks of a response or hello to the device inventory
+    fn describe(&mut self, mac: MacAddr, blocks: &[u8]) {
+
+        let device = self.devices.entry(mac).or_default();
+
+        for (option, sub, data) in dcp_blocks(blocks) {
+            // block info comes first
+            let Some(value) = data.get(2..) else {
+                continue;
+            };
+            let text = || String::from_utf8_lossy(value).trim_end_matches('\0').to_string();
+            match (option, sub) {
+                (OPT_IP, SUB_IP_PARAMETER | SUB_IP_SUITE) => {
+                    device.ip = ip_settings(value);
+                },
+                (OPT_DEVICE, SUB_TYPE_OF_STATION) => {
+                    device.station_type = Some(text());
+                },
+                (OPT_DEVICE, SUB_NAME_OF_STATION) => device.name = Some(text()),
+                (OPT_DEVICE, SUB_DEVICE_ID) => {
+                    if let (Some(vendor), Some(id)) = (be16(value, 0), be16(value, 2)) {
+                        device.ids = Some((vendor, id));
+                    }
+                },
+                (OPT_DEVICE, SUB_DEVICE_ROLE) => device.role = value.first().copied(),
+                _ => (),
+            }
+        }
+    }
+
+    /// station name of a MAC for the report, the MAC if there is none
+    fn station(&self, mac: &MacAddr) -> String {
+        match self.devices.get(mac).and_then(|d| d.name.as_ref()) {
+            Some(name) => format!("{} ({})", mac, name),
+            None => mac.to_string(),
+        }
+    }
+
+    /// write the PROFINET sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if !self.devices.is_empty() {
+
+            write!(file, "\n\n-- PROFINET Devices (DCP)\n")?;
+
+            for (mac, device) in &self.devices {
+                write!(file, "{}\n", mac)?;
+                if let Some(name) = &device.name {
+                    write!(file, "    name:     {}\n", name)?;
+                }
+                if let Some(station_type) = &device.station_type {
+                    write!(file, "    type:     {}\n", station_type)?;
+                }
+                if let Some((vendor, id)) = device.ids {
+                    write!(file, "    ids:      vendor 0x{:04x} device 0x{:04x}\n",
+                        vendor, id)?;
+                }
+                if let Some(role) = device.role {
+                    write!(file, "    role:     {}\n", role_names(role))?;
+                }
+                if let Some(ip) = &device.ip {
+                    write!(file, "    ip:       {}\n", ip)?;
+                }
+            }
+        }
+
+        if !self.dcp.is_empty() {
+
+            write!(file, "\n\n-- PROFINET DCP Services\n")?;
+
+            for ((mac, service, request), count) in &self.dcp {
+                let kind = if *request { "request" } else { "response" };
+                write!(file, "{:<40} {} {} ({}x)\n", self.station(mac),
+                    service_name(*service), kind, count)?;
+            }
+
+            for ((src, dst), changes) in &self.sets {
+                write!(file, "{} -> {}  [DCP SET]\n",
+                    self.station(src), self.station(dst))?;
+                for change in changes {
+                    write!(file, "    set: {}\n", change)?;
+                }
+            }
+        }
+
+        if !self.cyclic.is_empty() {
+
+            write!(file, "\n\n-- PROFINET RT Frames (source -> destination)\n")?;
+
+            for ((src, dst, class), cyclic) in &self.cyclic {
+                write!(file, "{} -> {}\n", self.station(src), self.station(dst))?;
+                match cyclic.rate() {
+                    Some(rate) => write!(file,
+                        "    {}: {} frames, {:.1} frames/s ({:.2} ms)\n",
+                        class, cyclic.frames, rate, 1000.0 / rate)?,
+                    None => write!(file, "    {}: {} frames\n",
+                        class, cyclic.frames)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+/// split DCP blocks into option, suboption and data. data is padded to
+/// an even length
+fn dcp_blocks(buf: &[u8]) -> Vec<(u8, u8, &[u8])> {
+
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+
+    while let (Some(&option), Some(&sub), Some(len)) =
+        (buf.get(pos), buf.get(pos+1), be16(buf, pos+2)) {
+        let len = len as usize;
+        let Some(data) = buf.get(pos+4..pos+4+len) else {
+            break;
+        };
+        blocks.push((option, sub, data));
+        pos += 4 + len + len % 2;
+    }
+
+    blocks
+}
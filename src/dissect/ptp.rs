@@ -0,0 +1,278 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! Precision Time Protocol (IEEE 1588v2), either straight on ethernet
+//! or over UDP. the grandmaster is elected like the best master clock
+//! algorithm does it, from the Announce messages.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, Frame, be16};
+
+/// ethertype of PTP
+pub const ETHERTYPE_PTP: u16 = 0x88f7;
+
+/// UDP ports of event and general messages
+pub const PTP_EVENT_PORT:   u16 = 319;
+pub const PTP_GENERAL_PORT: u16 = 320;
+
+/// size of the common header
+const HEADER_LEN: usize = 34;
+
+/// message type of Announce
+const MSG_ANNOUNCE: u8 = 0xb;
+
+/// offsets in an Announce message
+const ANNOUNCE_UTC_OFFSET:  usize = 44;
+const ANNOUNCE_PRIORITY1:   usize = 47;
+const ANNOUNCE_GM_IDENTITY: usize = 53;
+const ANNOUNCE_LEN:         usize = 64;
+
+/// identity of a clock, usually derived from a MAC
+pub type ClockIdentity = [u8; 8];
+
+/// format a clock identity like a MAC
+pub fn identity_string(id: &ClockIdentity) -> String {
+    id.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// get the name of a message type
+pub fn message_name(kind: u8) -> &'static str {
+    match kind {
+        0x0 => "Sync",
+        0x1 => "Delay_Req",
+        0x2 => "Pdelay_Req",
+        0x3 => "Pdelay_Resp",
+        0x8 => "Follow_Up",
+        0x9 => "Delay_Resp",
+        0xa => "Pdelay_Resp_Follow_Up",
+        0xb => "Announce",
+        0xc => "Signaling",
+        0xd => "Management",
+        _   => "unknown",
+    }
+}
+
+/// get the name of the time source of a grandmaster
+fn time_source_name(source: u8) -> &'static str {
+    match source {
+        0x10 => "atomic clock",
+        0x20 => "GPS",
+        0x30 => "terrestrial radio",
+        0x40 => "PTP",
+        0x50 => "NTP",
+        0x60 => "hand set",
+        0x90 => "other",
+        0xa0 => "internal oscillator",
+        _    => "unknown",
+    }
+}
+
+/// the grandmaster a clock announces
+#[derive(Clone)]
+pub struct Announce {
+    pub priority1:      u8,
+    pub class:          u8,
+    pub accuracy:       u8,
+    pub variance:       u16,
+    pub priority2:      u8,
+    pub grandmaster:    ClockIdentity,
+    pub steps_removed:  u16,
+    pub time_source:    u8,
+    pub utc_offset:     u16,
+}
+
+impl Announce {
+
+    /// the order of the best master clock algorithm, lower wins
+    fn rank(&self) -> (u8, u8, u8, u16, u8, ClockIdentity, u16) {
+        (self.priority1, self.class, self.accuracy, self.variance,
+         self.priority2, self.grandmaster, self.steps_removed)
+    }
+
+}
+
+/// parse the body of an Announce message
+fn parse_announce(buf: &[u8]) -> Option<Announce> {
+
+    if buf.len() < ANNOUNCE_LEN {
+        return None;
+    }
+
+    let mut grandmaster = ClockIdentity::default();
+    grandmaster.copy_from_slice(&buf[ANNOUNCE_GM_IDENTITY..ANNOUNCE_GM_IDENTITY+8]);
+
+    Some(Announce {
+        priority1:      buf[ANNOUNCE_PRIORITY1],
+        class:          buf[ANNOUNCE_PRIORITY1+1],
+        accuracy:       buf[ANNOUNCE_PRIORITY1+2],
+        variance:       be16(buf, ANNOUNCE_PRIORITY1+3)?,
+        priority2:      buf[ANNOUNCE_PRIORITY1+5],
+        grandmaster,
+        steps_removed:  be16(buf, ANNOUNCE_GM_IDENTITY+8)?,
+        time_source:    buf[ANNOUNCE_GM_IDENTITY+10],
+        utc_offset:     be16(buf, ANNOUNCE_UTC_OFFSET)?,
+    })
+}
+
+/// a clock as seen through the messages it sent
+#[derive(Default)]
+pub struct Clock {
+    /// MAC or IP the messages came from
+    pub sources:    BTreeSet<String>,
+    pub version:    u8,
+    pub messages:   BTreeMap<u8, u64>,
+    /// log2 of the message interval per message type
+    pub intervals:  BTreeMap<u8, i8>,
+    /// the last Announce of the clock
+    pub announce:   Option<Announce>,
+}
+
+/// what we learned from the PTP traffic in the capture
+#[derive(Default)]
+pub struct PtpInfo {
+    /// keyed by domain and clock identity
+    pub clocks: BTreeMap<(u8, ClockIdentity), Clock>,
+}
+
+impl PtpInfo {
+
+    /// handle the payload of a PTP frame
+    pub fn ethernet(&mut self, frame: &Frame, payload: &[u8]) {
+        self.message(frame.smac.to_string(), payload);
+    }
+
+    /// handle a PTP message sent over UDP
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+        self.message(flow.sip.to_string(), payload);
+    }
+
+    /// handle a PTP message, `source` is the address it came from
+    fn message(&mut self, source: String, buf: &[u8]) {
+
+        if buf.len() < HEADER_LEN {
+            return;
+        }
+
+        let kind = buf[0] & 0x0f;
+        let version = buf[1] & 0x0f;
+        let domain = buf[4];
+        let mut identity = ClockIdentity::default();
+        identity.copy_from_slice(&buf[20..28]);
+        let interval = buf[33] as i8;
+
+        // only PTPv2 has this header
+        if version != 2 {
+            return;
+        }
+
+        let clock = self.clocks.entry((domain, identity)).or_default();
+        clock.sources.insert(source);
+        clock.version = version;
+        *clock.messages.entry(kind).or_insert(0) += 1;
+        // 0x7f means the interval is not given
+        if interval != 0x7f {
+            clock.intervals.insert(kind, interval);
+        }
+
+        if kind == MSG_ANNOUNCE {
+            if let Some(announce) = parse_announce(buf) {
+                clock.announce = Some(announce);
+            }
+        }
+    }
+
+    /// the best grandmaster announced in a domain
+    fn elected(&self, domain: u8) -> Option<&Announce> {
+        self.clocks.iter()
+            .filter(|((d, _), _)| *d == domain)
+            .filter_map(|(_, clock)| clock.announce.as_ref())
+            .min_by_key(|a| a.rank())
+    }
+
+    /// write the timing section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.clocks.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- Timing (PTP)\n")?;
+
+        let domains: BTreeSet<u8> = self.clocks.keys().map(|(d, _)| *d).collect();
+
+        for domain in domains {
+
+            write!(file, "domain {}\n", domain)?;
+
+            // more than one grandmaster announced hints at a
+            // misconfiguration or a rogue master
+            let grandmasters: BTreeSet<ClockIdentity> = self.clocks.iter()
+                .filter(|((d, _), _)| *d == domain)
+                .filter_map(|(_, c)| c.announce.as_ref().map(|a| a.grandmaster))
+                .collect();
+            let flag = if grandmasters.len() > 1 { "  [COMPETING GRANDMASTERS]" } else { "" };
+
+            match self.elected(domain) {
+                Some(gm) => {
+                    write!(file, "    grandmaster: {}{}\n",
+                        identity_string(&gm.grandmaster), flag)?;
+                    write!(file, "    priority:    {}/{}\n", gm.priority1, gm.priority2)?;
+                    write!(file, "    quality:     class {} accuracy 0x{:02x} variance 0x{:04x}\n",
+                        gm.class, gm.accuracy, gm.variance)?;
+                    write!(file, "    source:      {}\n", time_source_name(gm.time_source))?;
+                    write!(file, "    utc offset:  {} s\n", gm.utc_offset)?;
+                },
+                None => write!(file, "    grandmaster: no Announce seen\n")?,
+            }
+
+            for ((_, identity), clock) in self.clocks.iter().filter(|((d, _), _)| *d == domain) {
+
+                let sources = clock.sources.iter().cloned().collect::<Vec<_>>().join(", ");
+                write!(file, "    clock {} ({})\n", identity_string(identity), sources)?;
+
+                let messages = clock.messages.iter()
+                    .map(|(kind, count)| format!("{} {}x", message_name(*kind), count))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(file, "        messages:  {}\n", messages)?;
+
+                for (kind, interval) in &clock.intervals {
+                    write!(file, "        interval:  {} every {} s\n",
+                        message_name(*kind), 2f64.powi(*interval as i32))?;
+                }
+
+                if let Some(announce) = &clock.announce {
+                    write!(file, "        announces: {} ({} steps removed)\n",
+                        identity_string(&announce.grandmaster), announce.steps_removed)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,578 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! TCP stream reassembly. segments are put in order by their sequence
+//! numbers per direction, retransmitted and overlapping bytes are only
+//! delivered once. a consumer gets the ordered data, is told about the
+//! bytes missing from a stream and about its end.
+//!
+//! segments ahead of the stream are held back until the hole before
+//! them is filled. if too much is held back, or the stream ends, the
+//! hole is given up as a gap. segments arriving after a direction
+//! ended count as retransmissions until a new SYN reopens it.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::dissect::Flow;
+
+/// TCP flags
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+
+/// bytes held back per direction before a hole becomes a gap
+const MAX_PENDING: usize = 1 << 20;
+
+/// directions listed in the report
+const MAX_LISTED: usize = 10;
+
+/// a TCP connection from the sender's point of view
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// what the reassembly needs from a TCP segment
+pub struct Segment<'a> {
+    pub seq:        u32,
+    pub flags:      u8,
+    pub payload:    &'a [u8],
+}
+
+/// gets the ordered data of each direction of a TCP connection
+pub trait StreamConsumer {
+
+    /// the next bytes of the direction `flow`
+    fn data(&mut self, flow: &Flow, data: &[u8]);
+
+    /// `len` bytes are missing before the next data
+    fn gap(&mut self, _flow: &Flow, _len: u32) {}
+
+    /// the direction ended with FIN or RST, or the capture ended
+    fn end(&mut self, _flow: &Flow) {}
+
+}
+
+/// what happened to the segments of a direction
+#[derive(Default, Clone, Copy)]
+pub struct Stats {
+    pub segments:           u64,
+    /// bytes delivered in order
+    pub bytes:              u64,
+    /// segments with nothing new
+    pub retransmissions:    u64,
+    /// segments partly covering delivered bytes
+    pub overlaps:           u64,
+    pub out_of_order:       u64,
+    pub gaps:               u64,
+    pub gap_bytes:          u64,
+}
+
+impl Stats {
+
+    fn add(&mut self, other: &Stats) {
+        self.segments += other.segments;
+        self.bytes += other.bytes;
+        self.retransmissions += other.retransmissions;
+        self.overlaps += other.overlaps;
+        self.out_of_order += other.out_of_order;
+        self.gaps += other.gaps;
+        self.gap_bytes += other.gap_bytes;
+    }
+
+    fn troubled(&self) -> bool {
+        self.retransmissions + self.overlaps + self.out_of_order + self.gaps > 0
+    }
+
+}
+
+/// one direction of a connection
+struct HalfStream {
+    /// the flow of the latest segment, handed to the consumer
+    flow:           Flow,
+    /// the sequence number of the next byte to deliver
+    next:           Option<u32>,
+    /// segments ahead of `next`
+    pending:        Vec<(u32, Vec<u8>)>,
+    pending_bytes:  usize,
+    /// the sequence number of the FIN
+    fin:            Option<u32>,
+    stats:          Stats,
+}
+
+impl HalfStream {
+
+    fn new(flow: Flow) -> Self {
+        HalfStream {
+            flow,
+            next:           None,
+            pending:        Vec::new(),
+            pending_bytes:  0,
+            fin:            None,
+            stats:          Stats::default(),
+        }
+    }
+
+    /// hand the part of `data` at `seq` that was not delivered yet to
+    /// the consumer
+    fn deliver(&mut self, seq: u32, data: &[u8], consumer: &mut dyn StreamConsumer) {
+
+        let next = self.next.unwrap_or(seq);
+        let skip = next.wrapping_sub(seq) as usize;
+
+        if skip >= data.len() {
+            self.stats.retransmissions += 1;
+            return;
+        }
+        if skip > 0 {
+            self.stats.overlaps += 1;
+        }
+
+        let data = &data[skip..];
+        consumer.data(&self.flow, data);
+        self.next = Some(next.wrapping_add(data.len() as u32));
+        self.stats.bytes += data.len() as u64;
+    }
+
+    /// deliver the held back segments that are due now
+    fn drain(&mut self, consumer: &mut dyn StreamConsumer) {
+
+        let Some(mut next) = self.next else {
+            return;
+        };
+
+        // segments starting at or before the next byte, earliest first
+        while let Some(idx) = self.pending.iter()
+            .enumerate()
+            .filter(|(_, (seq, _))| seq.wrapping_sub(next) as i32 <= 0)
+            .min_by_key(|(_, (seq, _))| seq.wrapping_sub(next) as i32)
+            .map(|(idx, _)| idx) {
+
+            let (seq, data) = self.pending.swap_remove(idx);
+            self.pending_bytes -= data.len();
+            self.deliver(seq, &data, consumer);
+            next = self.next.unwrap_or(next);
+        }
+    }
+
+    /// give up the hole before the earliest held back segment
+    fn skip_gap(&mut self, consumer: &mut dyn StreamConsumer) -> bool {
+
+        let Some(next) = self.next else {
+            return false;
+        };
+        let Some(seq) = self.pending.iter()
+            .map(|(seq, _)| *seq)
+            .min_by_key(|seq| seq.wrapping_sub(next) as i32) else {
+            return false;
+        };
+
+        let len = seq.wrapping_sub(next);
+        consumer.gap(&self.flow, len);
+        self.stats.gaps += 1;
+        self.stats.gap_bytes += len as u64;
+        self.next = Some(seq);
+        self.drain(consumer);
+        true
+    }
+
+    /// deliver everything held back, holes become gaps
+    fn flush(&mut self, consumer: &mut dyn StreamConsumer) {
+        while self.skip_gap(consumer) {}
+    }
+
+}
+
+/// reassembles the TCP streams of the capture
+#[derive(Default)]
+pub struct Reassembler {
+    halves:         HashMap<ConnKey, HalfStream>,
+    /// directions that ended, until a new SYN reopens them
+    closed:         HashSet<ConnKey>,
+    /// all directions that ended
+    pub total:      Stats,
+    pub streams:    u64,
+    /// directions that needed more than putting segments in a row
+    pub troubled:   BTreeMap<ConnKey, Stats>,
+}
+
+impl Reassembler {
+
+    /// handle a TCP segment, ordered data goes to `consumer`
+    pub fn segment(&mut self, flow: &Flow, segment: &Segment,
+        consumer: &mut dyn StreamConsumer) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        let mut seq = segment.seq;
+        let payload = segment.payload;
+
+        // a SYN on a direction we know starts a new connection
+        if segment.flags & TCP_SYN != 0 {
+            if self.halves.contains_key(&key) {
+                self.close(key, consumer);
+            }
+            self.closed.remove(&key);
+        }
+
+        // segments after the end of a direction, the final ACK or a
+        // retransmission, don't start a new stream
+        if self.closed.contains(&key) {
+            self.total.segments += 1;
+            if !payload.is_empty() || segment.flags & TCP_FIN != 0 {
+                self.total.retransmissions += 1;
+                let stats = self.troubled.entry(key).or_default();
+                stats.segments += 1;
+                stats.retransmissions += 1;
+            }
+            return;
+        }
+
+        // nor does a bare ACK, FIN or RST on a direction we don't know
+        if !self.halves.contains_key(&key) && payload.is_empty()
+            && segment.flags & TCP_SYN == 0 {
+            self.total.segments += 1;
+            return;
+        }
+
+        let half = self.halves.entry(key).or_insert_with(|| HalfStream::new(*flow));
+        half.flow = *flow;
+        half.stats.segments += 1;
+
+        // the SYN takes up a sequence number
+        if segment.flags & TCP_SYN != 0 {
+            seq = seq.wrapping_add(1);
+            half.next = Some(seq);
+        }
+
+        // without the handshake the first data starts the stream
+        if half.next.is_none() && !payload.is_empty() {
+            half.next = Some(seq);
+        }
+
+        if !payload.is_empty() {
+            let next = half.next.unwrap_or(seq);
+            if seq.wrapping_sub(next) as i32 > 0 {
+                // ahead of the stream, hold it back
+                half.stats.out_of_order += 1;
+                if half.pending.iter().any(|(s, d)| *s == seq && d.len() >= payload.len()) {
+                    half.stats.retransmissions += 1;
+                } else {
+                    half.pending.push((seq, payload.to_vec()));
+                    half.pending_bytes += payload.len();
+                }
+            } else {
+                half.deliver(seq, payload, consumer);
+            }
+            half.drain(consumer);
+
+            while half.pending_bytes > MAX_PENDING && half.skip_gap(consumer) {}
+        }
+
+        if segment.flags & TCP_FIN != 0 {
+            half.fin = Some(seq.wrapping_add(payload.len() as u32));
+        }
+
+        let finished = half.fin.is_some() && half.fin == half.next;
+        if finished || segment.flags & TCP_RST != 0 {
+            self.close(key, consumer);
+        }
+    }
+
+    /// end a direction, what is held back is delivered behind gaps
+    fn close(&mut self, key: ConnKey, consumer: &mut dyn StreamConsumer) {
+
+        let Some(mut half) = self.halves.remove(&key) else {
+            return;
+        };
+
+        half.flush(consumer);
+        consumer.end(&half.flow);
+        self.closed.insert(key);
+
+        self.streams += 1;
+        self.total.add(&half.stats);
+        if half.stats.troubled() {
+            self.troubled.entry(key).or_default().add(&half.stats);
+        }
+    }
+
+    /// end all directions still open at the end of the capture
+    pub fn finish(&mut self, consumer: &mut dyn StreamConsumer) {
+        let keys: Vec<ConnKey> = self.halves.keys().copied().collect();
+        for key in keys {
+            self.close(key, consumer);
+        }
+    }
+
+    /// write the reassembly section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.streams == 0 {
+            return Ok(());
+        }
+
+        let t = &self.total;
+
+        write!(file, "\n\n-- TCP Reassembly\n")?;
+        write!(file, "directions:      {}\n", self.streams)?;
+        write!(file, "segments:        {}\n", t.segments)?;
+        write!(file, "bytes:           {}\n", t.bytes)?;
+        write!(file, "retransmissions: {}\n", t.retransmissions)?;
+        write!(file, "overlaps:        {}\n", t.overlaps)?;
+        write!(file, "out of order:    {}\n", t.out_of_order)?;
+        write!(file, "gaps:            {} ({} bytes missing)\n", t.gaps, t.gap_bytes)?;
+
+        if self.troubled.is_empty() {
+            return Ok(());
+        }
+
+        // the directions with the most missing and repeated data
+        let mut troubled: Vec<_> = self.troubled.iter().collect();
+        troubled.sort_by_key(|(_, s)| std::cmp::Reverse((s.gap_bytes, s.retransmissions)));
+
+        write!(file, "most affected directions:\n")?;
+        for ((sip, sport, dip, dport), s) in troubled.into_iter().take(MAX_LISTED) {
+            write!(file, "    {}:{} -> {}:{}  {} retransmissions, {} overlaps, \
+                {} out of order, {} gaps ({} bytes)\n",
+                sip, sport, dip, dport, s.retransmissions, s.overlaps,
+                s.out_of_order, s.gaps, s.gap_bytes)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::pinfo::MacAddr;
+
+    /// what a consumer was given
+    #[derive(Default)]
+    struct Record {
+        data:   Vec<u8>,
+        gaps:   Vec<u32>,
+        ends:   usize,
+    }
+
+    impl StreamConsumer for Record {
+        fn data(&mut self, _flow: &Flow, data: &[u8]) {
+            self.data.extend_from_slice(data);
+        }
+        fn gap(&mut self, _flow: &Flow, len: u32) {
+            self.gaps.push(len);
+        }
+        fn end(&mut self, _flow: &Flow) {
+            self.ends += 1;
+        }
+    }
+
+    fn flow() -> Flow {
+        Flow {
+            smac:   MacAddr::new(&[0, 1, 2, 3, 4, 5]),
+            dmac:   MacAddr::new(&[6, 7, 8, 9, 10, 11]),
+            sip:    Ipv4Addr::new(10, 0, 0, 1),
+            dip:    Ipv4Addr::new(10, 0, 0, 2),
+            sport:  40000,
+            dport:  80,
+            ts:     0,
+            usec:   0,
+        }
+    }
+
+    /// feed segments given as (seq, flags, payload)
+    fn run(segments: &[(u32, u8, &[u8])]) -> (Reassembler, Record) {
+        let mut r = Reassembler::default();
+        let mut record = Record::default();
+        for (seq, flags, payload) in segments {
+            let segment = Segment { seq: *seq, flags: *flags, payload };
+            r.segment(&flow(), &segment, &mut record);
+        }
+        (r, record)
+    }
+
+    /// the stats of the single direction once it ended
+    fn stats(r: &Reassembler) -> Stats {
+        r.troubled.values().next().copied().unwrap_or(r.total)
+    }
+
+    #[test]
+    fn in_order() {
+        let (r, record) = run(&[
+            (99, TCP_SYN, b""),
+            (100, 0, b"hello "),
+            (106, TCP_FIN, b"world"),
+        ]);
+        assert_eq!(record.data, b"hello world");
+        assert_eq!(record.ends, 1);
+        assert!(record.gaps.is_empty());
+        assert_eq!(r.streams, 1);
+        assert!(r.troubled.is_empty());
+    }
+
+    #[test]
+    fn retransmission() {
+        let (mut r, mut record) = run(&[
+            (100, 0, b"hello "),
+            (100, 0, b"hello "),
+            (106, 0, b"world"),
+        ]);
+        r.finish(&mut record);
+        assert_eq!(record.data, b"hello world");
+        assert_eq!(stats(&r).retransmissions, 1);
+        assert_eq!(stats(&r).overlaps, 0);
+    }
+
+    #[test]
+    fn overlap() {
+        let (mut r, mut record) = run(&[
+            (100, 0, b"hello "),
+            (103, 0, b"lo world"),
+        ]);
+        r.finish(&mut record);
+        assert_eq!(record.data, b"hello world");
+        assert_eq!(stats(&r).overlaps, 1);
+        assert_eq!(stats(&r).bytes, 11);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut r, mut record) = run(&[
+            (99, TCP_SYN, b""),
+            (106, 0, b"world"),
+            (111, 0, b"!"),
+            (100, 0, b"hello "),
+        ]);
+        assert_eq!(record.data, b"hello world!");
+        r.finish(&mut record);
+        assert!(record.gaps.is_empty());
+        assert_eq!(stats(&r).out_of_order, 2);
+    }
+
+    #[test]
+    fn out_of_order_overlap() {
+        // a held back segment partly covered by a later one
+        let (mut r, mut record) = run(&[
+            (99, TCP_SYN, b""),
+            (104, 0, b"o world"),
+            (100, 0, b"hello "),
+        ]);
+        r.finish(&mut record);
+        assert_eq!(record.data, b"hello world");
+        assert_eq!(stats(&r).overlaps, 1);
+    }
+
+    #[test]
+    fn gap_at_end() {
+        let (r, record) = run(&[
+            (99, TCP_SYN, b""),
+            (100, 0, b"hello "),
+            (110, TCP_FIN, b"d"),
+        ]);
+        // the FIN is not reached, the stream stays open
+        assert_eq!(record.data, b"hello ");
+        assert_eq!(record.ends, 0);
+        drop(r);
+
+        let (mut r, mut record) = run(&[
+            (99, TCP_SYN, b""),
+            (100, 0, b"hello "),
+            (110, 0, b"d"),
+        ]);
+        r.finish(&mut record);
+        assert_eq!(record.data, b"hello d");
+        assert_eq!(record.gaps, vec![4]);
+        assert_eq!(record.ends, 1);
+        assert_eq!(stats(&r).gap_bytes, 4);
+    }
+
+    #[test]
+    fn gap_when_too_much_is_held_back() {
+        let big = vec![0x41; MAX_PENDING + 1];
+        let (r, record) = run(&[
+            (99, TCP_SYN, b""),
+            (100, 0, b"a"),
+            (200, 0, &big),
+        ]);
+        assert_eq!(record.gaps, vec![99]);
+        assert_eq!(record.data.len(), 1 + big.len());
+        assert_eq!(r.halves.len(), 1);
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let (r, record) = run(&[
+            (u32::MAX - 3, TCP_SYN, b""),
+            (2, 0, b"world"),
+            (u32::MAX - 2, 0, b"hello"),
+            (7, TCP_FIN, b""),
+        ]);
+        assert_eq!(record.data, b"helloworld");
+        assert!(record.gaps.is_empty());
+        assert_eq!(record.ends, 1);
+        assert_eq!(r.total.out_of_order, 1);
+    }
+
+    #[test]
+    fn reset_ends_the_stream() {
+        let (r, record) = run(&[
+            (100, 0, b"hello"),
+            (105, TCP_RST, b""),
+        ]);
+        assert_eq!(record.data, b"hello");
+        assert_eq!(record.ends, 1);
+        assert!(r.halves.is_empty());
+    }
+
+    #[test]
+    fn late_segments_after_fin() {
+        let (mut r, mut record) = run(&[
+            (99, TCP_SYN, b""),
+            (100, 0, b"hello "),
+            (106, TCP_FIN, b"world"),
+            // the final ACK, then the last segment once more
+            (112, 0, b""),
+            (106, TCP_FIN, b"world"),
+        ]);
+        r.finish(&mut record);
+        assert_eq!(record.data, b"hello world");
+        assert_eq!(record.ends, 1);
+        assert_eq!(r.streams, 1);
+        assert_eq!(r.total.segments, 5);
+        assert_eq!(r.total.retransmissions, 1);
+        assert_eq!(stats(&r).retransmissions, 1);
+    }
+
+    #[test]
+    fn syn_reopens_a_closed_direction() {
+        let (r, record) = run(&[
+            (100, TCP_RST, b"a"),
+            (500, TCP_SYN, b""),
+            (501, TCP_FIN, b"b"),
+        ]);
+        assert_eq!(record.data, b"ab");
+        assert_eq!(record.ends, 2);
+        assert_eq!(r.streams, 2);
+    }
+
+}
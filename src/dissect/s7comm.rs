@@ -0,0 +1,409 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! Siemens S7comm and S7comm-plus. both run over ISO-on-TCP: TPKT
+//! (RFC 1006) carries COTP (ISO 8073), which carries the S7 PDUs.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, be16};
+
+/// ISO-TSAP port
+pub const ISO_TSAP_PORT: u16 = 102;
+
+/// TPKT version
+const TPKT_VERSION: u8 = 3;
+
+/// COTP PDU types
+const COTP_CR: u8 = 0xe0;
+const COTP_DT: u8 = 0xf0;
+
+/// COTP parameter holding the called TSAP
+const COTP_DST_TSAP: u8 = 0xc2;
+
+/// protocol ids of S7comm and S7comm-plus
+const S7_PROTO: u8 = 0x32;
+const S7PLUS_PROTO: u8 = 0x72;
+
+/// S7comm message types (ROSCTR)
+const ROSCTR_JOB: u8 = 1;
+const ROSCTR_USERDATA: u8 = 7;
+
+/// S7comm job functions
+const FN_READ_VAR:          u8 = 0x04;
+const FN_WRITE_VAR:         u8 = 0x05;
+const FN_REQUEST_DOWNLOAD:  u8 = 0x1a;
+const FN_DOWNLOAD_BLOCK:    u8 = 0x1b;
+const FN_DOWNLOAD_ENDED:    u8 = 0x1c;
+const FN_START_UPLOAD:      u8 = 0x1d;
+const FN_UPLOAD:            u8 = 0x1e;
+const FN_END_UPLOAD:        u8 = 0x1f;
+const FN_PLC_CONTROL:       u8 = 0x28;
+const FN_PLC_STOP:          u8 = 0x29;
+const FN_SETUP_COMM:        u8 = 0xf0;
+
+/// S7comm-plus opcode of requests
+const S7PLUS_REQUEST: u8 = 0x31;
+
+/// get the name of a S7comm job function
+fn job_name(function: u8) -> String {
+    match function {
+        FN_READ_VAR         => "Read Var".to_string(),
+        FN_WRITE_VAR        => "Write Var".to_string(),
+        FN_REQUEST_DOWNLOAD => "Request Download".to_string(),
+        FN_DOWNLOAD_BLOCK   => "Download Block".to_string(),
+        FN_DOWNLOAD_ENDED   => "Download Ended".to_string(),
+        FN_START_UPLOAD     => "Start Upload".to_string(),
+        FN_UPLOAD           => "Upload".to_string(),
+        FN_END_UPLOAD       => "End Upload".to_string(),
+        FN_PLC_CONTROL      => "PLC Control".to_string(),
+        FN_PLC_STOP         => "PLC Stop".to_string(),
+        FN_SETUP_COMM       => "Setup Communication".to_string(),
+        _                   => format!("Job 0x{:02x}", function),
+    }
+}
+
+/// get the name of a userdata function group
+fn userdata_name(group: u8) -> String {
+    match group {
+        1   => "Userdata Programmer Commands".to_string(),
+        2   => "Userdata Cyclic Data".to_string(),
+        3   => "Userdata Block Functions".to_string(),
+        4   => "Userdata CPU Functions".to_string(),
+        5   => "Userdata Security".to_string(),
+        6   => "Userdata BSEND/BRECV".to_string(),
+        7   => "Userdata Time Functions".to_string(),
+        15  => "Userdata NC Programming".to_string(),
+        _   => format!("Userdata Group {}", group),
+    }
+}
+
+/// get the name of a S7comm-plus function
+fn plus_function_name(function: u16) -> String {
+    match function {
+        0x04bb => "Explore".to_string(),
+        0x04ca => "CreateObject".to_string(),
+        0x04d4 => "DeleteObject".to_string(),
+        0x04f2 => "SetVariable".to_string(),
+        0x04fc => "GetVariable".to_string(),
+        0x0524 => "AddLink".to_string(),
+        0x0542 => "SetMultiVariables".to_string(),
+        0x054c => "GetMultiVariables".to_string(),
+        0x0556 => "BeginSequence".to_string(),
+        0x0560 => "EndSequence".to_string(),
+        0x056b => "Invoke".to_string(),
+        0x0586 => "SetVarSubStreamed".to_string(),
+        0x0588 => "GetVarSubStreamed".to_string(),
+        0x058e => "GetVariablesAddress".to_string(),
+        0x0596 => "Abort".to_string(),
+        0x05b3 => "InitSsl".to_string(),
+        _      => format!("Function 0x{:04x}", function),
+    }
+}
+
+/// turn a block file name like `_0800001P` into `OB1`
+fn block_name(filename: &str) -> String {
+
+    let (Some(kind), Some(number)) = (filename.get(1..3), filename.get(3..8))
+    else {
+        return filename.to_string();
+    };
+
+    let kind = match kind {
+        "08" => "OB",
+        "0A" => "DB",
+        "0B" => "SDB",
+        "0C" => "FC",
+        "0D" => "SFC",
+        "0E" => "FB",
+        "0F" => "SFB",
+        _    => return filename.to_string(),
+    };
+
+    match number.parse::<u32>() {
+        Ok(n) => format!("{}{}", kind, n),
+        Err(_) => filename.to_string(),
+    }
+}
+
+/// read a string with a one byte length prefix at `offset`
+fn short_string(buf: &[u8], offset: usize) -> Option<String> {
+    let len = *buf.get(offset)? as usize;
+    let bytes = buf.get(offset+1..offset+1+len)?;
+    Some(String::from_utf8_lossy(bytes).to_string())
+}
+
+/// find the called TSAP in the parameters of a connection request
+fn cr_tsap(buf: &[u8]) -> Option<u16> {
+
+    // length indicator, type, dst ref, src ref, class
+    let mut pos = 7;
+    while pos + 2 <= buf.len() {
+        let (code, len) = (buf[pos], buf[pos+1] as usize);
+        if code == COTP_DST_TSAP && len == 2 {
+            return be16(buf, pos+2);
+        }
+        pos += 2 + len;
+    }
+
+    None
+}
+
+/// what a client asked of a PLC
+pub enum Operation {
+    /// a S7comm job, with the block it transfers if any
+    Job { function: u8, block: Option<String>, service: Option<String> },
+    /// a S7comm userdata request of a function group
+    Userdata(u8),
+    /// a S7comm-plus request
+    Plus { version: u8, function: u16 },
+}
+
+/// parse the S7 PDU carried by a COTP data TPDU
+pub fn parse_s7(buf: &[u8]) -> Option<Operation> {
+
+    match *buf.first()? {
+        S7_PROTO => {
+            let rosctr = *buf.get(1)?;
+            let param_len = be16(buf, 6)? as usize;
+            let param = buf.get(10..10+param_len)?;
+            match rosctr {
+                ROSCTR_JOB => {
+                    let function = *param.first()?;
+                    let (block, service) = match function {
+                        FN_REQUEST_DOWNLOAD | FN_START_UPLOAD => {
+                            (short_string(param, 8).map(|f| block_name(&f)), None)
+                        },
+                        FN_PLC_CONTROL => {
+                            // parameter block, then the PI service name
+                            let block_len = be16(param, 8)? as usize;
+                            (None, short_string(param, 10 + block_len))
+                        },
+                        FN_PLC_STOP => (None, short_string(param, 6)),
+                        _ => (None, None),
+                    };
+                    Some(Operation::Job { function, block, service })
+                },
+                // header, length, method, type and group
+                ROSCTR_USERDATA if param.get(..3)? == [0x00, 0x01, 0x12] => {
+                    let type_group = *param.get(5)?;
+                    // requests only, type 4
+                    if type_group >> 4 != 4 {
+                        return None;
+                    }
+                    Some(Operation::Userdata(type_group & 0x0f))
+                },
+                _ => None,
+            }
+        },
+        S7PLUS_PROTO => {
+            let version = *buf.get(1)?;
+            // V3 puts an integrity digest in front of the data
+            let mut pos = 4;
+            if version == 3 {
+                pos += 1 + *buf.get(pos)? as usize;
+            }
+            if *buf.get(pos)? != S7PLUS_REQUEST {
+                return None;
+            }
+            let function = be16(buf, pos+3)?;
+            Some(Operation::Plus { version, function })
+        },
+        _ => None,
+    }
+}
+
+/// everything a client did on a PLC
+#[derive(Default)]
+pub struct Session {
+    /// called TSAPs of the connection requests
+    pub tsaps:      BTreeSet<u16>,
+    pub operations: BTreeMap<String, u64>,
+    pub downloads:  BTreeSet<String>,
+    pub uploads:    BTreeSet<String>,
+    /// PI services started with PLC control
+    pub services:   BTreeSet<String>,
+    pub stops:      u64,
+    /// S7comm-plus protocol versions
+    pub plus:       BTreeSet<u8>,
+}
+
+/// what we learned from the S7 traffic in the capture
+#[derive(Default)]
+pub struct S7Info {
+    /// sessions keyed by PLC and client address
+    pub plcs:   BTreeMap<Ipv4Addr, BTreeMap<Ipv4Addr, Session>>,
+}
+
+impl S7Info {
+
+    /// handle whole TPKTs of a TCP direction, there may be several
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        // only requests tell us what the client did
+        if flow.dport != ISO_TSAP_PORT {
+            return;
+        }
+
+        let mut pos = 0;
+
+        while payload.len() >= pos + 4 && payload[pos] == TPKT_VERSION {
+
+            let Some(len) = be16(payload, pos+2) else {
+                break;
+            };
+            let len = len as usize;
+            let Some(tpkt) = payload.get(pos+4..pos+len) else {
+                break;
+            };
+            if len <= 4 {
+                break;
+            }
+
+            self.cotp(flow, tpkt);
+            pos += len;
+        }
+    }
+
+    /// handle a COTP TPDU
+    fn cotp(&mut self, flow: &Flow, buf: &[u8]) {
+
+        // the length indicator doesn't count itself
+        let Some(&li) = buf.first() else {
+            return;
+        };
+        let Some(&kind) = buf.get(1) else {
+            return;
+        };
+        let Some(user) = buf.get(1 + li as usize..) else {
+            return;
+        };
+
+        match kind & 0xf0 {
+            COTP_CR => {
+                let session = self.session(flow);
+                if let Some(tsap) = cr_tsap(&buf[..1 + li as usize]) {
+                    session.tsaps.insert(tsap);
+                }
+            },
+            COTP_DT => {
+                let Some(op) = parse_s7(user) else {
+                    return;
+                };
+                self.operation(flow, op);
+            },
+            _ => (),
+        }
+    }
+
+    fn session(&mut self, flow: &Flow) -> &mut Session {
+        self.plcs.entry(flow.dip).or_default()
+            .entry(flow.sip).or_default()
+    }
+
+    /// add an operation to the session of the client
+    fn operation(&mut self, flow: &Flow, op: Operation) {
+
+        let session = self.session(flow);
+
+        let name = match op {
+            Operation::Job { function, block, service } => {
+                match function {
+                    FN_REQUEST_DOWNLOAD => session.downloads.extend(block),
+                    FN_START_UPLOAD => session.uploads.extend(block),
+                    FN_PLC_STOP => session.stops += 1,
+                    FN_PLC_CONTROL => session.services.extend(service),
+                    _ => (),
+                }
+                job_name(function)
+            },
+            Operation::Userdata(group) => userdata_name(group),
+            Operation::Plus { version, function } => {
+                session.plus.insert(version);
+                format!("S7comm-plus {}", plus_function_name(function))
+            },
+        };
+
+        *session.operations.entry(name).or_insert(0) += 1;
+    }
+
+    /// write the S7 section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.plcs.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- S7comm (PLC <- client)\n")?;
+
+        for (plc, clients) in &self.plcs {
+
+            write!(file, "{}\n", plc)?;
+
+            for (client, session) in clients {
+
+                let mut flags = String::new();
+                if !session.downloads.is_empty() {
+                    flags.push_str("  [DOWNLOAD]");
+                }
+                if session.stops > 0 {
+                    flags.push_str("  [CPU STOP]");
+                }
+                write!(file, "    <- {}{}\n", client, flags)?;
+
+                // the second byte of the TSAP holds rack and slot
+                for tsap in &session.tsaps {
+                    write!(file, "        tsap:      0x{:04x} (rack {} slot {})\n",
+                        tsap, (tsap & 0xff) >> 5, tsap & 0x1f)?;
+                }
+                if !session.plus.is_empty() {
+                    let versions = session.plus.iter()
+                        .map(|v| format!("S7comm-plus V{}", v))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(file, "        protocol:  {}\n", versions)?;
+                }
+                for (name, count) in &session.operations {
+                    write!(file, "        operation: {} ({}x)\n", name, count)?;
+                }
+                if !session.downloads.is_empty() {
+                    write!(file, "        download:  {}\n",
+                        session.downloads.iter().cloned()
+                            .collect::<Vec<_>>().join(", "))?;
+                }
+                if !session.uploads.is_empty() {
+                    write!(file, "        upload:    {}\n",
+                        session.uploads.iter().cloned()
+                            .collect::<Vec<_>>().join(", "))?;
+                }
+                for service in &session.services {
+                    write!(file, "        control:   {}\n", service)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+}
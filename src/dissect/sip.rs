@@ -0,0 +1,406 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! SIP calls and their RTP streams. RTP has no port of its own, the
+//! SDP bodies of the SIP messages tell where media goes, so streams are
+//! only picked up after the call set up was seen.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+
+use crate::dissect::{Flow, be16, be32, http};
+
+/// SIP port for UDP and TCP
+pub const SIP_PORT: u16 = 5060;
+
+/// RTP version 2
+const RTP_VERSION: u8 = 2;
+
+/// size of the fixed RTP header
+const RTP_HEADER_LEN: usize = 12;
+
+/// clock rate of audio codecs without an rtpmap
+const DEFAULT_CLOCK_RATE: u32 = 8000;
+
+/// get the name of a static RTP payload type
+fn payload_name(pt: u8) -> Option<&'static str> {
+    match pt {
+        0  => Some("PCMU"),
+        3  => Some("GSM"),
+        4  => Some("G723"),
+        8  => Some("PCMA"),
+        9  => Some("G722"),
+        18 => Some("G729"),
+        _  => None,
+    }
+}
+
+/// a decoded SIP message
+pub struct SipMessage {
+    /// the method of a request, `None` for responses
+    pub method:     Option<String>,
+    pub status:     Option<u16>,
+    pub headers:    Vec<(String, String)>,
+    pub body:       String,
+}
+
+impl SipMessage {
+
+    /// get a header by its long or its compact name
+    fn header(&self, name: &str, compact: &str) -> Option<&str> {
+        http::header(&self.headers, name).or_else(|| http::header(&self.headers, compact))
+    }
+
+}
+
+/// parse a SIP request or response
+pub fn parse(buf: &[u8]) -> Option<SipMessage> {
+
+    let text = String::from_utf8_lossy(buf);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let mut lines = head.split("\r\n");
+
+    let start = lines.next()?;
+    let mut parts = start.splitn(3, ' ');
+    let first = parts.next()?;
+    let second = parts.next()?;
+    let third = parts.next().unwrap_or("");
+
+    let (method, status) = if first == "SIP/2.0" {
+        (None, Some(second.parse::<u16>().ok()?))
+    } else if third == "SIP/2.0" && first.bytes().all(|b| b.is_ascii_uppercase()) {
+        (Some(first.to_string()), None)
+    } else {
+        return None;
+    };
+
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Some(SipMessage { method, status, headers, body: body.to_string() })
+}
+
+/// a media stream offered in SDP
+pub struct Media {
+    pub addr:   Ipv4Addr,
+    pub port:   u16,
+    pub kind:   String,
+    /// clock rates and encodings from the rtpmap attributes
+    pub rtpmap: BTreeMap<u8, (String, u32)>,
+}
+
+/// get the media streams of an SDP body
+pub fn parse_sdp(sdp: &str) -> Vec<Media> {
+
+    let mut media: Vec<Media> = Vec::new();
+    let mut session_addr = None;
+
+    for line in sdp.lines() {
+        let line = line.trim_end();
+        if let Some(c) = line.strip_prefix("c=IN IP4 ") {
+            // a connection line after a media line only holds for it
+            let addr = c.split('/').next().and_then(|a| a.parse().ok());
+            match media.last_mut() {
+                Some(m) => m.addr = addr.unwrap_or(m.addr),
+                None => session_addr = addr,
+            }
+        } else if let Some(m) = line.strip_prefix("m=") {
+            let mut fields = m.split_whitespace();
+            let kind = fields.next().unwrap_or("").to_string();
+            let Some(port) = fields.next().and_then(|p| p.parse().ok()) else {
+                continue;
+            };
+            media.push(Media {
+                addr: session_addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                port,
+                kind,
+                rtpmap: BTreeMap::new(),
+            });
+        } else if let Some(map) = line.strip_prefix("a=rtpmap:") {
+            // a=rtpmap:101 telephone-event/8000
+            let Some((pt, encoding)) = map.split_once(' ') else {
+                continue;
+            };
+            let mut enc = encoding.split('/');
+            let name = enc.next().unwrap_or("").to_string();
+            let rate = enc.next().and_then(|r| r.parse().ok()).unwrap_or(DEFAULT_CLOCK_RATE);
+            if let (Some(m), Ok(pt)) = (media.last_mut(), pt.parse()) {
+                m.rtpmap.insert(pt, (name, rate));
+            }
+        }
+    }
+
+    media
+}
+
+/// an RTP stream, loss and jitter as in RFC 3550
+pub struct RtpStream {
+    pub payload_type:   u8,
+    pub packets:        u64,
+    base_seq:           u32,
+    /// highest sequence number, extended by the wraps
+    max_seq:            u32,
+    last_transit:       Option<f64>,
+    /// interarrival jitter in timestamp units
+    jitter:             f64,
+    clock_rate:         u32,
+}
+
+impl RtpStream {
+
+    fn update(&mut self, seq: u16, timestamp: u32, arrival: f64) {
+
+        // sequence numbers wrap, keep the number of wraps in the
+        // upper half
+        let cycles = self.max_seq & 0xffff0000;
+        let last = (self.max_seq & 0xffff) as u16;
+        let delta = seq.wrapping_sub(last);
+        if delta != 0 && delta < 0x8000 {
+            let wrapped = if seq < last { 0x10000 } else { 0 };
+            self.max_seq = (cycles + wrapped) | seq as u32;
+        }
+
+        // transit time in timestamp units, only the differences count
+        let transit = arrival * self.clock_rate as f64 - timestamp as f64;
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+        self.packets += 1;
+    }
+
+    /// expected and lost packets
+    pub fn loss(&self) -> (u64, u64) {
+        let expected = (self.max_seq - self.base_seq) as u64 + 1;
+        (expected, expected.saturating_sub(self.packets))
+    }
+
+    /// jitter in milliseconds
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter / self.clock_rate as f64 * 1000.0
+    }
+
+}
+
+/// a SIP dialog
+#[derive(Default)]
+pub struct Call {
+    pub from:       String,
+    pub to:         String,
+    pub caller:     Option<Ipv4Addr>,
+    pub callee:     Option<Ipv4Addr>,
+    pub user_agents: Vec<String>,
+    /// requests by method and responses by code
+    pub messages:   BTreeMap<String, u64>,
+    /// final response to the INVITE
+    pub result:     Option<u16>,
+    pub invite:     Option<f64>,
+    pub bye:        Option<f64>,
+    pub media:      Vec<Media>,
+    /// RTP streams keyed by source, destination and SSRC
+    pub streams:    BTreeMap<(Ipv4Addr, u16, Ipv4Addr, u16, u32), RtpStream>,
+}
+
+/// what we learned from SIP and RTP in the capture
+#[derive(Default)]
+pub struct SipInfo {
+    /// keyed by Call-ID
+    pub calls:      BTreeMap<String, Call>,
+    /// media endpoints from SDP and the Call-ID they belong to
+    endpoints:      BTreeMap<(Ipv4Addr, u16), String>,
+}
+
+impl SipInfo {
+
+    /// handle a SIP message over UDP or TCP
+    pub fn sip(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(msg) = parse(payload) else {
+            return;
+        };
+        let Some(call_id) = msg.header("Call-ID", "i") else {
+            return;
+        };
+
+        let call = self.calls.entry(call_id.to_string()).or_default();
+
+        if call.from.is_empty() {
+            call.from = msg.header("From", "f").unwrap_or("").to_string();
+            call.to = msg.header("To", "t").unwrap_or("").to_string();
+        }
+        let agent = http::header(&msg.headers, "User-Agent")
+            .or_else(|| http::header(&msg.headers, "Server"));
+        if let Some(agent) = agent {
+            if !call.user_agents.iter().any(|a| a == agent) {
+                call.user_agents.push(agent.to_string());
+            }
+        }
+
+        // the method a response answers is in CSeq
+        let cseq = http::header(&msg.headers, "CSeq")
+            .and_then(|c| c.split_whitespace().nth(1))
+            .unwrap_or("");
+
+        match (&msg.method, msg.status) {
+            (Some(method), _) => {
+                *call.messages.entry(method.clone()).or_insert(0) += 1;
+                match method.as_str() {
+                    "INVITE" if call.invite.is_none() => {
+                        call.invite = Some(flow.time());
+                        call.caller = Some(flow.sip);
+                        call.callee = Some(flow.dip);
+                    },
+                    "BYE" => call.bye = Some(flow.time()),
+                    _ => (),
+                }
+            },
+            (None, Some(status)) => {
+                *call.messages.entry(status.to_string()).or_insert(0) += 1;
+                if cseq == "INVITE" && status >= 200 && call.result.is_none() {
+                    call.result = Some(status);
+                }
+            },
+            _ => (),
+        }
+
+        for media in parse_sdp(&msg.body) {
+            // the address is missing if the SDP had no connection line
+            let addr = if media.addr.is_unspecified() { flow.sip } else { media.addr };
+            self.endpoints.insert((addr, media.port), call_id.to_string());
+            call.media.push(Media { addr, ..media });
+        }
+    }
+
+    /// true if UDP traffic goes to a media endpoint of a call
+    pub fn is_media(&self, flow: &Flow) -> bool {
+        self.endpoints.contains_key(&(flow.dip, flow.dport))
+    }
+
+    /// handle an RTP packet sent to a media endpoint
+    pub fn rtp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let (Some(&first), Some(&second), Some(seq), Some(timestamp), Some(ssrc)) =
+            (payload.first(), payload.get(1), be16(payload, 2), be32(payload, 4), be32(payload, 8))
+            else {
+            return;
+        };
+        if first >> 6 != RTP_VERSION || payload.len() < RTP_HEADER_LEN {
+            return;
+        }
+        let pt = second & 0x7f;
+        // RTCP shares the version, its packet types are 200 to 204
+        if (200..=204).contains(&second) {
+            return;
+        }
+
+        let Some(call) = self.endpoints.get(&(flow.dip, flow.dport))
+            .and_then(|id| self.calls.get_mut(id)) else {
+            return;
+        };
+
+        let clock_rate = call.media.iter()
+            .filter_map(|m| m.rtpmap.get(&pt))
+            .map(|(_, rate)| *rate)
+            .next()
+            .unwrap_or(DEFAULT_CLOCK_RATE);
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport, ssrc);
+        let stream = call.streams.entry(key).or_insert(RtpStream {
+            payload_type:   pt,
+            packets:        0,
+            base_seq:       seq as u32,
+            max_seq:        seq as u32,
+            last_transit:   None,
+            jitter:         0.0,
+            clock_rate,
+        });
+        stream.update(seq, timestamp, flow.time());
+    }
+
+    /// name of a payload type, from the SDP of the call if it has one
+    fn codec(call: &Call, pt: u8) -> String {
+        call.media.iter()
+            .filter_map(|m| m.rtpmap.get(&pt))
+            .map(|(name, rate)| format!("{}/{}", name, rate))
+            .next()
+            .or_else(|| payload_name(pt).map(|n| n.to_string()))
+            .unwrap_or_else(|| format!("PT {}", pt))
+    }
+
+    /// write the VoIP section of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.calls.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- VoIP Calls (SIP and RTP)\n")?;
+
+        for (call_id, call) in &self.calls {
+
+            let state = match (call.result, call.bye) {
+                (_, Some(_)) => "  [ENDED]".to_string(),
+                (Some(200..=299), None) => "  [ESTABLISHED]".to_string(),
+                (Some(code), None) => format!("  [FAILED {}]", code),
+                (None, None) => String::new(),
+            };
+            write!(file, "{}{}\n", call_id, state)?;
+            write!(file, "    from:     {}\n", call.from)?;
+            write!(file, "    to:       {}\n", call.to)?;
+            if let (Some(caller), Some(callee)) = (call.caller, call.callee) {
+                write!(file, "    hosts:    {} -> {}\n", caller, callee)?;
+            }
+            for agent in &call.user_agents {
+                write!(file, "    agent:    {}\n", agent)?;
+            }
+
+            let messages = call.messages.iter()
+                .map(|(m, count)| format!("{} {}x", m, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(file, "    messages: {}\n", messages)?;
+
+            if let (Some(invite), Some(bye)) = (call.invite, call.bye) {
+                write!(file, "    duration: {:.1} s\n", bye - invite)?;
+            }
+
+            for m in &call.media {
+                write!(file, "    media:    {}:{} {}\n", m.addr, m.port, m.kind)?;
+            }
+
+            for ((src, sport, dst, dport, ssrc), s) in &call.streams {
+                let (expected, lost) = s.loss();
+                write!(file, "    rtp:      {}:{} -> {}:{} ssrc 0x{:08x} {}\n",
+                    src, sport, dst, dport, ssrc, Self::codec(call, s.payload_type))?;
+                write!(file, "              {} packets, {} lost ({:.1}%), jitter {:.2} ms\n",
+                    s.packets, lost, lost as f64 * 100.0 / expected as f64, s.jitter_ms())?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,315 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! SNMP v1, v2c and v3. v1 and v2c send a community string in the clear
+//! with every message, v3 has a user name and may encrypt the PDU.
+//!
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dissect::{Flow, ber};
+
+/// SNMP ports of agents and trap receivers
+pub const SNMP_PORT:        u16 = 161;
+pub const SNMP_TRAP_PORT:   u16 = 162;
+
+/// message versions
+const VERSION_1:    u64 = 0;
+const VERSION_2C:   u64 = 1;
+const VERSION_3:    u64 = 3;
+
+/// PDU tags
+const PDU_GET:          u8 = 0xa0;
+const PDU_GET_NEXT:     u8 = 0xa1;
+const PDU_RESPONSE:     u8 = 0xa2;
+const PDU_SET:          u8 = 0xa3;
+const PDU_TRAP_V1:      u8 = 0xa4;
+const PDU_GET_BULK:     u8 = 0xa5;
+const PDU_INFORM:       u8 = 0xa6;
+const PDU_TRAP_V2:      u8 = 0xa7;
+const PDU_REPORT:       u8 = 0xa8;
+
+/// v3 message flags
+const FLAG_AUTH:    u8 = 0x01;
+const FLAG_PRIV:    u8 = 0x02;
+
+/// OIDs kept per pair
+const MAX_OIDS: usize = 20;
+
+/// community strings that come as defaults or are easily guessed
+const WEAK_COMMUNITIES: &[&str] = &[
+    "public", "private", "community", "admin", "default", "manager",
+    "snmp", "snmpd", "cisco", "read", "write", "secret", "test",
+    "monitor", "password", "system", "all", "",
+];
+
+/// get the name of a PDU
+pub fn pdu_name(tag: u8) -> &'static str {
+    match tag {
+        PDU_GET         => "GetRequest",
+        PDU_GET_NEXT    => "GetNextRequest",
+        PDU_RESPONSE    => "Response",
+        PDU_SET         => "SetRequest",
+        PDU_TRAP_V1     => "Trap",
+        PDU_GET_BULK    => "GetBulkRequest",
+        PDU_INFORM      => "InformRequest",
+        PDU_TRAP_V2     => "SNMPv2-Trap",
+        PDU_REPORT      => "Report",
+        _               => "unknown",
+    }
+}
+
+/// true for the community strings in `WEAK_COMMUNITIES`
+pub fn weak_community(community: &str) -> bool {
+    WEAK_COMMUNITIES.contains(&community.to_lowercase().as_str())
+}
+
+/// get the name of a message version
+fn version_name(version: u64) -> &'static str {
+    match version {
+        VERSION_1   => "v1",
+        VERSION_2C  => "v2c",
+        VERSION_3   => "v3",
+        _           => "unknown",
+    }
+}
+
+/// get the security level from the v3 message flags
+fn security_level(flags: u8) -> &'static str {
+    match (flags & FLAG_AUTH != 0, flags & FLAG_PRIV != 0) {
+        (true, true)    => "authPriv",
+        (true, false)   => "authNoPriv",
+        _               => "noAuthNoPriv",
+    }
+}
+
+/// the fields of a message we track
+#[derive(Default)]
+pub struct Message {
+    pub version:    u64,
+    pub community:  Option<String>,
+    pub user:       Option<String>,
+    pub level:      Option<&'static str>,
+    /// tag of the PDU, `None` if it is encrypted
+    pub pdu:        Option<u8>,
+    pub oids:       Vec<String>,
+}
+
+/// parse an SNMP message
+pub fn parse(buf: &[u8]) -> Option<Message> {
+
+    let (msg, _) = ber::read(buf)?;
+    if msg.tag != ber::TAG_SEQUENCE {
+        return None;
+    }
+    let fields = msg.children();
+    let version = fields.first().filter(|t| t.tag == ber::TAG_INTEGER)?.uint()?;
+
+    let mut message = Message { version, ..Default::default() };
+
+    let pdu = match version {
+        VERSION_1 | VERSION_2C => {
+            let community = fields.get(1).filter(|t| t.tag == ber::TAG_OCTET_STRING)?;
+            message.community = Some(community.string());
+            *fields.get(2)?
+        },
+        VERSION_3 => {
+            // msgGlobalData holds the flags as a one byte string
+            let global = fields.get(1)?.children();
+            let flags = global.get(2).and_then(|t| t.value.first().copied())?;
+            message.level = Some(security_level(flags));
+
+            // the USM parameters are BER wrapped in an octet string
+            let user = fields.get(2)
+                .and_then(|t| ber::read(t.value))
+                .and_then(|(usm, _)| usm.children().get(3).map(|t| t.string()));
+            message.user = user;
+
+            // an encrypted scoped PDU is an octet string
+            let scoped = fields.get(3)?;
+            if scoped.tag != ber::TAG_SEQUENCE {
+                return Some(message);
+            }
+            *scoped.children().get(2)?
+        },
+        _ => return None,
+    };
+
+    message.pdu = Some(pdu.tag);
+
+    // the v1 trap has its own layout, enterprise first and the
+    // variable bindings last
+    let items = pdu.children();
+    let bindings = if pdu.tag == PDU_TRAP_V1 {
+        if let Some(enterprise) = items.first().filter(|t| t.tag == ber::TAG_OID) {
+            message.oids.push(enterprise.oid());
+        }
+        items.get(5)
+    } else {
+        items.get(3)
+    };
+
+    for binding in bindings.map(|b| b.children()).unwrap_or_default() {
+        if let Some(oid) = binding.children().first().filter(|t| t.tag == ber::TAG_OID) {
+            message.oids.push(oid.oid());
+        }
+    }
+
+    Some(message)
+}
+
+/// what a manager and an agent exchanged
+#[derive(Default)]
+pub struct Pair {
+    pub versions:       BTreeSet<&'static str>,
+    pub communities:    BTreeSet<String>,
+    /// v3 user names and security levels
+    pub users:          BTreeSet<(String, &'static str)>,
+    pub pdus:           BTreeMap<&'static str, u64>,
+    /// OIDs asked for, written or trapped
+    pub oids:           BTreeSet<String>,
+    pub written:        BTreeSet<String>,
+    pub more_oids:      u64,
+}
+
+/// what we learned from the SNMP traffic in the capture
+#[derive(Default)]
+pub struct SnmpInfo {
+    /// keyed by agent and manager
+    pub pairs:  BTreeMap<(Ipv4Addr, Ipv4Addr), Pair>,
+}
+
+impl SnmpInfo {
+
+    /// handle a UDP payload on one of the SNMP ports
+    pub fn udp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let Some(message) = parse(payload) else {
+            return;
+        };
+
+        // requests go to the agent, everything else comes from it. an
+        // encrypted PDU only leaves the port to tell
+        let to_agent = match message.pdu {
+            Some(PDU_GET | PDU_GET_NEXT | PDU_GET_BULK | PDU_SET) => true,
+            Some(_) => false,
+            None => flow.dport == SNMP_PORT,
+        };
+        let key = if to_agent {
+            (flow.dip, flow.sip)
+        } else {
+            (flow.sip, flow.dip)
+        };
+
+        let pair = self.pairs.entry(key).or_default();
+        pair.versions.insert(version_name(message.version));
+        if let Some(community) = message.community {
+            pair.communities.insert(community);
+        }
+        if let (Some(user), Some(level)) = (message.user, message.level) {
+            pair.users.insert((user, level));
+        }
+
+        let name = match message.pdu {
+            Some(tag) => pdu_name(tag),
+            None => "encrypted",
+        };
+        *pair.pdus.entry(name).or_insert(0) += 1;
+
+        // responses repeat the OIDs of the request
+        if message.pdu == Some(PDU_RESPONSE) {
+            return;
+        }
+        for oid in message.oids {
+            if message.pdu == Some(PDU_SET) {
+                pair.written.insert(oid.clone());
+            }
+            if pair.oids.len() < MAX_OIDS || pair.oids.contains(&oid) {
+                pair.oids.insert(oid);
+            } else {
+                pair.more_oids += 1;
+            }
+        }
+    }
+
+    /// write the SNMP section of the report, community strings are only
+    /// shown if `reveal` is set
+    pub fn report(&self, file: &mut File, reveal: bool) -> Result<(), Error> {
+
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- SNMP (agent <- manager)\n")?;
+
+        let list = |hosts: BTreeSet<&Ipv4Addr>| {
+            hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ")
+        };
+        let agents = self.pairs.keys().map(|(agent, _)| agent).collect();
+        let managers = self.pairs.keys().map(|(_, manager)| manager).collect();
+        write!(file, "agents:   {}\n", list(agents))?;
+        write!(file, "managers: {}\n", list(managers))?;
+
+        for ((agent, manager), p) in &self.pairs {
+
+            let mut flags = String::new();
+            if p.communities.iter().any(|c| weak_community(c)) {
+                flags.push_str("  [WEAK COMMUNITY]");
+            }
+            if p.users.iter().any(|(_, level)| *level == "noAuthNoPriv") {
+                flags.push_str("  [NO AUTH]");
+            }
+            if !p.written.is_empty() {
+                flags.push_str("  [SET]");
+            }
+            write!(file, "{} <- {}{}\n", agent, manager, flags)?;
+
+            let versions = p.versions.iter().cloned().collect::<Vec<_>>().join(", ");
+            write!(file, "    versions:  {}\n", versions)?;
+            for community in &p.communities {
+                let weak = if weak_community(community) { "  [WEAK]" } else { "" };
+                if reveal {
+                    write!(file, "    community: \"{}\"{}\n", community, weak)?;
+                } else {
+                    write!(file, "    community: <redacted>{}\n", weak)?;
+                }
+            }
+            for (user, level) in &p.users {
+                write!(file, "    user:      {} ({})\n", user, level)?;
+            }
+            for (pdu, count) in &p.pdus {
+                write!(file, "    pdu:       {} ({}x)\n", pdu, count)?;
+            }
+            for oid in &p.oids {
+                let set = if p.written.contains(oid) { " (set)" } else { "" };
+                write!(file, "    oid:       {}{}\n", oid, set)?;
+            }
+            if p.more_oids > 0 {
+                write!(file, "    ... and {} more OIDs\n", p.more_oids)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
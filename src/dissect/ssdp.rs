@@ -0,0 +1,100 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dissect::http::header;
+
+/// SSDP multicast port
+pub const SSDP_PORT: u16 = 1900;
+
+/// marks the XML device description a UPnP device serves over HTTP
+const DEVICE_XMLNS: &str = "urn:schemas-upnp-org:device-1-0";
+
+/// the kind of SSDP message
+#[derive(PartialEq)]
+pub enum SsdpKind {
+    Notify,
+    Search,
+    Response,
+}
+
+/// a decoded SSDP message
+pub struct SsdpMessage {
+    pub kind:       SsdpKind,
+    pub headers:    Vec<(String, String)>,
+}
+
+impl SsdpMessage {
+
+    /// device or service type a device announces, NT in notifications
+    /// and ST in responses
+    pub fn device_type(&self) -> Option<&str> {
+        match self.kind {
+            SsdpKind::Notify   => header(&self.headers, "NT"),
+            SsdpKind::Response => header(&self.headers, "ST"),
+            SsdpKind::Search   => None,
+        }
+    }
+
+}
+
+/// parse a SSDP message, these are HTTP over UDP
+pub fn parse(buf: &[u8]) -> Option<SsdpMessage> {
+
+    let text = String::from_utf8_lossy(buf);
+    let mut lines = text.split("\r\n");
+
+    let kind = match lines.next()? {
+        l if l.starts_with("NOTIFY * HTTP/1.")   => SsdpKind::Notify,
+        l if l.starts_with("M-SEARCH * HTTP/1.") => SsdpKind::Search,
+        l if l.starts_with("HTTP/1.")            => SsdpKind::Response,
+        _ => return None,
+    };
+
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Some(SsdpMessage { kind, headers })
+}
+
+/// pull the interesting fields out of a UPnP device description, the
+/// description is fetched over HTTP from the LOCATION of an announcement
+pub fn parse_description(buf: &[u8]) -> Option<Vec<(&'static str, String)>> {
+
+    let text = String::from_utf8_lossy(buf);
+    if !text.contains(DEVICE_XMLNS) {
+        return None;
+    }
+
+    let mut fields = Vec::new();
+    for tag in ["friendlyName", "manufacturer", "modelName",
+                "modelNumber", "deviceType"] {
+        let open = format!("<{}>", tag);
+        let close = format!("</{}>", tag);
+        // the first occurrence belongs to the root device
+        if let Some(start) = text.find(&open) {
+            let start = start + open.len();
+            if let Some(len) = text[start..].find(&close) {
+                fields.push((tag, text[start..start+len].trim().to_string()));
+            }
+        }
+    }
+
+    Some(fields)
+}
@@ -0,0 +1,364 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use md5::{Digest, Md5};
+
+use crate::util;
+use crate::dissect::{Flow, be32};
+
+/// SSH server port
+pub const SSH_PORT: u16 = 22;
+
+/// message number of a SSH_MSG_KEXINIT
+const MSG_KEXINIT: u8 = 20;
+
+/// give up on a direction if we do not see the KEXINIT within this
+/// many bytes
+const MAX_BUFFER: usize = 65536;
+
+/// server software released before the fixes for the Terrapin attack
+/// (CVE-2023-48795), with the first fixed upstream version. builds of
+/// distributions often carry the fix in an older version.
+const OUTDATED: [(&str, &str); 3] = [
+    ("OpenSSH_", "9.6"),
+    ("dropbear_", "2024.84"),
+    ("libssh_", "0.10.6"),
+];
+
+/// the algorithms offered in a KEXINIT
+pub struct KexInit {
+    pub kex:            String,
+    pub host_key:       String,
+    pub enc_c2s:        String,
+    pub enc_s2c:        String,
+    pub mac_c2s:        String,
+    pub mac_s2c:        String,
+    pub comp_c2s:       String,
+    pub comp_s2c:       String,
+}
+
+impl KexInit {
+
+    /// HASSH of a client's KEXINIT
+    pub fn hassh(&self) -> String {
+        let s = format!("{};{};{};{}",
+            self.kex, self.enc_c2s, self.mac_c2s, self.comp_c2s);
+        util::hex(&Md5::digest(s.as_bytes()))
+    }
+
+    /// HASSHServer of a server's KEXINIT
+    pub fn hassh_server(&self) -> String {
+        let s = format!("{};{};{};{}",
+            self.kex, self.enc_s2c, self.mac_s2c, self.comp_s2c);
+        util::hex(&Md5::digest(s.as_bytes()))
+    }
+
+}
+
+/// parse the payload of a KEXINIT message, behind the message number
+pub fn parse_kexinit(payload: &[u8]) -> Option<KexInit> {
+
+    // skip the cookie
+    let mut pos = 16;
+    let mut lists = Vec::new();
+
+    for _ in 0..8 {
+        let len = be32(payload, pos)? as usize;
+        let list = payload.get(pos+4..pos+4+len)?;
+        lists.push(String::from_utf8_lossy(list).to_string());
+        pos += 4 + len;
+    }
+
+    let mut lists = lists.into_iter();
+    Some(KexInit {
+        kex:        lists.next()?,
+        host_key:   lists.next()?,
+        enc_c2s:    lists.next()?,
+        enc_s2c:    lists.next()?,
+        mac_c2s:    lists.next()?,
+        mac_s2c:    lists.next()?,
+        comp_c2s:   lists.next()?,
+        comp_s2c:   lists.next()?,
+    })
+}
+
+/// compare dotted version numbers, `8.9p1` is treated as `8.9.1`
+fn version_lt(version: &str, min: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.split(|c: char| !c.is_ascii_digit())
+            .filter(|p| !p.is_empty())
+            .filter_map(|p| p.parse().ok())
+            .collect()
+    };
+    parse(version) < parse(min)
+}
+
+/// why a server's identification string is considered outdated
+pub fn outdated(banner: &str) -> Option<String> {
+
+    // SSH-protoversion-softwareversion SP comments
+    let mut parts = banner.splitn(3, '-');
+    let _ = parts.next()?;
+    let proto = parts.next()?;
+    let mut rest = parts.next()?.splitn(2, ' ');
+    let software = rest.next()?;
+    // distributions name their build in the comments
+    let distribution = rest.next().is_some_and(|c| !c.trim().is_empty());
+
+    if proto != "2.0" {
+        return Some(format!("protocol version {}", proto));
+    }
+
+    for (prefix, min) in OUTDATED {
+        if let Some(version) = software.strip_prefix(prefix) {
+            if version_lt(version, min) {
+                let mut reason = format!("upstream version older than {} {}",
+                    prefix.trim_end_matches('_'), min);
+                if distribution {
+                    reason.push_str(", the distribution may have backported fixes");
+                }
+                return Some(reason);
+            }
+        }
+    }
+
+    None
+}
+
+/// a SSH connection between a client and a server
+#[derive(Default)]
+pub struct SshSession {
+    pub client_banner:  Option<String>,
+    pub server_banner:  Option<String>,
+    pub hassh:          Option<String>,
+    pub hassh_server:   Option<String>,
+}
+
+/// a TCP connection from the sender's point of view
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// (client, server, server port)
+type PairKey = (Ipv4Addr, Ipv4Addr, u16);
+
+/// what we learned from the SSH connections in the capture
+#[derive(Default)]
+pub struct SshInfo {
+    /// sessions per host pair, a pair may use several connections
+    pub sessions:   BTreeMap<PairKey, Vec<SshSession>>,
+    /// session index per connection
+    conns:          HashMap<ConnKey, usize>,
+    /// data of directions still waiting for their KEXINIT
+    buffers:        HashMap<ConnKey, Vec<u8>>,
+    /// directions we are done with
+    done:           HashSet<ConnKey>,
+}
+
+impl SshInfo {
+
+    /// handle the next data of a direction, in order from the
+    /// reassembly. the identification string and the KEXINIT are the
+    /// first things both sides send.
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+
+        // a new connection with the same addresses starts over
+        if payload.starts_with(b"SSH-") {
+            self.done.remove(&key);
+            self.buffers.insert(key, Vec::new());
+        }
+
+        if self.done.contains(&key) {
+            return;
+        }
+
+        let Some(buf) = self.buffers.get_mut(&key) else {
+            return;
+        };
+        buf.extend_from_slice(payload);
+
+        let buf = std::mem::take(buf);
+        match self.consume(flow, &buf) {
+            Some(true) => {
+                self.buffers.remove(&key);
+                self.done.insert(key);
+            },
+            Some(false) if buf.len() < MAX_BUFFER => {
+                self.buffers.insert(key, buf);
+            },
+            _ => {
+                self.buffers.remove(&key);
+                self.done.insert(key);
+            },
+        }
+    }
+
+    /// data is missing from a direction, the KEXINIT can't be found
+    /// past the hole
+    pub fn gap(&mut self, flow: &Flow) {
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        if self.buffers.remove(&key).is_some() {
+            self.done.insert(key);
+        }
+    }
+
+    /// a direction ended, forget its state
+    pub fn end(&mut self, flow: &Flow) {
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        self.buffers.remove(&key);
+        self.done.remove(&key);
+    }
+
+    /// look for the identification string and the KEXINIT at the start
+    /// of a direction. returns true once both were found.
+    fn consume(&mut self, flow: &Flow, buf: &[u8]) -> Option<bool> {
+
+        // the identification string ends with CR LF
+        let Some(eol) = buf.windows(2).position(|w| w == b"\r\n") else {
+            return Some(false);
+        };
+        let banner = String::from_utf8_lossy(&buf[..eol]).to_string();
+
+        // servers may send other lines before their identification
+        // string, this is rare enough to ignore
+        if !banner.starts_with("SSH-") {
+            return None;
+        }
+
+        let is_client = self.is_client(flow);
+        let session = self.session(flow, is_client);
+
+        if is_client {
+            session.client_banner = Some(banner);
+        } else {
+            session.server_banner = Some(banner);
+        }
+
+        // binary packet: length, padding length, payload
+        let packet = &buf[eol+2..];
+        let Some(len) = be32(packet, 0) else {
+            return Some(false);
+        };
+        let Some(data) = packet.get(4..4+len as usize) else {
+            return Some(false);
+        };
+
+        let padding = *data.first()? as usize;
+        let payload = data.get(1..data.len().checked_sub(padding)?)?;
+
+        if payload.first() != Some(&MSG_KEXINIT) {
+            return None;
+        }
+
+        let kex = parse_kexinit(&payload[1..])?;
+        if is_client {
+            session.hassh = Some(kex.hassh());
+        } else {
+            session.hassh_server = Some(kex.hassh_server());
+        }
+
+        Some(true)
+    }
+
+    /// true if the sender of `flow` is the client. the side talking to
+    /// port 22 is the client, otherwise we guess the lower port is the
+    /// server.
+    fn is_client(&self, flow: &Flow) -> bool {
+        if flow.dport == SSH_PORT || flow.sport == SSH_PORT {
+            return flow.dport == SSH_PORT;
+        }
+        flow.dport < flow.sport
+    }
+
+    /// get the session of the connection, it is created if necessary
+    fn session(&mut self, flow: &Flow, is_client: bool) -> &mut SshSession {
+
+        let (client, cport, server, port) = if is_client {
+            (flow.sip, flow.sport, flow.dip, flow.dport)
+        } else {
+            (flow.dip, flow.dport, flow.sip, flow.sport)
+        };
+
+        let sessions = self.sessions.entry((client, server, port))
+            .or_default();
+
+        let idx = *self.conns.entry((client, cport, server, port))
+            .or_insert_with(|| {
+                sessions.push(SshSession::default());
+                sessions.len() - 1
+            });
+
+        &mut sessions[idx]
+    }
+
+    /// write the SSH sections of the report
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.sessions.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- SSH Sessions (client -> server)\n")?;
+
+        // servers with outdated software, by server address and banner
+        let mut outdated_servers = BTreeMap::new();
+
+        for ((client, server, port), sessions) in &self.sessions {
+
+            write!(file, "{} -> {}:{}\n", client, server, port)?;
+
+            // identical sessions are merged
+            let mut lines: BTreeMap<String, u64> = BTreeMap::new();
+
+            for s in sessions {
+                let line = format!("client: {}  hassh: {}\n      \
+                                    server: {}  hasshServer: {}",
+                    s.client_banner.as_deref().unwrap_or("-"),
+                    s.hassh.as_deref().unwrap_or("-"),
+                    s.server_banner.as_deref().unwrap_or("-"),
+                    s.hassh_server.as_deref().unwrap_or("-"));
+                *lines.entry(line).or_insert(0) += 1;
+
+                if let Some(banner) = &s.server_banner {
+                    if let Some(reason) = outdated(banner) {
+                        outdated_servers.insert(
+                            (*server, *port, banner.clone()), reason);
+                    }
+                }
+            }
+
+            for (line, count) in lines {
+                write!(file, "    ({}x) {}\n", count, line)?;
+            }
+        }
+
+        write!(file, "\n\n-- SSH Outdated Servers\n")?;
+        for ((server, port, banner), reason) in outdated_servers {
+            write!(file, "{}:{:<6} {:<40} {}\n", server, port, banner, reason)?;
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,734 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::Error;
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::util;
+use crate::dissect::{Flow, be16};
+use crate::dissect::x509::{self, Certificate};
+
+/// record content types
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 20;
+const RECORD_ALERT:              u8 = 21;
+const RECORD_HANDSHAKE:          u8 = 22;
+const RECORD_APPLICATION_DATA:   u8 = 23;
+
+/// handshake message types
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE:  u8 = 11;
+
+/// extension types
+const EXT_SERVER_NAME:        u16 = 0;
+const EXT_SUPPORTED_GROUPS:   u16 = 10;
+const EXT_EC_POINT_FORMATS:   u16 = 11;
+const EXT_SIGNATURE_ALGS:     u16 = 13;
+const EXT_ALPN:               u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// we stop buffering a direction that does not finish its handshake
+/// within this many bytes
+const MAX_HANDSHAKE: usize = 65536;
+
+/// a decoded ClientHello
+#[derive(Default)]
+pub struct ClientHello {
+    /// the legacy version field
+    pub version:        u16,
+    pub ciphers:        Vec<u16>,
+    pub extensions:     Vec<u16>,
+    pub sni:            Option<String>,
+    pub alpn:           Vec<String>,
+    /// versions from the supported_versions extension
+    pub versions:       Vec<u16>,
+    pub groups:         Vec<u16>,
+    pub point_formats:  Vec<u8>,
+    pub sig_algs:       Vec<u16>,
+}
+
+/// a decoded ServerHello
+#[derive(Default)]
+pub struct ServerHello {
+    /// the legacy version field
+    pub version:        u16,
+    pub cipher:         u16,
+    pub extensions:     Vec<u16>,
+    /// version from the supported_versions extension, TLS 1.3 only
+    pub selected:       Option<u16>,
+    pub alpn:           Option<String>,
+}
+
+/// GREASE values (RFC 8701) are random and must not end up in
+/// fingerprints
+pub fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+/// get the name of a protocol version
+pub fn version_name(v: u16) -> String {
+    match v {
+        0x0300 => "SSL3.0".to_string(),
+        0x0301 => "TLS1.0".to_string(),
+        0x0302 => "TLS1.1".to_string(),
+        0x0303 => "TLS1.2".to_string(),
+        0x0304 => "TLS1.3".to_string(),
+        _      => format!("0x{:04x}", v),
+    }
+}
+
+/// read a list of u16 values prefixed with a 16 bit length
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    let len = be16(data, 0).unwrap_or(0) as usize;
+    data.get(2..2+len).unwrap_or(&[])
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect()
+}
+
+/// read the protocol names of an ALPN extension
+fn alpn_list(data: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut pos = 2;
+    while let Some(&len) = data.get(pos) {
+        let len = len as usize;
+        match data.get(pos+1..pos+1+len) {
+            Some(name) => names.push(String::from_utf8_lossy(name).to_string()),
+            None       => break,
+        }
+        pos += 1 + len;
+    }
+    names
+}
+
+/// iterate over the extensions block, calls `f` with type and data
+fn extensions(buf: &[u8], mut f: impl FnMut(u16, &[u8])) -> Option<()> {
+    let total = be16(buf, 0)? as usize;
+    let block = buf.get(2..2+total)?;
+    let mut pos = 0;
+    while pos + 4 <= block.len() {
+        let etype = be16(block, pos)?;
+        let len = be16(block, pos+2)? as usize;
+        let data = block.get(pos+4..pos+4+len)?;
+        f(etype, data);
+        pos += 4 + len;
+    }
+    Some(())
+}
+
+/// parse the body of a ClientHello handshake message
+pub fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+
+    let mut ch = ClientHello {
+        version: be16(body, 0)?,
+        ..Default::default()
+    };
+
+    // skip the random
+    let sid_len = *body.get(34)? as usize;
+    let mut pos = 35 + sid_len;
+
+    let cs_len = be16(body, pos)? as usize;
+    ch.ciphers = body.get(pos+2..pos+2+cs_len)?
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect();
+    pos += 2 + cs_len;
+
+    let comp_len = *body.get(pos)? as usize;
+    pos += 1 + comp_len;
+
+    // SSLv3 style hellos come without extensions
+    if pos >= body.len() {
+        return Some(ch);
+    }
+
+    extensions(&body[pos..], |etype, data| {
+        ch.extensions.push(etype);
+        match etype {
+            EXT_SERVER_NAME => {
+                // a list with a single host_name entry in practice
+                if let Some(len) = be16(data, 3) {
+                    if let Some(name) = data.get(5..5+len as usize) {
+                        ch.sni = Some(String::from_utf8_lossy(name)
+                            .to_string());
+                    }
+                }
+            },
+            EXT_SUPPORTED_GROUPS => ch.groups = u16_list(data),
+            EXT_EC_POINT_FORMATS => {
+                let len = *data.first().unwrap_or(&0) as usize;
+                ch.point_formats = data.get(1..1+len)
+                    .unwrap_or(&[]).to_vec();
+            },
+            EXT_SIGNATURE_ALGS => ch.sig_algs = u16_list(data),
+            EXT_ALPN => ch.alpn = alpn_list(data),
+            EXT_SUPPORTED_VERSIONS => {
+                let len = *data.first().unwrap_or(&0) as usize;
+                ch.versions = data.get(1..1+len).unwrap_or(&[])
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+            },
+            _ => (),
+        }
+    })?;
+
+    Some(ch)
+}
+
+/// parse the body of a ServerHello handshake message
+pub fn parse_server_hello(body: &[u8]) -> Option<ServerHello> {
+
+    let sid_len = *body.get(34)? as usize;
+    let pos = 35 + sid_len;
+
+    let mut sh = ServerHello {
+        version: be16(body, 0)?,
+        cipher:  be16(body, pos)?,
+        ..Default::default()
+    };
+
+    // skip cipher suite and compression method
+    let pos = pos + 3;
+    if pos >= body.len() {
+        return Some(sh);
+    }
+
+    extensions(&body[pos..], |etype, data| {
+        sh.extensions.push(etype);
+        match etype {
+            EXT_SUPPORTED_VERSIONS => sh.selected = be16(data, 0),
+            EXT_ALPN => sh.alpn = alpn_list(data).into_iter().next(),
+            _ => (),
+        }
+    })?;
+
+    Some(sh)
+}
+
+/// join values with `-` for JA3, GREASE values are left out
+fn ja3_list<T: std::fmt::Display + Copy + Into<u16>>(values: &[T]) -> String {
+    values.iter()
+        .filter(|v| !is_grease((**v).into()))
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// the JA3 fingerprint of a ClientHello
+pub fn ja3(ch: &ClientHello) -> String {
+    let s = format!("{},{},{},{},{}",
+        ch.version,
+        ja3_list(&ch.ciphers),
+        ja3_list(&ch.extensions),
+        ja3_list(&ch.groups),
+        ja3_list(&ch.point_formats));
+    util::hex(&Md5::digest(s.as_bytes()))
+}
+
+/// the JA3S fingerprint of a ServerHello
+pub fn ja3s(sh: &ServerHello) -> String {
+    let s = format!("{},{},{}",
+        sh.version,
+        sh.cipher,
+        ja3_list(&sh.extensions));
+    util::hex(&Md5::digest(s.as_bytes()))
+}
+
+/// truncated sha256 as used by JA4, all zeros if there is no input
+fn ja4_hash(s: &str) -> String {
+    if s.is_empty() {
+        return "000000000000".to_string();
+    }
+    util::hex(&Sha256::digest(s.as_bytes()))[..12].to_string()
+}
+
+/// the JA4 fingerprint of a ClientHello seen on TCP
+pub fn ja4(ch: &ClientHello) -> String {
+
+    let version = ch.versions.iter()
+        .filter(|v| !is_grease(**v))
+        .max()
+        .copied()
+        .unwrap_or(ch.version);
+
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _      => "00",
+    };
+
+    let sni = if ch.sni.is_some() { 'd' } else { 'i' };
+
+    let mut ciphers = ch.ciphers.iter()
+        .filter(|c| !is_grease(**c))
+        .copied()
+        .collect::<Vec<_>>();
+    let mut exts = ch.extensions.iter()
+        .filter(|e| !is_grease(**e))
+        .copied()
+        .collect::<Vec<_>>();
+
+    // first and last character of the first ALPN value
+    let alpn = match ch.alpn.first().map(|a| a.as_bytes()) {
+        Some(&[first, .., last]) | Some(&[first @ last]) => {
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                format!("{:x}{:x}", first >> 4, last & 0x0f)
+            }
+        },
+        _ => "00".to_string(),
+    };
+
+    let a = format!("t{}{}{:02}{:02}{}", version, sni,
+        ciphers.len().min(99), exts.len().min(99), alpn);
+
+    ciphers.sort();
+    let b = ciphers.iter()
+        .map(|c| format!("{:04x}", c))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    // SNI and ALPN are already part of the first block
+    exts.retain(|e| *e != EXT_SERVER_NAME && *e != EXT_ALPN);
+    exts.sort();
+    let mut c = exts.iter()
+        .map(|e| format!("{:04x}", e))
+        .collect::<Vec<_>>()
+        .join(",");
+    if !c.is_empty() && !ch.sig_algs.is_empty() {
+        let algs = ch.sig_algs.iter()
+            .filter(|s| !is_grease(**s))
+            .map(|s| format!("{:04x}", s))
+            .collect::<Vec<_>>()
+            .join(",");
+        c = format!("{}_{}", c, algs);
+    }
+
+    format!("{}_{}_{}", a, ja4_hash(&b), ja4_hash(&c))
+}
+
+/// parse the chain of a Certificate message, each certificate comes 
+/// with a 24 bit length
+pub fn parse_certificates(body: &[u8]) -> Vec<Certificate> {
+
+    let mut chain = Vec::new();
+    let mut pos = 3;
+
+    while pos + 3 <= body.len() {
+        let len = (body[pos] as usize) << 16
+            | (body[pos+1] as usize) << 8
+            | body[pos+2] as usize;
+        let Some(der) = body.get(pos+3..pos+3+len) else {
+            break;
+        };
+        if let Some(cert) = x509::parse(der) {
+            chain.push(cert);
+        }
+        pos += 3 + len;
+    }
+
+    chain
+}
+
+/// a handshake between a client and a server
+pub struct TlsSession {
+    pub client:     Ipv4Addr,
+    pub server:     Ipv4Addr,
+    pub port:       u16,
+    pub hello:      Option<ClientHello>,
+    pub ja3:        Option<String>,
+    pub ja4:        Option<String>,
+    pub reply:      Option<ServerHello>,
+    pub ja3s:       Option<String>,
+    /// the server's chain, only visible up to TLS 1.2
+    pub chain:      Vec<Certificate>,
+    /// capture time of the certificate message
+    pub chain_ts:   i64,
+}
+
+impl TlsSession {
+
+    /// the version both sides agreed on
+    pub fn negotiated_version(&self) -> Option<u16> {
+        self.reply.as_ref().map(|sh| sh.selected.unwrap_or(sh.version))
+    }
+
+}
+
+/// handshake data of one direction of a connection, records and
+/// handshake messages may span several segments
+#[derive(Default)]
+struct HalfStream {
+    records:    Vec<u8>,
+    handshake:  Vec<u8>,
+}
+
+/// certificates by fingerprint, with the time they were seen
+type CertMap<'a> = BTreeMap<&'a str, (&'a Certificate, i64)>;
+
+/// a TCP connection from the sender's point of view
+type ConnKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// what we learned from the TLS handshakes in the capture
+#[derive(Default)]
+pub struct TlsInfo {
+    pub sessions:   Vec<TlsSession>,
+    /// session index per connection, client side first
+    conns:          HashMap<ConnKey, usize>,
+    /// directions still in the handshake
+    streams:        HashMap<ConnKey, HalfStream>,
+    /// directions that finished their cleartext handshake
+    done:           HashSet<ConnKey>,
+}
+
+impl TlsInfo {
+
+    /// handle the next data of a direction, in order from the
+    /// reassembly
+    pub fn tcp(&mut self, flow: &Flow, payload: &[u8]) {
+
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+
+        let starts_record = payload.len() >= 6
+            && payload[0] == RECORD_HANDSHAKE
+            && payload[1] == 3;
+
+        // a new ClientHello on a reused connection starts over
+        if starts_record && payload[5] == CLIENT_HELLO {
+            self.done.remove(&key);
+            self.streams.remove(&key);
+        }
+
+        if self.done.contains(&key) {
+            return;
+        }
+
+        let mut stream = match self.streams.remove(&key) {
+            Some(stream) => stream,
+            None if starts_record => HalfStream::default(),
+            None => return,
+        };
+
+        stream.records.extend_from_slice(payload);
+
+        match self.records(flow, &mut stream) {
+            Some(false) if stream.records.len() < MAX_HANDSHAKE => {
+                self.streams.insert(key, stream);
+            },
+            _ => {
+                self.done.insert(key);
+            },
+        }
+    }
+
+    /// data is missing from a direction, records can't be followed
+    /// past the hole
+    pub fn gap(&mut self, flow: &Flow) {
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        if self.streams.remove(&key).is_some() {
+            self.done.insert(key);
+        }
+    }
+
+    /// a direction ended, forget its state
+    pub fn end(&mut self, flow: &Flow) {
+        let key = (flow.sip, flow.sport, flow.dip, flow.dport);
+        self.streams.remove(&key);
+        self.done.remove(&key);
+    }
+
+    /// consume the complete records in `stream`, returns true once the
+    /// cleartext part of the handshake is over and `None` if the data
+    /// is not TLS
+    fn records(&mut self, flow: &Flow, stream: &mut HalfStream)
+        -> Option<bool> {
+
+        let mut finished = false;
+        let mut pos = 0;
+
+        while pos + 5 <= stream.records.len() {
+
+            let ctype = stream.records[pos];
+            if stream.records[pos+1] != 3 {
+                return None;
+            }
+
+            let len = be16(&stream.records, pos+3)? as usize;
+            let Some(fragment) = stream.records.get(pos+5..pos+5+len) else {
+                break;
+            };
+
+            match ctype {
+                RECORD_HANDSHAKE => stream.handshake.extend_from_slice(fragment),
+                RECORD_CHANGE_CIPHER_SPEC
+                    | RECORD_ALERT
+                    | RECORD_APPLICATION_DATA => finished = true,
+                _ => return None,
+            }
+
+            pos += 5 + len;
+
+            // anything behind this is encrypted
+            if finished {
+                break;
+            }
+        }
+
+        stream.records.drain(..pos);
+
+        // handshake messages have a 24 bit length
+        let mut pos = 0;
+        while pos + 4 <= stream.handshake.len() {
+            let htype = stream.handshake[pos];
+            let len = (stream.handshake[pos+1] as usize) << 16
+                | be16(&stream.handshake, pos+2)? as usize;
+            let Some(body) = stream.handshake.get(pos+4..pos+4+len) else {
+                break;
+            };
+            self.message(flow, htype, body);
+            pos += 4 + len;
+        }
+
+        stream.handshake.drain(..pos);
+
+        Some(finished)
+    }
+
+    /// handle a single handshake message
+    fn message(&mut self, flow: &Flow, htype: u8, body: &[u8]) {
+        match htype {
+            CLIENT_HELLO => {
+                if let Some(ch) = parse_client_hello(body) {
+                    let idx = self.session(flow.sip, flow.sport,
+                        flow.dip, flow.dport);
+                    let session = &mut self.sessions[idx];
+                    session.ja3 = Some(ja3(&ch));
+                    session.ja4 = Some(ja4(&ch));
+                    session.hello = Some(ch);
+                }
+            },
+            SERVER_HELLO => {
+                if let Some(sh) = parse_server_hello(body) {
+                    let idx = self.session(flow.dip, flow.dport,
+                        flow.sip, flow.sport);
+                    let session = &mut self.sessions[idx];
+                    session.ja3s = Some(ja3s(&sh));
+                    session.reply = Some(sh);
+                }
+            },
+            CERTIFICATE => {
+                let idx = self.session(flow.dip, flow.dport,
+                    flow.sip, flow.sport);
+                let session = &mut self.sessions[idx];
+                session.chain = parse_certificates(body);
+                session.chain_ts = flow.ts;
+            },
+            _ => (),
+        }
+    }
+
+    /// get the session of a connection, it is created if necessary
+    fn session(&mut self, client: Ipv4Addr, cport: u16,
+        server: Ipv4Addr, port: u16) -> usize {
+
+        let key = (client, cport, server, port);
+
+        if let Some(idx) = self.conns.get(&key) {
+            return *idx;
+        }
+
+        self.sessions.push(TlsSession {
+            client, server, port,
+            hello:  None,
+            ja3:    None,
+            ja4:    None,
+            reply:  None,
+            ja3s:   None,
+            chain:  Vec::new(),
+            chain_ts: 0,
+        });
+        self.conns.insert(key, self.sessions.len() - 1);
+        self.sessions.len() - 1
+    }
+
+    /// label the edges between clients and servers with the SNI, the
+    /// negotiated version and the JA4 of the client
+    pub fn edge_labels(&self) -> Vec<((Ipv4Addr, Ipv4Addr), String)> {
+        self.sessions.iter()
+            .filter_map(|s| {
+                let mut label = Vec::new();
+                if let Some(sni) = s.hello.as_ref().and_then(|ch| ch.sni.clone()) {
+                    label.push(sni);
+                }
+                if let Some(version) = s.negotiated_version() {
+                    label.push(version_name(version));
+                }
+                if let Some(ja4) = &s.ja4 {
+                    label.push(format!("ja4={}", ja4));
+                }
+                if label.is_empty() {
+                    return None;
+                }
+                Some(((s.client, s.server), label.join(" ")))
+            })
+            .collect()
+    }
+
+    /// write the TLS section of the report, grouped by client
+    pub fn report(&self, file: &mut File) -> Result<(), Error> {
+
+        if self.sessions.is_empty() {
+            return Ok(());
+        }
+
+        let mut fingerprints: BTreeMap<Ipv4Addr, BTreeSet<String>> =
+            BTreeMap::new();
+        let mut handshakes: BTreeMap<Ipv4Addr, BTreeMap<String, u64>> =
+            BTreeMap::new();
+
+        for s in &self.sessions {
+
+            if let (Some(ja3), Some(ja4)) = (&s.ja3, &s.ja4) {
+                fingerprints.entry(s.client).or_default()
+                    .insert(format!("JA3 {}  JA4 {}", ja3, ja4));
+            }
+
+            let mut line = format!("{}:{}", s.server, s.port);
+
+            if let Some(ch) = &s.hello {
+                line += &format!(" sni={}", ch.sni.as_deref().unwrap_or("-"));
+                if !ch.alpn.is_empty() {
+                    line += &format!(" alpn={}", ch.alpn.join(","));
+                }
+                let offered = ch.versions.iter()
+                    .filter(|v| !is_grease(**v))
+                    .map(|v| version_name(*v))
+                    .collect::<Vec<_>>();
+                if offered.is_empty() {
+                    line += &format!(" offered={}", version_name(ch.version));
+                } else {
+                    line += &format!(" offered={}", offered.join(","));
+                }
+            }
+
+            if let Some(sh) = &s.reply {
+                line += &format!(" selected={} cipher=0x{:04x}",
+                    version_name(s.negotiated_version().unwrap_or(0)),
+                    sh.cipher);
+                if let Some(alpn) = &sh.alpn {
+                    line += &format!(" alpn-selected={}", alpn);
+                }
+            }
+
+            if let Some(ja3s) = &s.ja3s {
+                line += &format!(" ja3s={}", ja3s);
+            }
+
+            *handshakes.entry(s.client).or_default()
+                .entry(line).or_insert(0) += 1;
+        }
+
+        write!(file, "\n\n-- TLS per Client\n")?;
+
+        for (client, lines) in &handshakes {
+            write!(file, "{}\n", client)?;
+            if let Some(fps) = fingerprints.get(client) {
+                for fp in fps {
+                    write!(file, "    {}\n", fp)?;
+                }
+            }
+            for (line, count) in lines {
+                write!(file, "    {} ({}x)\n", line, count)?;
+            }
+        }
+
+        self.report_certificates(file)
+    }
+
+    /// write the certificates each server presented
+    fn report_certificates(&self, file: &mut File) -> Result<(), Error> {
+
+        // server -> fingerprint -> (certificate, time seen)
+        let mut servers: BTreeMap<(Ipv4Addr, u16), CertMap> = BTreeMap::new();
+
+        for s in &self.sessions {
+            for cert in &s.chain {
+                servers.entry((s.server, s.port)).or_default()
+                    .insert(&cert.sha256, (cert, s.chain_ts));
+            }
+        }
+
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        write!(file, "\n\n-- TLS Certificates per Server\n")?;
+
+        for ((server, port), certs) in &servers {
+            write!(file, "{}:{}\n", server, port)?;
+
+            for (fingerprint, (cert, ts)) in certs {
+
+                let mut flags = Vec::new();
+                if cert.expired_at(*ts) {
+                    flags.push("EXPIRED");
+                }
+                if cert.not_yet_valid_at(*ts) {
+                    flags.push("NOT YET VALID");
+                }
+                if cert.self_signed {
+                    flags.push("SELF-SIGNED");
+                }
+                if cert.key.is_weak() {
+                    flags.push("WEAK-KEY");
+                }
+
+                write!(file, "    subject:  {}", cert.subject)?;
+                if !flags.is_empty() {
+                    write!(file, "  [{}]", flags.join("] ["))?;
+                }
+                write!(file, "\n")?;
+                write!(file, "    issuer:   {}\n", cert.issuer)?;
+                if !cert.sans.is_empty() {
+                    write!(file, "    sans:     {}\n", cert.sans.join(", "))?;
+                }
+                write!(file, "    valid:    {} - {}\n",
+                    x509::date_string(cert.not_before),
+                    x509::date_string(cert.not_after))?;
+                write!(file, "    key:      {}\n", cert.key)?;
+                write!(file, "    sha256:   {}\n\n", fingerprint)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,325 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use sha2::{Digest, Sha256};
+
+use crate::util;
+use crate::dissect::ber::{self, Tlv};
+
+/// public key algorithms
+const OID_RSA:      &str = "1.2.840.113549.1.1.1";
+const OID_DSA:      &str = "1.2.840.10040.4.1";
+const OID_EC:       &str = "1.2.840.10045.2.1";
+const OID_ED25519:  &str = "1.3.101.112";
+const OID_ED448:    &str = "1.3.101.113";
+
+/// the subject alternative name extension
+const OID_SAN: &str = "2.5.29.17";
+
+/// keys below these sizes are considered weak
+const MIN_RSA_BITS: u32 = 2048;
+const MIN_EC_BITS:  u32 = 224;
+
+/// type and size of a public key
+pub enum KeyInfo {
+    Rsa(u32),
+    Dsa(u32),
+    Ec(String, Option<u32>),
+    Ed25519,
+    Ed448,
+    Unknown(String),
+}
+
+impl KeyInfo {
+
+    /// true if the key is too small to be considered secure
+    pub fn is_weak(&self) -> bool {
+        match self {
+            KeyInfo::Rsa(bits) | KeyInfo::Dsa(bits) => *bits < MIN_RSA_BITS,
+            KeyInfo::Ec(_, Some(bits)) => *bits < MIN_EC_BITS,
+            _ => false,
+        }
+    }
+
+}
+
+impl fmt::Display for KeyInfo {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyInfo::Rsa(bits)              => write!(f, "RSA {}", bits),
+            KeyInfo::Dsa(bits)              => write!(f, "DSA {}", bits),
+            KeyInfo::Ec(curve, Some(bits))  => write!(f, "EC {} ({})", bits, curve),
+            KeyInfo::Ec(curve, None)        => write!(f, "EC ({})", curve),
+            KeyInfo::Ed25519                => write!(f, "Ed25519"),
+            KeyInfo::Ed448                  => write!(f, "Ed448"),
+            KeyInfo::Unknown(oid)           => write!(f, "unknown ({})", oid),
+        }
+    }
+
+}
+
+/// the fields of a certificate we report
+pub struct Certificate {
+    pub subject:        String,
+    pub issuer:         String,
+    pub sans:           Vec<String>,
+    /// validity as unix timestamps
+    pub not_before:     i64,
+    pub not_after:      i64,
+    pub key:            KeyInfo,
+    pub sha256:         String,
+    pub self_signed:    bool,
+}
+
+impl Certificate {
+
+    /// true if the certificate ran out before `ts`
+    pub fn expired_at(&self, ts: i64) -> bool {
+        ts > self.not_after
+    }
+
+    /// true if the certificate only becomes valid after `ts`
+    pub fn not_yet_valid_at(&self, ts: i64) -> bool {
+        ts < self.not_before
+    }
+
+}
+
+/// short names for the attributes of a distinguished name
+fn attribute_name(oid: &str) -> String {
+    match oid {
+        "2.5.4.3"               => "CN".to_string(),
+        "2.5.4.5"               => "serialNumber".to_string(),
+        "2.5.4.6"               => "C".to_string(),
+        "2.5.4.7"               => "L".to_string(),
+        "2.5.4.8"               => "ST".to_string(),
+        "2.5.4.10"              => "O".to_string(),
+        "2.5.4.11"              => "OU".to_string(),
+        "1.2.840.113549.1.9.1"  => "emailAddress".to_string(),
+        _                       => oid.to_string(),
+    }
+}
+
+/// render a Name as `CN=.., O=..`
+fn name_string(name: &Tlv) -> String {
+    let mut parts = Vec::new();
+    for rdn in name.children() {
+        for attr in rdn.children() {
+            let items = attr.children();
+            if let [oid, value, ..] = items.as_slice() {
+                parts.push(format!("{}={}",
+                    attribute_name(&oid.oid()), value.string()));
+            }
+        }
+    }
+    parts.join(", ")
+}
+
+/// days since 1970-01-01 for a date of the proleptic gregorian calendar
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// decode a UTCTime or GeneralizedTime to a unix timestamp
+fn time(t: &Tlv) -> Option<i64> {
+
+    let s = t.string();
+    let digits = |from: usize, len: usize| -> Option<i64> {
+        s.get(from..from+len)?.parse::<i64>().ok()
+    };
+
+    let (year, rest) = match t.tag {
+        ber::TAG_UTC_TIME => {
+            // two digit years, 50 and above are in the 1900s
+            let yy = digits(0, 2)?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, 2)
+        },
+        ber::TAG_GEN_TIME => (digits(0, 4)?, 4),
+        _ => return None,
+    };
+
+    let month = digits(rest, 2)?;
+    let day = digits(rest+2, 2)?;
+    let hour = digits(rest+4, 2)?;
+    let min = digits(rest+6, 2)?;
+    let sec = digits(rest+8, 2).unwrap_or(0);
+
+    Some(days_from_civil(year, month, day) * 86400
+        + hour * 3600 + min * 60 + sec)
+}
+
+/// format a unix timestamp as `YYYY-MM-DD`
+pub fn date_string(ts: i64) -> String {
+    // civil_from_days, the inverse of days_from_civil
+    let z = ts.div_euclid(86400) + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// number of significant bits of a big endian integer
+fn bit_length(value: &[u8]) -> u32 {
+    match value.iter().position(|b| *b != 0) {
+        Some(i) => (value.len() - i) as u32 * 8 - value[i].leading_zeros(),
+        None    => 0,
+    }
+}
+
+/// decode the SubjectPublicKeyInfo
+fn key_info(spki: &Tlv) -> Option<KeyInfo> {
+
+    let items = spki.children();
+    let alg = items.first()?.children();
+    let oid = alg.first()?.oid();
+    let params = alg.get(1);
+
+    let info = match oid.as_str() {
+        OID_RSA => {
+            // the key is a sequence of modulus and exponent, wrapped in
+            // a bit string with a leading "unused bits" byte
+            let bits = items.get(1)?.value.get(1..)?;
+            let (key, _) = ber::read(bits)?;
+            let modulus = key.children();
+            KeyInfo::Rsa(bit_length(modulus.first()?.value))
+        },
+        OID_DSA => {
+            let p = params?.children();
+            KeyInfo::Dsa(bit_length(p.first()?.value))
+        },
+        OID_EC => {
+            let curve = params?.oid();
+            // the size of a curve we don't know is left open, so it
+            // is never flagged as weak
+            let (name, bits) = match curve.as_str() {
+                "1.2.840.10045.3.1.1" => ("P-192", Some(192)),
+                "1.3.132.0.33"        => ("P-224", Some(224)),
+                "1.2.840.10045.3.1.7" => ("P-256", Some(256)),
+                "1.3.132.0.34"        => ("P-384", Some(384)),
+                "1.3.132.0.35"        => ("P-521", Some(521)),
+                "1.3.132.0.10"        => ("secp256k1", Some(256)),
+                _                     => (curve.as_str(), None),
+            };
+            KeyInfo::Ec(name.to_string(), bits)
+        },
+        OID_ED25519 => KeyInfo::Ed25519,
+        OID_ED448   => KeyInfo::Ed448,
+        _           => KeyInfo::Unknown(oid),
+    };
+
+    Some(info)
+}
+
+/// decode the entries of a SubjectAltName extension
+fn subject_alt_names(value: &[u8]) -> Vec<String> {
+
+    let Some((names, _)) = ber::read(value) else {
+        return Vec::new();
+    };
+
+    names.children().iter()
+        .filter_map(|name| match name.tag {
+            // rfc822Name, dNSName and URI are context specific strings
+            0x81 | 0x82 | 0x86 => Some(name.string()),
+            0x87 => match name.value.len() {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    octets.copy_from_slice(name.value);
+                    Some(Ipv4Addr::from(octets).to_string())
+                },
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(name.value);
+                    Some(Ipv6Addr::from(octets).to_string())
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// parse a DER encoded certificate
+pub fn parse(der: &[u8]) -> Option<Certificate> {
+
+    let (cert, _) = ber::read(der)?;
+    let tbs = cert.children().into_iter().next()?;
+    let mut fields = tbs.children().into_iter().peekable();
+
+    // the version is optional and tagged [0]
+    if fields.peek()?.tag == 0xa0 {
+        fields.next();
+    }
+
+    let _serial = fields.next()?;
+    let _signature = fields.next()?;
+    let issuer = fields.next()?;
+    let validity = fields.next()?.children();
+    let subject = fields.next()?;
+    let spki = fields.next()?;
+
+    let mut sans = Vec::new();
+
+    // extensions are tagged [3], unique ids [1] and [2] come before
+    for field in fields {
+        if field.tag != 0xa3 {
+            continue;
+        }
+        let Some((exts, _)) = ber::read(field.value) else {
+            continue;
+        };
+        for ext in exts.children() {
+            let items = ext.children();
+            let Some(oid) = items.first() else {
+                continue;
+            };
+            if oid.oid() == OID_SAN {
+                // the value is the last item, critical may precede it
+                if let Some(value) = items.last() {
+                    sans = subject_alt_names(value.value);
+                }
+            }
+        }
+    }
+
+    Some(Certificate {
+        subject:        name_string(&subject),
+        issuer:         name_string(&issuer),
+        sans,
+        not_before:     time(validity.first()?)?,
+        not_after:      time(validity.get(1)?)?,
+        key:            key_info(&spki)?,
+        sha256:         util::hex(&Sha256::digest(cert.raw)),
+        self_signed:    subject.raw == issuer.raw,
+    })
+}
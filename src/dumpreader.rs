@@ -23,6 +23,8 @@ use std::collections::HashSet;
 use pcap::{Capture, Offline};
 
 use crate::util;
+use crate::dissect::{Dissectors, Flow, Frame};
+use crate::dissect::reassembly::Segment;
 use crate::pinfo::{PacketData, MacAddr, PortAddr, Protocol};
 
 /// ethertype field for IPv4
@@ -34,6 +36,9 @@ const ETHERTYPE_ARP:  u16 = 0x0806;
 /// ethertype field for IPv6
 const ETHERTYPE_IPV6:  u16 = 0x86DD;
 
+/// ethertype field for 802.1Q VLAN tags
+const ETHERTYPE_VLAN:  u16 = 0x8100;
+
 /// open a capture
 pub fn open_capture(fpath: &str) -> Capture<Offline> {
 
@@ -51,8 +56,9 @@ pub fn open_capture(fpath: &str) -> Capture<Offline> {
 }
 
 
-/// parse the capture
-pub fn parse(cap: &mut Capture<Offline>) -> HashSet<PacketData> {
+/// parse the capture, payloads are handed to `dissectors`
+pub fn parse(cap: &mut Capture<Offline>, dissectors: &mut Dissectors) 
+    -> HashSet<PacketData> {
 
     let mut packets = HashSet::new();
 
@@ -139,6 +145,40 @@ pub fn parse(cap: &mut Capture<Offline>) -> HashSet<PacketData> {
                 // add ports
                 pdata.ports(sport, dport);
 
+                // the payload ends with the IP packet, not the frame,
+                // ethernet may add padding
+                let total = u16::from_be_bytes(
+                    parse_to_u16(&ipv4[2..4])) as usize;
+                let end = total.min(ipv4.len());
+
+                let flow = Flow {
+                    smac, dmac, sip, dip,
+                    sport: sport.0,
+                    dport: dport.0,
+                    ts: packet.header.ts.tv_sec,
+                    usec: packet.header.ts.tv_usec,
+                };
+
+                if proto == Protocol::UDP {
+                    if let Some(payload) = ipv4.get(offset+8..end) {
+                        dissectors.udp(&flow, payload);
+                    }
+                } else if let (Some(seq), Some(hdr), Some(flags)) = (
+                    ipv4.get(offset+4..offset+8),
+                    ipv4.get(offset+12),
+                    ipv4.get(offset+13)) {
+                    // TCP data offset is given in 32 bit words
+                    let doff = ((hdr >> 4) * 4) as usize;
+                    if let Some(payload) = ipv4.get(offset+doff..end) {
+                        let segment = Segment {
+                            seq: u32::from_be_bytes(parse_to_u32(seq)),
+                            flags: *flags,
+                            payload,
+                        };
+                        dissectors.segment(&flow, &segment);
+                    }
+                }
+
             } 
         } else {
             // it's not IPv4
@@ -154,6 +194,25 @@ pub fn parse(cap: &mut Capture<Offline>) -> HashSet<PacketData> {
                     // for instance log the unknown protocol
                     pdata.protocol(Protocol::Unknown);
                     ignore = true;
+
+                    // layer 2 protocols, e.g. industrial ethernet, are
+                    // often sent with a VLAN tag for their priority
+                    let (ethertype, start) = match packet.get(16..18) {
+                        Some(inner) if ethertype == ETHERTYPE_VLAN => {
+                            (u16::from_be_bytes(parse_to_u16(inner)), 18)
+                        },
+                        _ => (ethertype, 14),
+                    };
+
+                    let frame = Frame {
+                        smac, dmac,
+                        ts: packet.header.ts.tv_sec,
+                        usec: packet.header.ts.tv_usec,
+                    };
+
+                    if let Some(payload) = packet.get(start..) {
+                        dissectors.ethernet(&frame, ethertype, payload);
+                    }
                 },
             }
         }
@@ -164,6 +223,9 @@ pub fn parse(cap: &mut Capture<Offline>) -> HashSet<PacketData> {
 
     }
 
+    // streams still open are delivered as far as they got
+    dissectors.finish();
+
     let mut state = state.lock().unwrap();
     let total = state.1;
     state.0 = false;
@@ -35,21 +35,59 @@ pub mod util;
 pub mod pinfo;
 pub mod dumpreader;
 pub mod analyze;
+pub mod dissect;
+pub mod services;
+
+/// show credentials in the report instead of redacting them
+const SHOW_CREDENTIALS: &str = "--show-credentials";
+
+/// load site specific service names from a file
+const SERVICES_FILE: &str = "--services";
+
+/// extract transferred files to a folder
+const EXTRACT_DIR: &str = "--extract";
 
 fn usage() {
     print!("\n-- NETANALYZE\n");
-    print!("-- ./netanalyze [PCAP file]\n");
+    print!("-- ./netanalyze [--show-credentials] [--services FILE] [--extract DIR] [PCAP file]\n");
     print!("-- this will produce:\n");
     print!("-- | report.txt - a short summary of the dump\n");
     print!("-- | graph.png  - shows a graphical overview of the network\n");
     print!("-- | out.png    - a dot file you can use with graphviz \n");
     print!("-- | nx.html    - an interactive graph you can view in a browser\n");
+    print!("-- | http.csv   - HTTP requests and responses\n");
+    print!("-- | DIR/       - with --extract, transferred files and manifest.csv\n");
     print!("-- author: 0xca7\n\n");
 }
 
 fn main() {
  
-    let args: Vec<String> = env::args().collect(); 
+    let mut args: Vec<String> = env::args().collect(); 
+    let show_credentials = args.iter().any(|a| a == SHOW_CREDENTIALS);
+    args.retain(|a| a != SHOW_CREDENTIALS);
+
+    let mut services = services::Services::new();
+    if let Some(pos) = args.iter().position(|a| a == SERVICES_FILE) {
+        let Some(path) = args.get(pos + 1).cloned() else {
+            usage();
+            std::process::exit(1);
+        };
+        if let Err(e) = services.load(&path) {
+            print!("error: cannot read services file: {}\n", e);
+            std::process::exit(1);
+        }
+        args.drain(pos..pos + 2);
+    }
+
+    let mut extract_dir = None;
+    if let Some(pos) = args.iter().position(|a| a == EXTRACT_DIR) {
+        let Some(path) = args.get(pos + 1).cloned() else {
+            usage();
+            std::process::exit(1);
+        };
+        extract_dir = Some(std::path::PathBuf::from(path));
+        args.drain(pos..pos + 2);
+    }
 
     if args.len() < 2 {
         usage();
@@ -66,7 +104,10 @@ fn main() {
     let now = Instant::now();
 
     let mut cap = dumpreader::open_capture(capfile);
-    let packets = dumpreader::parse(&mut cap);
+    let mut dissectors = dissect::Dissectors::default();
+    dissectors.creds.reveal = show_credentials;
+    dissectors.files.dir = extract_dir;
+    let packets = dumpreader::parse(&mut cap, &mut dissectors);
 
     let packetlist = packets.into_iter().collect::<Vec<_>>();
 
@@ -75,17 +116,29 @@ fn main() {
     print!("[+] reporting...\n");
     let now = Instant::now();
 
-    match analyze::generate_report(&packetlist) {
+    match analyze::generate_report(&packetlist, &dissectors, &services) {
         Ok(()) => print!("[+] report done\n"),
         Err(e) => eprint!("error: {}\n", e),
     };
 
-    match analyze::dotfile(&packetlist) {
+    match analyze::dotfile(&packetlist, &dissectors, &services) {
         Ok(()) => print!("[+] writing dotfile done\n"),
         Err(e) => eprint!("error: {}\n", e),
     };
 
-    match analyze::visualize(&packetlist) {
+    match analyze::http_csv(&dissectors) {
+        Ok(()) => print!("[+] writing http csv done\n"),
+        Err(e) => eprint!("error: {}\n", e),
+    };
+
+    if dissectors.files.dir.is_some() {
+        match dissectors.files.manifest() {
+            Ok(()) => print!("[+] {} files extracted\n", dissectors.files.extracted.len()),
+            Err(e) => eprint!("error: {}\n", e),
+        };
+    }
+
+    match analyze::visualize(&packetlist, &dissectors, &services) {
         Ok(()) => print!("[+] visualization done\n"),
         Err(e) => eprint!("error: {}\n", e),
     };
@@ -20,6 +20,8 @@
 use std::fmt;
 use std::net::Ipv4Addr;
 
+use crate::util;
+
 /// port type can either be TCP or UDP
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Protocol{
@@ -60,7 +62,7 @@ impl fmt::Display for PortAddr {
 }
 
 /// type to model a MAC address
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct MacAddr(u8,u8,u8,u8,u8,u8);
 
 impl MacAddr {
@@ -72,6 +74,16 @@ impl MacAddr {
             bytes[3], bytes[4], bytes[5])
     }
 
+    /// true if the locally administered bit is set
+    pub fn is_local(&self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    /// the address with the locally administered bit flipped
+    pub fn flip_local(&self) -> MacAddr {
+        MacAddr(self.0 ^ 0x02, self.1, self.2, self.3, self.4, self.5)
+    }
+
 }
 
 impl fmt::Display for MacAddr {
@@ -104,20 +116,27 @@ impl PacketData {
         PacketDataBuilder::new()
     }
 
-    /// get a string containing packet data in graph form
-    pub fn write_graph(&self) -> String {
+    /// get a string containing packet data in graph form, 
+    /// with an optional edge `label`
+    pub fn write_graph(&self, label: Option<&str>) -> String {
         if self.proto == Protocol::ARP {
-            return format!("{},{}\n", self.smac, self.dmac)
+            return format!("{},{},\n", self.smac, self.dmac)
         }
-        format!("{:?},{:?}\n", self.sip, self.dip)
+        format!("{:?},{:?},{}\n", self.sip, self.dip, 
+            util::csv_field(label.unwrap_or("")))
     }
 
-    /// get a string containing packet data in dot language form
-    pub fn write_dot(&self) -> String {
+    /// get a string containing packet data in dot language form,
+    /// with an optional edge `label`, which is escaped here
+    pub fn write_dot(&self, label: Option<&str>) -> String {
         if self.proto == Protocol::ARP {
             return format!("\"{}\" -> \"{}\"\n", self.smac, self.dmac)
         }
-        format!("\"{:?}\" -> \"{:?}\"\n", self.sip, self.dip)
+        match label {
+            Some(label) => format!("\"{:?}\" -> \"{:?}\" [label=\"{}\"]\n", 
+                self.sip, self.dip, util::dot_escape(label)),
+            None => format!("\"{:?}\" -> \"{:?}\"\n", self.sip, self.dip),
+        }
     }
 
     pub fn get_sip(&self) -> Ipv4Addr {
@@ -136,6 +155,10 @@ impl PacketData {
         self.dport
     }
 
+    pub fn get_proto(&self) -> Protocol {
+        self.proto
+    }
+
     pub fn get_smac(&self) -> MacAddr {
         self.smac
     }
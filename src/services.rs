@@ -0,0 +1,107 @@
+/*
+    net-analyze - quick summary of pcap dumps
+    Copyright (C) 2022  0xca7
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! service names for ports. the IANA registry and a supplement for
+//! industrial protocols are built in, a file in the same format can
+//! override both. data/iana-services is generated from the registry
+//! by py/iana_services.py.
+//!
+
+use std::fs;
+use std::io::Error;
+use std::collections::HashMap;
+
+use crate::pinfo::Protocol;
+
+/// the IANA registry and the ICS supplement, in /etc/services format
+const IANA_SERVICES: &str = include_str!("../data/iana-services");
+const ICS_SERVICES: &str = include_str!("../data/ics-services");
+
+/// service names keyed by port and transport protocol
+pub struct Services {
+    names: HashMap<(u16, Protocol), String>,
+}
+
+impl Services {
+
+    /// the built in services, the ICS names win over the IANA names
+    pub fn new() -> Self {
+        let mut services = Services { names: HashMap::new() };
+        services.add(IANA_SERVICES);
+        services.add(ICS_SERVICES);
+        services
+    }
+
+    /// add the services of a file, for site specific names
+    pub fn load(&mut self, path: &str) -> Result<(), Error> {
+        let text = fs::read_to_string(path)?;
+        self.add(&text);
+        Ok(())
+    }
+
+    /// add services given as "name port/protocol [aliases] [# comment]"
+    /// lines, later lines replace earlier ones
+    fn add(&mut self, text: &str) {
+
+        for line in text.lines() {
+
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(port)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let Some((port, proto)) = port.split_once('/') else {
+                continue;
+            };
+            let proto = match proto.to_lowercase().as_str() {
+                "tcp" => Protocol::TCP,
+                "udp" => Protocol::UDP,
+                _ => continue,
+            };
+            let Ok(port) = port.parse::<u16>() else {
+                continue;
+            };
+
+            self.names.insert((port, proto), name.to_string());
+        }
+    }
+
+    /// get the name of the service on `port`
+    pub fn name(&self, port: u16, proto: Protocol) -> Option<&str> {
+        self.names.get(&(port, proto)).map(|n| n.as_str())
+    }
+
+    /// port, transport and service name, like "502/tcp modbus"
+    pub fn label(&self, port: u16, proto: Protocol) -> String {
+        let transport = proto.to_string().to_lowercase();
+        match self.name(port, proto) {
+            Some(name) => format!("{}/{} {}", port, transport, name),
+            None => format!("{}/{}", port, transport),
+        }
+    }
+
+}
+
+impl Default for Services {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
@@ -29,6 +29,61 @@ pub fn check_exists(fname: &str) -> bool {
     Path::new(fname).exists()
 }
 
+/// quote a field for a csv file if it contains a separator or quotes
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
+
+/// escape text for a quoted string in a dot file
+pub fn dot_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// lowercase hex representation of `bytes`
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// decode standard base64, `None` if `text` contains anything else
+pub fn base64(text: &str) -> Option<Vec<u8>> {
+
+    let mut out = Vec::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.trim().trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
 /// show progress for packet parsing every `x` nanoseconds
 pub fn progressbar(state: Arc<Mutex<(bool, u64)>>) {
 